@@ -0,0 +1,249 @@
+//! Measures the throughput of a TCP connection through two onetun tunnels, on the loopback interface.
+//!
+//! The first tunnel forwards a local port to the second one, which forwards the connection back to a local server with
+//! a remote port forward. The data is uploaded to the server, then downloaded from it.
+//!
+//! Run with `cargo bench --bench throughput`. `ONETUN_BENCH_MB` sets the size of each transfer (256 MiB by default).
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use onetun::config::{
+    Config, PeerConfig, PortForwardConfig, PortProtocol, RestartPolicy, X25519SecretKey,
+};
+use onetun::events::Bus;
+use onetun::start_tunnels;
+use smoltcp::wire::{IpAddress, IpCidr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const DEFAULT_SIZE_MB: usize = 256;
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 70, 1);
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 70, 2);
+const SERVER_PORT: u16 = 80;
+const MTU: usize = 1420;
+const CHUNK: usize = 64 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() -> anyhow::Result<()> {
+    let size_mb = match std::env::var("ONETUN_BENCH_MB") {
+        Ok(s) => s
+            .parse()
+            .with_context(|| format!("Invalid ONETUN_BENCH_MB value: '{}'", s))?,
+        Err(_) => DEFAULT_SIZE_MB,
+    };
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(bench(size_mb * 1024 * 1024))
+}
+
+async fn bench(size: usize) -> anyhow::Result<()> {
+    let client_key = X25519SecretKey::new();
+    let server_key = X25519SecretKey::new();
+    let client_public_key = client_key.public_key();
+    let server_public_key = server_key.public_key();
+    let client_endpoint = free_udp_addr()?;
+    let server_endpoint = free_udp_addr()?;
+    let source = free_tcp_addr()?;
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+
+    let client_config = config(
+        client_key,
+        PeerConfig {
+            name: "server".into(),
+            endpoint_public_key: Arc::new(server_public_key),
+            preshared_key: None,
+            endpoints: vec![server_endpoint.into()],
+            source_peer_ips: vec![IpCidr::new(IpAddress::from(CLIENT_IP), 32)],
+            allowed_ips: vec![IpCidr::new(IpAddress::from(SERVER_IP), 32)],
+            keepalive_seconds: None,
+        },
+        client_endpoint,
+        PortForwardConfig {
+            name: None,
+            source,
+            destination: SocketAddr::from((SERVER_IP, SERVER_PORT)),
+            destination_host: None,
+            protocol: PortProtocol::Tcp,
+            remote: false,
+            peer: None,
+        },
+    );
+    let server_config = config(
+        server_key,
+        PeerConfig {
+            name: "client".into(),
+            endpoint_public_key: Arc::new(client_public_key),
+            preshared_key: None,
+            endpoints: vec![client_endpoint.into()],
+            source_peer_ips: vec![IpCidr::new(IpAddress::from(SERVER_IP), 32)],
+            allowed_ips: vec![IpCidr::new(IpAddress::from(CLIENT_IP), 32)],
+            keepalive_seconds: None,
+        },
+        server_endpoint,
+        PortForwardConfig {
+            name: None,
+            source: SocketAddr::from((SERVER_IP, SERVER_PORT)),
+            destination: server.local_addr()?,
+            destination_host: None,
+            protocol: PortProtocol::Tcp,
+            remote: true,
+            peer: None,
+        },
+    );
+
+    let client_tunnels = start_tunnels(client_config, Bus::default()).await?;
+    let server_tunnels = start_tunnels(server_config, Bus::default()).await?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, client_tunnels.readiness().wait())
+        .await
+        .with_context(|| "The WireGuard handshake did not complete")?;
+
+    // Upload: the client writes, the server reads
+    let start = Instant::now();
+    let start_cpu = cpu_time();
+    let mut client = TcpStream::connect(source).await?;
+    let (mut accepted, _) = server.accept().await?;
+    let reader = tokio::spawn(async move { read_all(&mut accepted).await });
+    write_all(&mut client, size).await?;
+    client.shutdown().await?;
+    let received = reader.await??;
+    report(
+        "upload",
+        size,
+        received,
+        start.elapsed(),
+        cpu_time() - start_cpu,
+    );
+    drop(client);
+
+    // Download: the server writes, the client reads
+    let start = Instant::now();
+    let start_cpu = cpu_time();
+    let mut client = TcpStream::connect(source).await?;
+    let (mut accepted, _) = server.accept().await?;
+    let writer = tokio::spawn(async move {
+        write_all(&mut accepted, size).await?;
+        accepted.shutdown().await
+    });
+    let received = read_all(&mut client).await?;
+    writer.await??;
+    report(
+        "download",
+        size,
+        received,
+        start.elapsed(),
+        cpu_time() - start_cpu,
+    );
+
+    client_tunnels.shutdown().await?;
+    server_tunnels.shutdown().await
+}
+
+/// The config of one of the tunnels, with a single peer and port forward.
+fn config(
+    private_key: X25519SecretKey,
+    peer: PeerConfig,
+    endpoint_bind_addr: SocketAddr,
+    port_forward: PortForwardConfig,
+) -> Config {
+    let (port_forwards, remote_port_forwards) = if port_forward.remote {
+        (vec![], vec![port_forward])
+    } else {
+        (vec![port_forward], vec![])
+    };
+    Config {
+        port_forwards,
+        remote_port_forwards,
+        private_key: Arc::new(private_key),
+        peers: vec![peer],
+        endpoint_bind_addr,
+        max_transmission_unit: MTU,
+        log: String::new(),
+        warnings: vec![],
+        pcap_file: None,
+        socks5_addr: None,
+        http_proxy_addr: None,
+        http_proxy_auth: None,
+        dns_server: None,
+        dns_forwarder_addr: None,
+        dns_forwarder_cache: false,
+        config_file: None,
+        arg_port_forwards: vec![],
+        control_addr: None,
+        metrics_addr: None,
+        wait_handshake: None,
+        restart_policy: RestartPolicy::default(),
+    }
+}
+
+/// Finds a free UDP port on the loopback interface.
+fn free_udp_addr() -> anyhow::Result<SocketAddr> {
+    Ok(std::net::UdpSocket::bind((IpAddr::from(Ipv4Addr::LOCALHOST), 0))?.local_addr()?)
+}
+
+/// Finds a free TCP port on the loopback interface.
+fn free_tcp_addr() -> anyhow::Result<SocketAddr> {
+    Ok(std::net::TcpListener::bind((IpAddr::from(Ipv4Addr::LOCALHOST), 0))?.local_addr()?)
+}
+
+async fn write_all(socket: &mut TcpStream, size: usize) -> std::io::Result<()> {
+    let chunk = vec![0x42u8; CHUNK];
+    let mut written = 0;
+    while written < size {
+        let len = CHUNK.min(size - written);
+        socket.write_all(&chunk[..len]).await?;
+        written += len;
+    }
+    Ok(())
+}
+
+async fn read_all(socket: &mut TcpStream) -> std::io::Result<usize> {
+    let mut buffer = vec![0u8; CHUNK];
+    let mut received = 0;
+    loop {
+        match socket.read(&mut buffer).await? {
+            0 => return Ok(received),
+            size => received += size,
+        }
+    }
+}
+
+/// Prints the throughput of a transfer, and the CPU time used by both tunnels for each MiB.
+fn report(name: &str, size: usize, received: usize, elapsed: Duration, cpu: Duration) {
+    let mib = received as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:<8} {:>8.1} MiB in {:>6.2} s: {:>8.1} MiB/s, {:>6.2} ms of CPU per MiB{}",
+        name,
+        mib,
+        elapsed.as_secs_f64(),
+        mib / elapsed.as_secs_f64(),
+        cpu.as_secs_f64() * 1000.0 / mib,
+        if received == size {
+            ""
+        } else {
+            " (incomplete)"
+        }
+    );
+}
+
+/// The CPU time used by the process, in user and kernel mode.
+#[cfg(unix)]
+fn cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: getrusage fills the struct, which is zeroed if it fails
+    let usage = unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+        usage.assume_init()
+    };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// The CPU time is not measured on this platform.
+#[cfg(not(unix))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
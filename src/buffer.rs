@@ -0,0 +1,77 @@
+use bytes::{Bytes, BytesMut};
+
+/// The size of the chunks of the buffer pools.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Buffers for the packets and data on the hot path, to avoid an allocation for each of them.
+///
+/// The buffers are split off large chunks of memory, and frozen into `Bytes` that can be sent to other tasks without
+/// copies. A chunk is reused once all the buffers split off it are dropped, so the pool only allocates while the
+/// buffers are held elsewhere (e.g. queued for a slow connection).
+pub(crate) struct BufferPool {
+    chunk: BytesMut,
+    chunk_size: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(CHUNK_SIZE)
+    }
+}
+
+impl BufferPool {
+    /// Creates a pool that allocates chunks of the given size.
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk: BytesMut::with_capacity(chunk_size),
+            chunk_size,
+        }
+    }
+
+    /// Returns a zeroed buffer of the given length.
+    pub fn get(&mut self, len: usize) -> BytesMut {
+        self.reserve(len);
+        self.chunk.resize(len, 0);
+        self.chunk.split_to(len)
+    }
+
+    /// Copies the data into a buffer of the pool.
+    pub fn copy_from_slice(&mut self, data: &[u8]) -> Bytes {
+        self.reserve(data.len());
+        self.chunk.extend_from_slice(data);
+        self.chunk.split().freeze()
+    }
+
+    fn reserve(&mut self, len: usize) {
+        if self.chunk.capacity() < len {
+            // Reclaims the chunk if its buffers were dropped, otherwise allocates a new one
+            self.chunk.reserve(self.chunk_size.max(len));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a chunk is reused once its buffers are dropped, and not before.
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let mut pool = BufferPool::new(4);
+        let first = pool.copy_from_slice(b"ab");
+        let second = pool.copy_from_slice(b"cd");
+        assert_eq!(&first[..], b"ab");
+        assert_eq!(&second[..], b"cd");
+        assert_eq!(second.as_ptr(), first.as_ptr().wrapping_add(2));
+
+        // The chunk is full, and its buffers are still held
+        let held = pool.get(4);
+        assert_ne!(held.as_ptr(), first.as_ptr());
+        assert_eq!(&held[..], &[0, 0, 0, 0]);
+
+        let chunk = held.as_ptr();
+        drop(held);
+        let reused = pool.copy_from_slice(b"ef");
+        assert_eq!(reused.as_ptr(), chunk);
+    }
+}
@@ -0,0 +1,361 @@
+use std::convert::TryFrom;
+use std::fs::read_to_string;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use smoltcp::wire::IpCidr;
+
+use crate::config::{
+    find_peer, native, parse_allowed_ips, parse_destination, parse_endpoints, parse_keep_alive,
+    parse_preshared_key, parse_public_key, parse_source_peer_ips, wg_quick, PeerConfig,
+    PortForwardConfig, PortProtocol, DEFAULT_PORT_FORWARD_SOURCE,
+};
+
+/// Values read from a configuration file. These are used as defaults for the
+/// CLI arguments and environment variables, which take precedence.
+///
+/// Values are kept as strings so that they go through the same validation as the CLI arguments.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ConfigFile {
+    pub private_key: Option<String>,
+    pub private_key_file: Option<String>,
+    pub endpoint_public_key: Option<String>,
+    pub preshared_key: Option<String>,
+    pub endpoint_addr: Option<String>,
+    pub endpoint_bind_addr: Option<String>,
+    /// The local UDP port to bind for the WireGuard connection, on all interfaces.
+    pub listen_port: Option<String>,
+    pub source_peer_ip: Option<String>,
+    /// Comma-separated IP ranges.
+    pub allowed_ips: Option<String>,
+    pub keep_alive: Option<String>,
+    pub max_transmission_unit: Option<String>,
+    pub log: Option<String>,
+    pub pcap: Option<String>,
+    pub socks5: Option<String>,
+    pub http_proxy: Option<String>,
+    /// The DNS server reached through the tunnel, in the format `ip[:port]`.
+    pub dns: Option<String>,
+    pub dns_forwarder: Option<String>,
+    pub dns_forwarder_cache: bool,
+    /// The HTTP proxy credentials, in the format `user:password`.
+    pub http_proxy_auth: Option<String>,
+    /// The address of the control API, or the path of its Unix socket.
+    pub control: Option<String>,
+    pub metrics: Option<String>,
+    /// The handshake timeout on startup, in seconds.
+    pub wait_handshake: Option<String>,
+    pub max_restarts: Option<String>,
+    /// The delay before the first restart, in seconds.
+    pub restart_backoff: Option<String>,
+    /// Additional peers, besides the one defined by the top-level values.
+    pub peers: Vec<FilePeer>,
+    pub port_forwards: Vec<FilePortForward>,
+    /// Warnings about values that were read but ignored.
+    pub warnings: Vec<String>,
+}
+
+impl ConfigFile {
+    /// Reads and parses the configuration file at the given path.
+    /// The format is determined by the file extension: `.toml`, `.yaml` or `.yml` for the onetun format,
+    /// and the wg-quick format otherwise.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents =
+            read_to_string(path).with_context(|| format!("Failed to read config file {}", path))?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        match extension.as_deref() {
+            Some("toml") => native::parse_toml(&contents)
+                .with_context(|| format!("Invalid TOML config file {}", path)),
+            Some("yaml") | Some("yml") => native::parse_yaml(&contents)
+                .with_context(|| format!("Invalid YAML config file {}", path)),
+            _ => wg_quick::parse(&contents)
+                .with_context(|| format!("Invalid WireGuard config file {}", path)),
+        }
+    }
+}
+
+/// A peer definition read from a configuration file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FilePeer {
+    /// The location of the definition in the file, used in error messages. Example: `peers[1]`
+    pub key: String,
+    pub name: String,
+    pub endpoint_public_key: Option<String>,
+    pub preshared_key: Option<String>,
+    pub endpoint_addr: Option<String>,
+    /// Comma-separated IPs, with optional prefix lengths. Defaults to the top-level source peer IPs.
+    pub source_peer_ip: Option<String>,
+    /// Comma-separated IP ranges.
+    pub allowed_ips: Option<String>,
+    pub keep_alive: Option<String>,
+}
+
+impl FilePeer {
+    /// Converts the definition into `PeerConfig`.
+    pub fn to_peer_config(
+        &self,
+        default_source_peer_ips: Option<&[IpCidr]>,
+    ) -> anyhow::Result<PeerConfig> {
+        self.parse(default_source_peer_ips)
+            .with_context(|| format!("Invalid peer {} ('{}')", self.key, self.name))
+    }
+
+    fn parse(&self, default_source_peer_ips: Option<&[IpCidr]>) -> anyhow::Result<PeerConfig> {
+        let source_peer_ips = match &self.source_peer_ip {
+            Some(ips) => parse_source_peer_ips(Some(ips)),
+            None => default_source_peer_ips
+                .map(<[IpCidr]>::to_vec)
+                .with_context(|| "Missing IP"),
+        }
+        .with_context(|| format!("{}.source_peer_ip: invalid source peer IP", self.key))?;
+
+        Ok(PeerConfig {
+            name: self.name.as_str().into(),
+            endpoint_public_key: Arc::new(
+                parse_public_key(self.endpoint_public_key.as_deref()).with_context(|| {
+                    format!("{}.endpoint_public_key: invalid public key", self.key)
+                })?,
+            ),
+            preshared_key: parse_preshared_key(self.preshared_key.as_deref())
+                .with_context(|| format!("{}.preshared_key: invalid pre-shared key", self.key))?,
+            endpoints: parse_endpoints(self.endpoint_addr.as_deref())
+                .with_context(|| format!("{}.endpoint_addr: invalid address", self.key))?,
+            source_peer_ips,
+            allowed_ips: parse_allowed_ips(self.allowed_ips.as_deref())
+                .with_context(|| format!("{}.allowed_ips: invalid allowed IPs", self.key))?,
+            keepalive_seconds: parse_keep_alive(self.keep_alive.as_deref())
+                .with_context(|| format!("{}.keep_alive: invalid keep-alive value", self.key))?,
+        })
+    }
+}
+
+/// A port forward definition read from a configuration file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FilePortForward {
+    /// The location of the definition in the file, used in error messages. Example: `port_forwards[2]`
+    pub key: String,
+    pub name: Option<String>,
+    /// The source address, in the format `[src_host:]<src_port>`.
+    pub source: String,
+    /// The destination address, in the format `<dst_host>:<dst_port>`.
+    pub destination: String,
+    /// The protocols to forward. Defaults to TCP if empty.
+    pub protocols: Vec<String>,
+    pub remote: bool,
+    /// The name of the peer to forward through.
+    pub peer: Option<String>,
+}
+
+impl FilePortForward {
+    /// Converts the definition into `PortForwardConfig`, one for each protocol.
+    ///
+    /// The source host defaults to the source peer IP of the selected peer for remote port forwards,
+    /// and to `127.0.0.1` otherwise.
+    pub fn to_port_forwards(&self, peers: &[PeerConfig]) -> anyhow::Result<Vec<PortForwardConfig>> {
+        self.parse(peers).with_context(|| match &self.name {
+            Some(name) => format!("Invalid port forward {} ('{}')", self.key, name),
+            None => format!("Invalid port forward {}", self.key),
+        })
+    }
+
+    fn parse(&self, peers: &[PeerConfig]) -> anyhow::Result<Vec<PortForwardConfig>> {
+        let peer = find_peer(peers, self.peer.as_deref());
+        if peer.is_none() && (self.remote || self.peer.is_some()) {
+            return Err(anyhow::anyhow!(
+                "{}.peer: unknown peer '{}'",
+                self.key,
+                self.peer.as_deref().unwrap_or_default()
+            ));
+        }
+        let default_source = match peer {
+            Some(peer) if self.remote => {
+                IpAddr::from(peer.source_peer_ips[0].address()).to_string()
+            }
+            _ => DEFAULT_PORT_FORWARD_SOURCE.to_string(),
+        };
+
+        let source = if let Ok(port) = self.source.parse::<u16>() {
+            resolve(&(default_source.as_str(), port))
+        } else {
+            resolve(&self.source.as_str())
+        }
+        .with_context(|| format!("{}.source: invalid address '{}'", self.key, self.source))?;
+
+        let (destination, destination_host) = match self.destination.parse::<SocketAddr>() {
+            Ok(addr) => Ok((addr, None)),
+            Err(_) => self
+                .destination
+                .rsplit_once(':')
+                .filter(|(host, _)| !host.is_empty() && !host.contains(':'))
+                .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+                .map(|(host, port)| parse_destination(host, port))
+                .with_context(|| "Expected <dst_host>:<dst_port>"),
+        }
+        .with_context(|| {
+            format!(
+                "{}.destination: invalid address '{}'",
+                self.key, self.destination
+            )
+        })?;
+
+        let protocols = if self.protocols.is_empty() {
+            vec![PortProtocol::Tcp]
+        } else {
+            self.protocols
+                .iter()
+                .map(|p| PortProtocol::try_from(p.as_str()))
+                .collect::<anyhow::Result<Vec<PortProtocol>>>()
+                .with_context(|| format!("{}.protocols: invalid protocol", self.key))?
+        };
+
+        let name: Option<Arc<str>> = self.name.as_deref().map(Arc::from);
+        let peer: Option<Arc<str>> = self.peer.as_deref().map(Arc::from);
+        Ok(protocols
+            .into_iter()
+            .map(|protocol| PortForwardConfig {
+                name: name.clone(),
+                source,
+                destination,
+                destination_host: destination_host.clone(),
+                protocol,
+                remote: self.remote,
+                peer: peer.clone(),
+            })
+            .collect())
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: &A) -> anyhow::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .with_context(|| "Could not resolve address")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// Tests the conversion of a port forward definition.
+    #[test]
+    fn test_file_port_forward() {
+        let pf = FilePortForward {
+            key: "port_forwards[0]".into(),
+            name: Some("web".into()),
+            source: "8080".into(),
+            destination: "192.168.4.2:80".into(),
+            protocols: vec!["tcp".into(), "udp".into()],
+            remote: false,
+            peer: None,
+        };
+        let name: Option<Arc<str>> = Some(Arc::from("web"));
+        assert_eq!(
+            pf.to_port_forwards(&[]).expect("Failed to convert"),
+            vec![
+                PortForwardConfig {
+                    name: name.clone(),
+                    source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+                    destination: SocketAddr::from_str("192.168.4.2:80").unwrap(),
+                    destination_host: None,
+                    protocol: PortProtocol::Tcp,
+                    remote: false,
+                    peer: None,
+                },
+                PortForwardConfig {
+                    name,
+                    source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+                    destination: SocketAddr::from_str("192.168.4.2:80").unwrap(),
+                    destination_host: None,
+                    protocol: PortProtocol::Udp,
+                    remote: false,
+                    peer: None,
+                }
+            ]
+        );
+    }
+
+    /// Tests that remote port forwards are bound on the source peer IP of the selected peer.
+    #[test]
+    fn test_file_port_forward_peer() {
+        let peers = ["office", "lab"]
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                FilePeer {
+                    key: format!("peers[{}]", index),
+                    name: name.to_string(),
+                    endpoint_public_key: Some(
+                        "t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk=".into(),
+                    ),
+                    preshared_key: None,
+                    endpoint_addr: Some("140.30.3.182:51820".into()),
+                    source_peer_ip: Some(format!("10.0.{}.3", index)),
+                    allowed_ips: None,
+                    keep_alive: None,
+                }
+                .to_peer_config(None)
+                .expect("Failed to convert peer")
+            })
+            .collect::<Vec<_>>();
+
+        let mut pf = FilePortForward {
+            key: "port_forwards[0]".into(),
+            name: None,
+            source: "2222".into(),
+            destination: "127.0.0.1:22".into(),
+            protocols: vec![],
+            remote: true,
+            peer: Some("lab".into()),
+        };
+        let port_forwards = pf.to_port_forwards(&peers).expect("Failed to convert");
+        assert_eq!(
+            port_forwards[0].source,
+            SocketAddr::from_str("10.0.1.3:2222").unwrap()
+        );
+        assert_eq!(
+            port_forwards[0].select_peer(&peers).unwrap().name,
+            peers[1].name
+        );
+
+        pf.peer = Some("home".into());
+        let error = pf
+            .to_port_forwards(&peers)
+            .expect_err("Unknown peer should be rejected");
+        assert!(format!("{:#}", error).ends_with("port_forwards[0].peer: unknown peer 'home'"));
+    }
+
+    /// Tests that errors point to the invalid key.
+    #[test]
+    fn test_file_port_forward_invalid() {
+        let pf = FilePortForward {
+            key: "port_forwards[3]".into(),
+            name: Some("db".into()),
+            source: "5432".into(),
+            destination: "192.168.4.2".into(),
+            protocols: vec![],
+            remote: false,
+            peer: None,
+        };
+        let error = pf
+            .to_port_forwards(&[])
+            .expect_err("Missing destination port should be rejected");
+        assert_eq!(
+            format!("{:#}", error)
+                .split(": ")
+                .take(3)
+                .collect::<Vec<_>>(),
+            vec![
+                "Invalid port forward port_forwards[3] ('db')",
+                "port_forwards[3].destination",
+                "invalid address '192.168.4.2'"
+            ]
+        );
+    }
+}
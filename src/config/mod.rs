@@ -0,0 +1,1609 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::fs::read_to_string;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+pub use boringtun::crypto::{X25519PublicKey, X25519SecretKey};
+use smoltcp::wire::{IpAddress, IpCidr};
+
+use crate::config::file::{ConfigFile, FilePortForward};
+
+mod file;
+mod native;
+mod wg_quick;
+
+const DEFAULT_PORT_FORWARD_SOURCE: &str = "127.0.0.1";
+const DEFAULT_MTU: &str = "1420";
+const DEFAULT_LOG: &str = "info";
+/// The name of the peer configured by the CLI arguments, environment variables and top-level config file values.
+const DEFAULT_PEER_NAME: &str = "default";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub port_forwards: Vec<PortForwardConfig>,
+    pub remote_port_forwards: Vec<PortForwardConfig>,
+    pub private_key: Arc<X25519SecretKey>,
+    /// The WireGuard peers to connect to. Port forwards without a peer name use the first one.
+    pub peers: Vec<PeerConfig>,
+    /// The address of the UDP socket of the tunnel. `[::]` binds a dual-stack socket, which reaches both IPv4 and
+    /// IPv6 endpoints.
+    pub endpoint_bind_addr: SocketAddr,
+    pub max_transmission_unit: usize,
+    pub log: String,
+    pub warnings: Vec<String>,
+    pub pcap_file: Option<String>,
+    /// The address of the SOCKS5 proxy server, if enabled.
+    pub socks5_addr: Option<SocketAddr>,
+    /// The address of the HTTP proxy server, if enabled.
+    pub http_proxy_addr: Option<SocketAddr>,
+    /// The credentials required by the HTTP proxy server, in the format `user:password`.
+    pub http_proxy_auth: Option<String>,
+    /// The DNS server used to resolve destination host names, reached through the tunnel.
+    /// Host names are resolved by onetun's host if omitted.
+    pub dns_server: Option<SocketAddr>,
+    /// The address of the local DNS forwarder, if enabled.
+    pub dns_forwarder_addr: Option<SocketAddr>,
+    /// Whether the DNS forwarder caches the responses.
+    pub dns_forwarder_cache: bool,
+    /// The path of the config file, read again when the port forwards are reloaded.
+    pub config_file: Option<String>,
+    /// The port forwards given by the CLI arguments and environment variables, which are kept on reload.
+    pub arg_port_forwards: Vec<PortForwardConfig>,
+    /// The address of the control API, if enabled.
+    pub control_addr: Option<ControlAddr>,
+    /// The address of the Prometheus metrics server, if enabled.
+    pub metrics_addr: Option<SocketAddr>,
+    /// How long to wait for the first WireGuard handshake on startup, before failing.
+    pub wait_handshake: Option<Duration>,
+    /// How the port forwards, proxies and servers are restarted when they fail.
+    pub restart_policy: RestartPolicy,
+}
+
+impl Config {
+    #[cfg(feature = "bin")]
+    pub fn from_args() -> anyhow::Result<Self> {
+        use clap::{App, Arg};
+
+        let mut warnings = vec![];
+
+        let matches = App::new("onetun")
+            .author("Aram Peres <aram.peres@gmail.com>")
+            .version(env!("CARGO_PKG_VERSION"))
+            .args(&[
+                Arg::with_name("PORT_FORWARD")
+                    .required(false)
+                    .multiple(true)
+                    .takes_value(true)
+                    .help("Port forward configurations. The format of each argument is [src_host:]<src_port>:<dst_host>:<dst_port>[:TCP,UDP,...], \
+                    where [src_host] is the local IP to listen on, <src_port> is the local port to listen on, <dst_host> is the remote peer IP to forward to, and <dst_port> is the remote port to forward to. \
+                    Environment variables of the form 'ONETUN_PORT_FORWARD_[#]' are also accepted, where [#] starts at 1.\n\
+                    Examples:\n\
+                    \t127.0.0.1:8080:192.168.4.1:8081:TCP,UDP\n\
+                    \t127.0.0.1:8080:192.168.4.1:8081:TCP\n\
+                    \t0.0.0.0:8080:192.168.4.1:8081\n\
+                    \t[::1]:8080:192.168.4.1:8081\n\
+                    \t8080:192.168.4.1:8081\n\
+                    \t8080:192.168.4.1:8081:TCP\n\
+                    \tlocalhost:8080:192.168.4.1:8081:TCP\n\
+                    \tlocalhost:8080:peer.intranet:8081:TCP\
+                    "),
+                Arg::with_name("config")
+                    .required(false)
+                    .takes_value(true)
+                    .long("config")
+                    .env("ONETUN_CONFIG")
+                    .help("The path to a configuration file. Files ending in '.toml', '.yaml' or '.yml' use the onetun format, \
+                    which supports all the options and a list of named port forwards. Other files use the wg-quick format (e.g. wg0.conf), \
+                    where the [Interface] and [Peer] values are used for the WireGuard options of this peer. \
+                    CLI arguments and environment variables take precedence over the values in the file."),
+                Arg::with_name("private-key")
+                    .required_unless_one(&["private-key-file", "config"])
+                    .takes_value(true)
+                    .long("private-key")
+                    .env("ONETUN_PRIVATE_KEY")
+                    .help("The private key of this peer. The corresponding public key should be registered in the WireGuard endpoint. \
+                    You can also use '--private-key-file' to specify a file containing the key instead."),
+                Arg::with_name("private-key-file")
+                    .takes_value(true)
+                    .long("private-key-file")
+                    .env("ONETUN_PRIVATE_KEY_FILE")
+                    .help("The path to a file containing the private key of this peer. The corresponding public key should be registered in the WireGuard endpoint."),
+                Arg::with_name("endpoint-public-key")
+                    .required_unless("config")
+                    .takes_value(true)
+                    .long("endpoint-public-key")
+                    .env("ONETUN_ENDPOINT_PUBLIC_KEY")
+                    .help("The public key of the WireGuard endpoint (remote)."),
+                Arg::with_name("preshared-key")
+                    .required(false)
+                    .takes_value(true)
+                    .long("preshared-key")
+                    .env("ONETUN_PRESHARED_KEY")
+                    .help("The pre-shared key (PSK) as configured with the peer."),
+                Arg::with_name("endpoint-addr")
+                    .required_unless("config")
+                    .takes_value(true)
+                    .long("endpoint-addr")
+                    .env("ONETUN_ENDPOINT_ADDR")
+                    .help("The address (IP or host name + port) of the WireGuard endpoint (remote). \
+                    Several addresses can be given in order of preference, separated by commas: the next one is used \
+                    when the handshakes with the current one fail. \
+                    A host name is resolved again while the endpoint is unreachable. Example: 1.2.3.4:51820"),
+                Arg::with_name("allowed-ips")
+                    .required(false)
+                    .takes_value(true)
+                    .long("allowed-ips")
+                    .env("ONETUN_ALLOWED_IPS")
+                    .help("The IP ranges reachable through the WireGuard endpoint, separated by commas, like AllowedIPs in wg-quick. \
+                    Packets to other IPs are not sent to this peer, and packets received from other IPs are dropped. \
+                    Example: 192.168.4.0/24,fd00::/64. Defaults to all IPs."),
+                Arg::with_name("endpoint-bind-addr")
+                    .required(false)
+                    .takes_value(true)
+                    .long("endpoint-bind-addr")
+                    .env("ONETUN_ENDPOINT_BIND_ADDR")
+                    .help("The address (IP + port) used to bind the local UDP socket for the WireGuard tunnel. Example: 1.2.3.4:30000. \
+                    An address of [::] binds a dual-stack socket, which reaches both IPv4 and IPv6 endpoints. \
+                    Defaults to 0.0.0.0:0 if all the endpoints are IPv4, or [::]:0 otherwise."),
+                Arg::with_name("source-peer-ip")
+                    .required_unless("config")
+                    .takes_value(true)
+                    .long("source-peer-ip")
+                    .env("ONETUN_SOURCE_PEER_IP")
+                    .help("The source IP to identify this peer as (local), with an optional prefix length. \
+                    Several IPs can be given, separated by commas, like Address in wg-quick: each connection uses the first one \
+                    of the IP version of its destination. Example: 192.168.4.3/24,fd00::3/64"),
+                Arg::with_name("keep-alive")
+                    .required(false)
+                    .takes_value(true)
+                    .long("keep-alive")
+                    .env("ONETUN_KEEP_ALIVE")
+                    .help("Configures a persistent keep-alive for the WireGuard tunnel, in seconds."),
+                Arg::with_name("max-transmission-unit")
+                    .required(false)
+                    .takes_value(true)
+                    .long("max-transmission-unit")
+                    .env("ONETUN_MTU")
+                    .help("Configures the max-transmission-unit (MTU) of the WireGuard tunnel. Defaults to 1420."),
+                Arg::with_name("log")
+                    .required(false)
+                    .takes_value(true)
+                    .long("log")
+                    .env("ONETUN_LOG")
+                    .help("Configures the log level and format. Defaults to info."),
+                Arg::with_name("pcap")
+                    .required(false)
+                    .takes_value(true)
+                    .long("pcap")
+                    .env("ONETUN_PCAP")
+                    .help("Decrypts and captures IP packets on the WireGuard tunnel to a given output file."),
+                Arg::with_name("dns")
+                    .required(false)
+                    .takes_value(true)
+                    .long("dns")
+                    .env("ONETUN_DNS")
+                    .help("The DNS server used to resolve the host names of destinations, reached through the WireGuard tunnel. \
+                    The port defaults to 53. Names are resolved for each connection, and cached for the TTL of the records. \
+                    If omitted, names are resolved by onetun's host. Example: 192.168.4.1"),
+                Arg::with_name("dns-forwarder")
+                    .required(false)
+                    .takes_value(true)
+                    .long("dns-forwarder")
+                    .env("ONETUN_DNS_FORWARDER")
+                    .help("Starts a DNS server on the given address (IP + port), over UDP and TCP, which forwards the queries of local applications \
+                    to the DNS server given by --dns. Example: 127.0.0.1:5353"),
+                Arg::with_name("dns-forwarder-cache")
+                    .required(false)
+                    .takes_value(false)
+                    .long("dns-forwarder-cache")
+                    .help("Caches the responses of the DNS forwarder, for the TTL of their records."),
+                Arg::with_name("socks5")
+                    .required(false)
+                    .takes_value(true)
+                    .long("socks5")
+                    .env("ONETUN_SOCKS5")
+                    .help("Starts a SOCKS5 proxy server on the given address (IP + port), which connects to any host and port through the WireGuard tunnel. \
+                    The CONNECT and UDP ASSOCIATE commands are supported, without authentication. Example: 127.0.0.1:1080"),
+                Arg::with_name("http-proxy")
+                    .required(false)
+                    .takes_value(true)
+                    .long("http-proxy")
+                    .env("ONETUN_HTTP_PROXY")
+                    .help("Starts an HTTP proxy server on the given address (IP + port), which connects to any host and port through the WireGuard tunnel. \
+                    Both CONNECT and plain http:// requests are supported. Example: 127.0.0.1:8080"),
+                Arg::with_name("http-proxy-auth")
+                    .required(false)
+                    .takes_value(true)
+                    .long("http-proxy-auth")
+                    .env("ONETUN_HTTP_PROXY_AUTH")
+                    .help("Requires clients of the HTTP proxy server to authenticate with these Basic credentials, in the format <user>:<password>. \
+                    For security, prefer the ONETUN_HTTP_PROXY_AUTH env variable or the config file."),
+                Arg::with_name("control")
+                    .required(false)
+                    .takes_value(true)
+                    .long("control")
+                    .env("ONETUN_CONTROL")
+                    .help("Starts the control API on the given address (IP + port), or on a Unix socket if given a path. \
+                    The API lists and changes the port forwards and connections of the running onetun, without authentication. \
+                    Examples: 127.0.0.1:9080, /run/onetun.sock"),
+                Arg::with_name("metrics")
+                    .required(false)
+                    .takes_value(true)
+                    .long("metrics")
+                    .env("ONETUN_METRICS")
+                    .help("Serves Prometheus metrics about the tunnel and its connections on the given address (IP + port), at /metrics. \
+                    Example: 127.0.0.1:9090"),
+                Arg::with_name("wait-handshake")
+                    .required(false)
+                    .takes_value(true)
+                    .long("wait-handshake")
+                    .env("ONETUN_WAIT_HANDSHAKE")
+                    .help("Waits for a WireGuard handshake to complete before listening, and fails if none completes within the given number of seconds."),
+                Arg::with_name("max-restarts")
+                    .required(false)
+                    .takes_value(true)
+                    .long("max-restarts")
+                    .env("ONETUN_MAX_RESTARTS")
+                    .help("The number of times a failed port forward, proxy or server is restarted before onetun exits with an error. \
+                    The count is reset once it has been running for a minute. Use 0 to exit on the first failure. [default: 5]"),
+                Arg::with_name("restart-backoff")
+                    .required(false)
+                    .takes_value(true)
+                    .long("restart-backoff")
+                    .env("ONETUN_RESTART_BACKOFF")
+                    .help("The number of seconds before the first restart of a failed port forward, proxy or server. \
+                    The delay doubles for each of the next restarts, up to a minute. [default: 1]"),
+                Arg::with_name("remote")
+                    .required(false)
+                    .takes_value(true)
+                    .multiple(true)
+                    .long("remote")
+                    .short("r")
+                    .help("Remote port forward configurations. The format of each argument is <src_port>:<dst_host>:<dst_port>[:TCP,UDP,...], \
+                    where <src_port> is the port the other peers will reach the server with, <dst_host> is the IP to forward to, and <dst_port> is the port to forward to. \
+                    The <src_port> will be bound on onetun's peer IP, as specified by --source-peer-ip. If you pass a different value for <src_host> here, it will be rejected.\n\
+                    Note: <dst_host>:<dst_port> must be reachable by onetun. If referring to another WireGuard peer, use --bridge instead (not supported yet).\n\
+                    Environment variables of the form 'ONETUN_REMOTE_PORT_FORWARD_[#]' are also accepted, where [#] starts at 1.\n\
+                    Examples:\n\
+                    \t--remote 8080:localhost:8081:TCP,UDP\n\
+                    \t--remote 8080:[::1]:8081:TCP\n\
+                    \t--remote 8080:google.com:80\
+                    "),
+            ]).get_matches();
+
+        // Read the config file, which provides the values missing from the CLI args and envs
+        let file = if let Some(config_file) = matches.value_of("config") {
+            let (group_readable, world_readable) =
+                is_file_insecurely_readable(config_file).unwrap_or_default();
+            if group_readable {
+                warnings.push("Config file is group-readable. This is insecure.".into());
+            }
+            if world_readable {
+                warnings.push("Config file is world-readable. This is insecure.".into());
+            }
+            ConfigFile::load(config_file)?
+        } else {
+            ConfigFile::default()
+        };
+        warnings.extend(file.warnings.iter().cloned());
+        let value_of = |name: &str, file_value: &Option<String>| -> Option<String> {
+            matches
+                .value_of(name)
+                .map(String::from)
+                .or_else(|| file_value.clone())
+        };
+
+        // Combine `PORT_FORWARD` arg and `ONETUN_PORT_FORWARD_#` envs
+        let mut port_forward_strings = HashSet::new();
+        if let Some(values) = matches.values_of("PORT_FORWARD") {
+            for value in values {
+                port_forward_strings.insert(value.to_owned());
+            }
+        }
+        for n in 1.. {
+            if let Ok(env) = std::env::var(format!("ONETUN_PORT_FORWARD_{}", n)) {
+                port_forward_strings.insert(env);
+            } else {
+                break;
+            }
+        }
+
+        // Parse `PORT_FORWARD` strings into `PortForwardConfig`
+        let port_forwards: anyhow::Result<Vec<Vec<PortForwardConfig>>> = port_forward_strings
+            .into_iter()
+            .map(|s| PortForwardConfig::from_notation(&s, DEFAULT_PORT_FORWARD_SOURCE))
+            .collect();
+        let mut port_forwards: Vec<PortForwardConfig> = port_forwards
+            .with_context(|| "Failed to parse port forward config")?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        // Read the default peer, which may be omitted if the config file defines other peers
+        let source_peer_ips = value_of("source-peer-ip", &file.source_peer_ip)
+            .map(|ips| parse_source_peer_ips(Some(&ips)))
+            .transpose()
+            .with_context(|| "Invalid source peer IP")?;
+        let endpoint_public_key = value_of("endpoint-public-key", &file.endpoint_public_key);
+        let mut peers = vec![];
+        if endpoint_public_key.is_some() || file.peers.is_empty() {
+            peers.push(PeerConfig {
+                name: DEFAULT_PEER_NAME.into(),
+                endpoint_public_key: Arc::new(
+                    parse_public_key(endpoint_public_key.as_deref())
+                        .with_context(|| "Invalid endpoint public key")?,
+                ),
+                preshared_key: parse_preshared_key(
+                    value_of("preshared-key", &file.preshared_key).as_deref(),
+                )?,
+                endpoints: parse_endpoints(
+                    value_of("endpoint-addr", &file.endpoint_addr).as_deref(),
+                )
+                .with_context(|| "Invalid endpoint address")?,
+                source_peer_ips: source_peer_ips
+                    .clone()
+                    .with_context(|| "Missing IP")
+                    .with_context(|| "Invalid source peer IP")?,
+                allowed_ips: parse_allowed_ips(
+                    value_of("allowed-ips", &file.allowed_ips).as_deref(),
+                )
+                .with_context(|| "Invalid allowed IPs")?,
+                keepalive_seconds: parse_keep_alive(
+                    value_of("keep-alive", &file.keep_alive).as_deref(),
+                )
+                .with_context(|| "Invalid keep-alive value")?,
+            });
+        }
+        for peer in file.peers.iter() {
+            peers.push(peer.to_peer_config(source_peer_ips.as_deref())?);
+        }
+        validate_peers(&peers)?;
+        for port_forward in port_forwards.iter() {
+            validate_destination(port_forward, &peers)?;
+        }
+
+        // Combined `remote` arg and `ONETUN_REMOTE_PORT_FORWARD_#` envs
+        let mut port_forward_strings = HashSet::new();
+        if let Some(values) = matches.values_of("remote") {
+            for value in values {
+                port_forward_strings.insert(value.to_owned());
+            }
+        }
+        for n in 1.. {
+            if let Ok(env) = std::env::var(format!("ONETUN_REMOTE_PORT_FORWARD_{}", n)) {
+                port_forward_strings.insert(env);
+            } else {
+                break;
+            }
+        }
+        // Parse `PORT_FORWARD` strings into `PortForwardConfig`
+        let remote_port_forwards: anyhow::Result<Vec<Vec<PortForwardConfig>>> =
+            port_forward_strings
+                .into_iter()
+                .map(|s| {
+                    PortForwardConfig::from_notation(
+                        &s,
+                        &IpAddr::from(peers[0].source_peer_ips[0].address()).to_string(),
+                    )
+                })
+                .collect();
+        let mut remote_port_forwards: Vec<PortForwardConfig> = remote_port_forwards
+            .with_context(|| "Failed to parse remote port forward config")?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        for port_forward in remote_port_forwards.iter_mut() {
+            port_forward.remote = true;
+            prepare_remote_port_forward(port_forward, &peers)?;
+        }
+        // The port forwards given by the args are kept when the config file is reloaded
+        let arg_port_forwards: Vec<PortForwardConfig> = port_forwards
+            .iter()
+            .chain(remote_port_forwards.iter())
+            .cloned()
+            .collect();
+
+        // Add the port forwards defined in the config file
+        for port_forward in file_port_forwards(&file, &peers)? {
+            if port_forward.remote {
+                remote_port_forwards.push(port_forward);
+            } else {
+                port_forwards.push(port_forward);
+            }
+        }
+
+        let socks5_addr = value_of("socks5", &file.socks5)
+            .map(|addr| parse_addr(Some(&addr)))
+            .transpose()
+            .with_context(|| "Invalid SOCKS5 proxy address")?;
+
+        let dns_server = value_of("dns", &file.dns)
+            .map(|s| parse_dns_server(&s))
+            .transpose()
+            .with_context(|| "Invalid DNS server")?;
+        if let Some(dns_server) = dns_server {
+            if route_peer(&peers, dns_server.ip()).is_none() {
+                return Err(anyhow::anyhow!(
+                    "The DNS server {} is not in the allowed IPs of any peer",
+                    dns_server
+                ));
+            }
+        }
+
+        let dns_forwarder_addr = value_of("dns-forwarder", &file.dns_forwarder)
+            .map(|addr| parse_addr(Some(&addr)))
+            .transpose()
+            .with_context(|| "Invalid DNS forwarder address")?;
+        if dns_forwarder_addr.is_some() && dns_server.is_none() {
+            return Err(anyhow::anyhow!(
+                "The DNS forwarder requires a DNS server to forward to (--dns)"
+            ));
+        }
+        let dns_forwarder_cache =
+            matches.is_present("dns-forwarder-cache") || file.dns_forwarder_cache;
+
+        let http_proxy_addr = value_of("http-proxy", &file.http_proxy)
+            .map(|addr| parse_addr(Some(&addr)))
+            .transpose()
+            .with_context(|| "Invalid HTTP proxy address")?;
+
+        if matches.value_of("http-proxy-auth").is_some()
+            && std::env::var("ONETUN_HTTP_PROXY_AUTH").is_err()
+        {
+            warnings.push(
+                "HTTP proxy credentials were passed using CLI. This is insecure. \
+            Use the \"ONETUN_HTTP_PROXY_AUTH\" env variable or the config file instead."
+                    .into(),
+            );
+        }
+        let http_proxy_auth = value_of("http-proxy-auth", &file.http_proxy_auth);
+        if let Some(auth) = &http_proxy_auth {
+            if !auth.contains(':') {
+                return Err(anyhow::anyhow!(
+                    "Invalid HTTP proxy credentials: expected <user>:<password>"
+                ));
+            }
+        }
+
+        let control_addr = value_of("control", &file.control)
+            .map(|addr| parse_control_addr(&addr))
+            .transpose()
+            .with_context(|| "Invalid control API address")?;
+        if let Some(ControlAddr::Tcp(addr)) = &control_addr {
+            if !addr.ip().is_loopback() {
+                warnings.push(format!(
+                    "The control API is reachable on a non-loopback address ({}) without authentication. This is insecure.",
+                    addr
+                ));
+            }
+        }
+
+        let metrics_addr = value_of("metrics", &file.metrics)
+            .map(|addr| parse_addr(Some(&addr)))
+            .transpose()
+            .with_context(|| "Invalid metrics address")?;
+
+        let wait_handshake = value_of("wait-handshake", &file.wait_handshake)
+            .map(|s| {
+                s.parse::<u64>()
+                    .map(Duration::from_secs)
+                    .with_context(|| format!("Expected a number of seconds, got '{}'", s))
+            })
+            .transpose()
+            .with_context(|| "Invalid handshake timeout")?;
+
+        let mut restart_policy = RestartPolicy::default();
+        if let Some(s) = value_of("max-restarts", &file.max_restarts) {
+            restart_policy.max_restarts = s
+                .parse()
+                .with_context(|| format!("Invalid max-restarts value: '{}'", s))?;
+        }
+        if let Some(s) = value_of("restart-backoff", &file.restart_backoff) {
+            restart_policy.backoff = s
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .with_context(|| format!("Invalid restart-backoff value: '{}'", s))?;
+        }
+
+        // Port forwards can be added later through the control API
+        if port_forwards.is_empty()
+            && remote_port_forwards.is_empty()
+            && socks5_addr.is_none()
+            && http_proxy_addr.is_none()
+            && dns_forwarder_addr.is_none()
+            && control_addr.is_none()
+        {
+            return Err(anyhow::anyhow!(
+                "No port forward configurations or proxy server given."
+            ));
+        }
+
+        // Read private key from file or CLI argument. The config file is only used if neither is given.
+        let private_key_file = matches.value_of("private-key-file").or_else(|| {
+            if matches.value_of("private-key").is_some() {
+                None
+            } else {
+                file.private_key_file.as_deref()
+            }
+        });
+        let (group_readable, world_readable) = private_key_file
+            .and_then(is_file_insecurely_readable)
+            .unwrap_or_default();
+        if group_readable {
+            warnings.push("Private key file is group-readable. This is insecure.".into());
+        }
+        if world_readable {
+            warnings.push("Private key file is world-readable. This is insecure.".into());
+        }
+
+        let private_key = if let Some(private_key_file) = private_key_file {
+            read_to_string(private_key_file)
+                .map(|s| s.trim().to_string())
+                .with_context(|| "Failed to read private key file")
+        } else if let Some(private_key) = matches.value_of("private-key") {
+            if std::env::var("ONETUN_PRIVATE_KEY").is_err() {
+                warnings.push("Private key was passed using CLI. This is insecure. \
+                Use \"--private-key-file <file containing private key>\", or the \"ONETUN_PRIVATE_KEY\" env variable instead.".into());
+            }
+            Ok(private_key.to_string())
+        } else {
+            file.private_key
+                .clone()
+                .with_context(|| "Missing private key")
+        }?;
+
+        let endpoint_bind_addr =
+            if let Some(addr) = value_of("endpoint-bind-addr", &file.endpoint_bind_addr) {
+                parse_addr(Some(&addr)).with_context(|| "Invalid bind address")?
+            } else {
+                // A dual-stack socket if any endpoint is IPv6, with the port from the config file
+                let port = parse_port(file.listen_port.as_deref())
+                    .with_context(|| "Invalid listen port")?;
+                if peers
+                    .iter()
+                    .flat_map(|peer| peer.endpoints.iter())
+                    .all(|endpoint| endpoint.addr.is_ipv4())
+                {
+                    SocketAddr::from(([0, 0, 0, 0], port))
+                } else {
+                    SocketAddr::from(([0u16; 8], port))
+                }
+            };
+        // All the peers use the same UDP socket
+        for peer in peers.iter() {
+            if let Some(endpoint) = peer
+                .endpoints
+                .iter()
+                .find(|endpoint| !can_reach(endpoint_bind_addr, endpoint.addr))
+            {
+                return Err(anyhow::anyhow!(
+                    "Endpoint {} of peer '{}' is not the IP version of the bind address {} (bind [::] for both)",
+                    endpoint,
+                    peer.name,
+                    endpoint_bind_addr
+                ));
+            }
+        }
+
+        Ok(Self {
+            port_forwards,
+            remote_port_forwards,
+            private_key: Arc::new(
+                parse_private_key(&private_key).with_context(|| "Invalid private key")?,
+            ),
+            peers,
+            endpoint_bind_addr,
+            max_transmission_unit: parse_mtu(Some(
+                value_of("max-transmission-unit", &file.max_transmission_unit)
+                    .as_deref()
+                    .unwrap_or(DEFAULT_MTU),
+            ))
+            .with_context(|| "Invalid max-transmission-unit value")?,
+            log: value_of("log", &file.log).unwrap_or_else(|| DEFAULT_LOG.into()),
+            pcap_file: value_of("pcap", &file.pcap),
+            socks5_addr,
+            http_proxy_addr,
+            http_proxy_auth,
+            dns_server,
+            dns_forwarder_addr,
+            dns_forwarder_cache,
+            config_file: matches.value_of("config").map(String::from),
+            arg_port_forwards,
+            control_addr,
+            metrics_addr,
+            wait_handshake,
+            restart_policy,
+            warnings,
+        })
+    }
+}
+
+/// How the port forwards, proxies and servers are restarted when they fail.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RestartPolicy {
+    /// The number of restarts before a failure is fatal. Zero makes the first failure fatal.
+    pub max_restarts: u32,
+    /// The delay before the first restart, doubled for each of the next ones.
+    pub backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the port forwards from the config file again, along with those given by the args.
+    /// The other values of the config file are only read on startup.
+    pub fn reload_port_forwards(&self) -> anyhow::Result<Vec<PortForwardConfig>> {
+        let config_file = self
+            .config_file
+            .as_deref()
+            .with_context(|| "No config file to reload")?;
+        let file = ConfigFile::load(config_file)?;
+        for warning in file.warnings.iter() {
+            warn!("{}", warning);
+        }
+
+        let mut port_forwards = self.arg_port_forwards.clone();
+        port_forwards.extend(file_port_forwards(&file, &self.peers)?);
+        Ok(port_forwards)
+    }
+}
+
+/// Converts the port forwards defined in the config file.
+fn file_port_forwards(
+    file: &ConfigFile,
+    peers: &[PeerConfig],
+) -> anyhow::Result<Vec<PortForwardConfig>> {
+    let mut port_forwards = Vec::new();
+    for port_forward in file.port_forwards.iter() {
+        port_forwards.extend(convert_file_port_forward(port_forward, peers)?);
+    }
+    Ok(port_forwards)
+}
+
+/// Parses a port forward definition in JSON, with the same keys as a `port_forwards` entry of the config file.
+/// One port forward is returned for each protocol.
+pub fn parse_port_forward_json(
+    s: &str,
+    peers: &[PeerConfig],
+) -> anyhow::Result<Vec<PortForwardConfig>> {
+    let port_forward = native::parse_json_port_forward(s)?;
+    convert_file_port_forward(&port_forward, peers)
+}
+
+fn convert_file_port_forward(
+    port_forward: &FilePortForward,
+    peers: &[PeerConfig],
+) -> anyhow::Result<Vec<PortForwardConfig>> {
+    let mut port_forwards = port_forward.to_port_forwards(peers)?;
+    for port_forward in port_forwards.iter_mut() {
+        if port_forward.remote {
+            prepare_remote_port_forward(port_forward, peers)?;
+        } else {
+            validate_destination(port_forward, peers)?;
+        }
+    }
+    Ok(port_forwards)
+}
+
+/// Checks that the destination IP of a local port forward can be reached through its peer,
+/// which requires a source peer IP of the same IP version. Host names are checked once resolved.
+fn validate_destination(
+    port_forward: &PortForwardConfig,
+    peers: &[PeerConfig],
+) -> anyhow::Result<()> {
+    if port_forward.destination_host.is_some() {
+        return Ok(());
+    }
+    let peer = port_forward
+        .select_peer(peers)
+        .with_context(|| format!("Unknown peer for port forward {}", port_forward))?;
+    let destination = port_forward.destination.ip();
+    if peer.source_peer_ip(destination).is_none() {
+        return Err(anyhow::anyhow!(
+            "Port forward {} has an {} destination, but its peer '{}' has no {} source peer IP ({})",
+            port_forward,
+            ip_version(destination),
+            peer.name,
+            ip_version(destination),
+            peer.display_source_peer_ips()
+        ));
+    }
+    Ok(())
+}
+
+fn ip_version(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
+
+/// Checks that a remote port forward is bound on a source peer IP of its peer, and resolves its destination.
+fn prepare_remote_port_forward(
+    port_forward: &mut PortForwardConfig,
+    peers: &[PeerConfig],
+) -> anyhow::Result<()> {
+    let peer = port_forward
+        .select_peer(peers)
+        .with_context(|| format!("Unknown peer for remote port forward {}", port_forward))?;
+    if !peer.is_source_peer_ip(port_forward.source.ip()) {
+        return Err(anyhow::anyhow!("Remote port forward config <src_host> must match a source peer IP of its peer ({}), or be omitted.", peer.display_source_peer_ips()));
+    }
+    // The destination of a remote port forward is reached from onetun's host, so it is resolved by the host
+    if let Some(host) = port_forward.destination_host.take() {
+        port_forward.destination = (host.as_ref(), port_forward.destination.port())
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("Could not resolve destination address {}", host))?;
+    }
+    Ok(())
+}
+
+/// Checks that the peer names are unique, so that port forwards can select their peer by name.
+fn validate_peers(peers: &[PeerConfig]) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    for peer in peers {
+        if !names.insert(peer.name.clone()) {
+            return Err(anyhow::anyhow!("Duplicate peer name '{}'", peer.name));
+        }
+    }
+    Ok(())
+}
+
+fn parse_addr(s: Option<&str>) -> anyhow::Result<SocketAddr> {
+    s.with_context(|| "Missing address")?
+        .to_socket_addrs()
+        .with_context(|| "Invalid address")?
+        .next()
+        .with_context(|| "Could not lookup address")
+}
+
+/// Parses the comma-separated addresses of the endpoints of a peer, in order of preference.
+/// The host names are kept, to resolve them again.
+fn parse_endpoints(s: Option<&str>) -> anyhow::Result<Vec<PeerEndpoint>> {
+    let endpoints = s
+        .with_context(|| "Missing address")?
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let addr = parse_addr(Some(s)).with_context(|| format!("Invalid address '{}'", s))?;
+            let host = match s.parse::<SocketAddr>() {
+                Ok(_) => None,
+                Err(_) => Some(Arc::from(s)),
+            };
+            Ok(PeerEndpoint { addr, host })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if endpoints.is_empty() {
+        return Err(anyhow::anyhow!("Missing address"));
+    }
+    Ok(endpoints)
+}
+
+/// Parses the host of a port forward destination, which is kept as given if it is not an IP address.
+fn parse_destination(host: &str, port: u16) -> (SocketAddr, Option<Arc<str>>) {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => (SocketAddr::new(ip, port), None),
+        Err(_) => (
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            Some(Arc::from(host)),
+        ),
+    }
+}
+
+/// Parses the address of the control API: a socket address, or the path of a Unix socket otherwise.
+fn parse_control_addr(s: &str) -> anyhow::Result<ControlAddr> {
+    if let Ok(addr) = parse_addr(Some(s)) {
+        return Ok(ControlAddr::Tcp(addr));
+    }
+    if cfg!(unix) {
+        Ok(ControlAddr::Unix(PathBuf::from(s)))
+    } else {
+        Err(anyhow::anyhow!("Invalid address '{}'", s))
+    }
+}
+
+/// Parses the address of a DNS server, with the port defaulting to 53.
+fn parse_dns_server(s: &str) -> anyhow::Result<SocketAddr> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .with_context(|| format!("Invalid DNS server address '{}'", s))
+}
+
+fn parse_ip(s: Option<&str>) -> anyhow::Result<IpAddr> {
+    s.with_context(|| "Missing IP")?
+        .parse::<IpAddr>()
+        .with_context(|| "Invalid IP address")
+}
+
+/// Parses a comma-separated list of IP ranges. An IP without a prefix length is a single-address range.
+fn parse_allowed_ips(s: Option<&str>) -> anyhow::Result<Vec<IpCidr>> {
+    if let Some(s) = s {
+        parse_cidrs(s)
+    } else {
+        Ok(vec![
+            IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0),
+            IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0), 0),
+        ])
+    }
+}
+
+/// Parses the comma-separated source peer IPs, with optional prefix lengths. Not empty.
+fn parse_source_peer_ips(s: Option<&str>) -> anyhow::Result<Vec<IpCidr>> {
+    let ips = parse_cidrs(s.with_context(|| "Missing IP")?)?;
+    if ips.is_empty() {
+        return Err(anyhow::anyhow!("Missing IP"));
+    }
+    Ok(ips)
+}
+
+/// Parses comma-separated IP addresses with optional prefix lengths (a single address by default).
+fn parse_cidrs(s: &str) -> anyhow::Result<Vec<IpCidr>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            if s.contains('/') {
+                IpCidr::from_str(s).map_err(|_| anyhow::anyhow!("Invalid IP range '{}'", s))
+            } else {
+                let ip = parse_ip(Some(s))?;
+                Ok(IpCidr::new(
+                    IpAddress::from(ip),
+                    if ip.is_ipv4() { 32 } else { 128 },
+                ))
+            }
+        })
+        .collect()
+}
+
+fn parse_private_key(s: &str) -> anyhow::Result<X25519SecretKey> {
+    s.parse::<X25519SecretKey>()
+        .map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+fn parse_public_key(s: Option<&str>) -> anyhow::Result<X25519PublicKey> {
+    s.with_context(|| "Missing public key")?
+        .parse::<X25519PublicKey>()
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .with_context(|| "Invalid public key")
+}
+
+fn parse_preshared_key(s: Option<&str>) -> anyhow::Result<Option<[u8; 32]>> {
+    if let Some(s) = s {
+        let psk = base64::decode(s).with_context(|| "Invalid pre-shared key")?;
+        Ok(Some(psk.try_into().map_err(|_| {
+            anyhow::anyhow!("Unsupported pre-shared key")
+        })?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn parse_keep_alive(s: Option<&str>) -> anyhow::Result<Option<u16>> {
+    if let Some(s) = s {
+        let parsed: u16 = s.parse().with_context(|| {
+            format!(
+                "Keep-alive must be a number between 0 and {} seconds",
+                u16::MAX
+            )
+        })?;
+        Ok(Some(parsed))
+    } else {
+        Ok(None)
+    }
+}
+
+fn parse_port(s: Option<&str>) -> anyhow::Result<u16> {
+    if let Some(s) = s {
+        s.parse().with_context(|| "Invalid port")
+    } else {
+        Ok(0)
+    }
+}
+
+fn parse_mtu(s: Option<&str>) -> anyhow::Result<usize> {
+    s.with_context(|| "Missing MTU")?
+        .parse()
+        .with_context(|| "Invalid MTU")
+}
+
+#[cfg(unix)]
+fn is_file_insecurely_readable(path: &str) -> Option<(bool, bool)> {
+    use std::fs::File;
+    use std::os::unix::fs::MetadataExt;
+
+    let mode = File::open(path).ok()?.metadata().ok()?.mode();
+    Some((mode & 0o40 > 0, mode & 0o4 > 0))
+}
+
+#[cfg(not(unix))]
+fn is_file_insecurely_readable(_path: &str) -> Option<(bool, bool)> {
+    // No good way to determine permissions on non-Unix target
+    None
+}
+
+/// The address the control API listens on.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ControlAddr {
+    Tcp(SocketAddr),
+    /// The path of a Unix socket.
+    Unix(PathBuf),
+}
+
+impl Display for ControlAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlAddr::Tcp(addr) => write!(f, "{}", addr),
+            ControlAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A WireGuard peer that onetun connects to.
+#[derive(Clone, Debug)]
+pub struct PeerConfig {
+    /// The name of the peer, used by port forwards to select it.
+    pub name: Arc<str>,
+    pub endpoint_public_key: Arc<X25519PublicKey>,
+    pub preshared_key: Option<[u8; 32]>,
+    /// The endpoints of the peer, in order of preference. Not empty.
+    /// The next one is used when the handshakes with the active one fail.
+    pub endpoints: Vec<PeerEndpoint>,
+    /// The source IPs to identify onetun as, in the network of this peer, with the prefix lengths of their networks
+    /// (like `Address` in wg-quick). Not empty. Each connection uses the first one of the IP version of its destination.
+    /// Peers may share the same source peer IPs, in which case packets are routed by `allowed_ips`.
+    pub source_peer_ips: Vec<IpCidr>,
+    /// The IP ranges reachable through this peer (cryptokey routing).
+    pub allowed_ips: Vec<IpCidr>,
+    pub keepalive_seconds: Option<u16>,
+}
+
+impl PeerConfig {
+    /// The endpoints, in order of preference.
+    pub fn display_endpoints(&self) -> String {
+        self.endpoints
+            .iter()
+            .map(PeerEndpoint::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The source IP for connections to the destination: the first source peer IP of its IP version.
+    pub fn source_peer_ip(&self, destination: IpAddr) -> Option<IpAddr> {
+        self.source_peer_ips
+            .iter()
+            .map(|cidr| IpAddr::from(cidr.address()))
+            .find(|ip| ip.is_ipv4() == destination.is_ipv4())
+    }
+
+    /// Whether the IP is one of the source peer IPs, i.e. packets to it are for onetun.
+    pub fn is_source_peer_ip(&self, ip: IpAddr) -> bool {
+        let ip = IpAddress::from(ip);
+        self.source_peer_ips.iter().any(|cidr| cidr.address() == ip)
+    }
+
+    /// The source peer IPs, with their prefix lengths.
+    pub fn display_source_peer_ips(&self) -> String {
+        self.source_peer_ips
+            .iter()
+            .map(IpCidr::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Whether the IP is in one of the allowed IP ranges of the peer.
+    /// Returns the prefix length of the most specific matching range.
+    pub fn allowed_prefix_len(&self, ip: IpAddr) -> Option<u8> {
+        let ip = IpAddress::from(ip);
+        self.allowed_ips
+            .iter()
+            .filter(|cidr| cidr.contains_addr(&ip))
+            .map(|cidr| cidr.prefix_len())
+            .max()
+    }
+
+    /// A peer for the tests, with the given source peer IPs and allowed IPs (all IPs if `None`).
+    #[cfg(test)]
+    pub(crate) fn test(source_peer_ips: &str, allowed_ips: Option<&str>) -> Self {
+        Self {
+            name: DEFAULT_PEER_NAME.into(),
+            endpoint_public_key: Arc::new(
+                parse_public_key(Some("t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk=")).unwrap(),
+            ),
+            preshared_key: None,
+            endpoints: vec![SocketAddr::from(([140, 30, 3, 182], 51820)).into()],
+            source_peer_ips: parse_source_peer_ips(Some(source_peer_ips)).unwrap(),
+            allowed_ips: parse_allowed_ips(allowed_ips).unwrap(),
+            keepalive_seconds: None,
+        }
+    }
+}
+
+/// An endpoint of a peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerEndpoint {
+    /// The address of the endpoint. If it is given as a host name, this is the address it resolved to on startup.
+    pub addr: SocketAddr,
+    /// The host name and port of the endpoint, which is resolved again while the peer is unreachable.
+    /// `None` if the endpoint is given as an IP address.
+    pub host: Option<Arc<str>>,
+}
+
+impl From<SocketAddr> for PeerEndpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Self { addr, host: None }
+    }
+}
+
+impl Display for PeerEndpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            Some(host) => write!(f, "{} ({})", host, self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Whether a UDP socket bound on `bind_addr` can send datagrams to `addr`: if they are the same IP version,
+/// or if the socket is dual-stack.
+pub fn can_reach(bind_addr: SocketAddr, addr: SocketAddr) -> bool {
+    bind_addr.is_ipv4() == addr.is_ipv4() || is_dual_stack(bind_addr)
+}
+
+/// Whether a UDP socket bound on `bind_addr` is dual-stack, i.e. bound on `[::]`.
+pub fn is_dual_stack(bind_addr: SocketAddr) -> bool {
+    match bind_addr.ip() {
+        IpAddr::V6(ip) => ip.is_unspecified(),
+        IpAddr::V4(_) => false,
+    }
+}
+
+/// Finds the peer to reach the destination IP through, for connections without a port forward.
+/// This is the peer with the most specific allowed IP range containing the destination,
+/// among the peers with a source peer IP of the same IP version.
+pub fn route_peer(peers: &[PeerConfig], destination: IpAddr) -> Option<&PeerConfig> {
+    peers
+        .iter()
+        .filter(|peer| peer.source_peer_ip(destination).is_some())
+        .filter_map(|peer| {
+            peer.allowed_prefix_len(destination)
+                .map(|prefix_len| (peer, prefix_len))
+        })
+        // On equal prefix lengths, the first configured peer wins
+        .rev()
+        .max_by_key(|(_, prefix_len)| *prefix_len)
+        .map(|(peer, _)| peer)
+}
+
+/// Finds the peer with the given name, or the first peer if no name is given.
+fn find_peer<'a>(peers: &'a [PeerConfig], name: Option<&str>) -> Option<&'a PeerConfig> {
+    match name {
+        Some(name) => peers.iter().find(|peer| &*peer.name == name),
+        None => peers.first(),
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PortForwardConfig {
+    /// The name of the port forward, as given in the config file.
+    pub name: Option<Arc<str>>,
+    /// The source IP and port where the local server will run.
+    pub source: SocketAddr,
+    /// The destination IP and port to which traffic will be forwarded.
+    /// If the destination is given as a host name, only the port is set until it is resolved.
+    pub destination: SocketAddr,
+    /// The host name of the destination, which is resolved for each connection (see `DnsResolver`).
+    /// `None` once resolved, or if the destination is an IP address.
+    pub destination_host: Option<Arc<str>>,
+    /// The transport protocol to use for the port (Layer 4).
+    pub protocol: PortProtocol,
+    /// Whether this is a remote port forward.
+    pub remote: bool,
+    /// The name of the peer to forward through. The first peer is used if omitted.
+    pub peer: Option<Arc<str>>,
+}
+
+impl PortForwardConfig {
+    /// Finds the peer that this port forward goes through.
+    pub fn select_peer<'a>(&self, peers: &'a [PeerConfig]) -> Option<&'a PeerConfig> {
+        find_peer(peers, self.peer.as_deref())
+    }
+
+    /// The destination, with its host name if it is not resolved yet.
+    pub fn display_destination(&self) -> String {
+        match &self.destination_host {
+            Some(host) => format!("{}:{}", host, self.destination.port()),
+            None => self.destination.to_string(),
+        }
+    }
+
+    /// Converts a string representation into `PortForwardConfig`.
+    ///
+    /// Sample formats:
+    ///  - `127.0.0.1:8080:192.168.4.1:8081:TCP,UDP`
+    ///  - `127.0.0.1:8080:192.168.4.1:8081:TCP`
+    ///  - `0.0.0.0:8080:192.168.4.1:8081`
+    ///  - `[::1]:8080:192.168.4.1:8081`
+    ///  - `8080:192.168.4.1:8081`
+    ///  - `8080:192.168.4.1:8081:TCP`
+    ///  - `localhost:8080:192.168.4.1:8081:TCP`
+    ///  - `localhost:8080:peer.intranet:8081:TCP`
+    ///
+    /// Implementation Notes:
+    ///  - The format is formalized as `[src_host:]<src_port>:<dst_host>:<dst_port>[:PROTO1,PROTO2,...]`
+    ///  - `src_host` is optional and defaults to `127.0.0.1`.
+    ///  - `src_host` and `dst_host` may be specified as IPv4, IPv6, or a FQDN to be resolved by DNS.
+    ///    `dst_host` is resolved for each connection, through the tunnel if a DNS server is configured.
+    ///  - IPv6 addresses must be prefixed with `[` and suffixed with `]`. Example: `[::1]`.
+    ///  - Any `u16` is accepted as `src_port` and `dst_port`
+    ///  - Specifying protocols (`PROTO1,PROTO2,...`) is optional and defaults to `TCP`. Values must be separated by commas.
+    pub fn from_notation(s: &str, default_source: &str) -> anyhow::Result<Vec<PortForwardConfig>> {
+        mod parsers {
+            use nom::branch::alt;
+            use nom::bytes::complete::is_not;
+            use nom::character::complete::{alpha1, char, digit1};
+            use nom::combinator::{complete, map, opt, success};
+            use nom::error::ErrorKind;
+            use nom::multi::separated_list1;
+            use nom::sequence::{delimited, preceded, separated_pair, tuple};
+            use nom::IResult;
+
+            fn ipv6(s: &str) -> IResult<&str, &str> {
+                delimited(char('['), is_not("]"), char(']'))(s)
+            }
+
+            fn ipv4_or_fqdn(s: &str) -> IResult<&str, &str> {
+                let s = is_not(":")(s)?;
+                if s.1.chars().all(|c| c.is_ascii_digit()) {
+                    // If ipv4 or fqdn is all digits, it's not valid.
+                    Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+                        s.1,
+                        ErrorKind::Fail,
+                    )))
+                } else {
+                    Ok(s)
+                }
+            }
+
+            fn port(s: &str) -> IResult<&str, &str> {
+                digit1(s)
+            }
+
+            fn ip_or_fqdn(s: &str) -> IResult<&str, &str> {
+                alt((ipv6, ipv4_or_fqdn))(s)
+            }
+
+            fn no_ip(s: &str) -> IResult<&str, Option<&str>> {
+                success(None)(s)
+            }
+
+            fn src_addr(s: &str) -> IResult<&str, (Option<&str>, &str)> {
+                let with_ip = separated_pair(map(ip_or_fqdn, Some), char(':'), port);
+                let without_ip = tuple((no_ip, port));
+                alt((with_ip, without_ip))(s)
+            }
+
+            fn dst_addr(s: &str) -> IResult<&str, (&str, &str)> {
+                separated_pair(ip_or_fqdn, char(':'), port)(s)
+            }
+
+            fn protocol(s: &str) -> IResult<&str, &str> {
+                alpha1(s)
+            }
+
+            fn protocols(s: &str) -> IResult<&str, Option<Vec<&str>>> {
+                opt(preceded(char(':'), separated_list1(char(','), protocol)))(s)
+            }
+
+            #[allow(clippy::type_complexity)]
+            pub fn port_forward(
+                s: &str,
+            ) -> IResult<&str, ((Option<&str>, &str), (), (&str, &str), Option<Vec<&str>>)>
+            {
+                complete(tuple((
+                    src_addr,
+                    map(char(':'), |_| ()),
+                    dst_addr,
+                    protocols,
+                )))(s)
+            }
+        }
+
+        // TODO: Could improve error management with custom errors, so that the messages are more helpful.
+        let (src_addr, _, dst_addr, protocols) = parsers::port_forward(s)
+            .map_err(|e| anyhow::anyhow!("Invalid port-forward definition: {}", e))?
+            .1;
+
+        let source = (
+            src_addr.0.unwrap_or(default_source),
+            src_addr
+                .1
+                .parse::<u16>()
+                .with_context(|| "Invalid source port")?,
+        )
+            .to_socket_addrs()
+            .with_context(|| "Invalid source address")?
+            .next()
+            .with_context(|| "Could not resolve source address")?;
+
+        let destination_port = dst_addr
+            .1
+            .parse::<u16>()
+            .with_context(|| "Invalid destination port")?;
+        let (destination, destination_host) = parse_destination(dst_addr.0, destination_port);
+
+        // Parse protocols
+        let protocols = if let Some(protocols) = protocols {
+            let protocols: anyhow::Result<Vec<PortProtocol>> =
+                protocols.into_iter().map(PortProtocol::try_from).collect();
+            protocols
+        } else {
+            Ok(vec![PortProtocol::Tcp])
+        }
+        .with_context(|| "Failed to parse protocols")?;
+
+        // Returns an config for each protocol
+        Ok(protocols
+            .into_iter()
+            .map(|protocol| Self {
+                name: None,
+                source,
+                destination,
+                destination_host: destination_host.clone(),
+                protocol,
+                remote: false,
+                peer: None,
+            })
+            .collect())
+    }
+}
+
+impl Display for PortForwardConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.remote {
+            write!(
+                f,
+                "(remote){}:{}:{}",
+                self.source,
+                self.display_destination(),
+                self.protocol
+            )?;
+        } else {
+            write!(
+                f,
+                "{}:{}:{}",
+                self.source,
+                self.display_destination(),
+                self.protocol
+            )?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, "({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Layer 7 protocols for ports.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum PortProtocol {
+    /// TCP
+    Tcp,
+    /// UDP
+    Udp,
+}
+
+impl TryFrom<&str> for PortProtocol {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> anyhow::Result<Self> {
+        match value.to_uppercase().as_str() {
+            "TCP" => Ok(Self::Tcp),
+            "UDP" => Ok(Self::Udp),
+            _ => Err(anyhow::anyhow!("Invalid protocol specifier: {}", value)),
+        }
+    }
+}
+
+impl Display for PortProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Tcp => "TCP",
+                Self::Udp => "UDP",
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_1() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "192.168.0.1:8080:192.168.4.1:8081:TCP,UDP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![
+                PortForwardConfig {
+                    name: None,
+                    source: SocketAddr::from_str("192.168.0.1:8080").unwrap(),
+                    destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                    destination_host: None,
+                    protocol: PortProtocol::Tcp,
+                    remote: false,
+                    peer: None,
+                },
+                PortForwardConfig {
+                    name: None,
+                    source: SocketAddr::from_str("192.168.0.1:8080").unwrap(),
+                    destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                    destination_host: None,
+                    protocol: PortProtocol::Udp,
+                    remote: false,
+                    peer: None,
+                }
+            ]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_2() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "192.168.0.1:8080:192.168.4.1:8081:TCP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: SocketAddr::from_str("192.168.0.1:8080").unwrap(),
+                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                destination_host: None,
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_3() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "0.0.0.0:8080:192.168.4.1:8081",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: SocketAddr::from_str("0.0.0.0:8080").unwrap(),
+                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                destination_host: None,
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_4() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "[::1]:8080:192.168.4.1:8081",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: SocketAddr::from_str("[::1]:8080").unwrap(),
+                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                destination_host: None,
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_5() {
+        assert_eq!(
+            PortForwardConfig::from_notation("8080:192.168.4.1:8081", DEFAULT_PORT_FORWARD_SOURCE)
+                .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                destination_host: None,
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_6() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "8080:192.168.4.1:8081:TCP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                destination_host: None,
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_7() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "localhost:8080:192.168.4.1:8081",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
+                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                destination_host: None,
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_8() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "localhost:8080:localhost:8081:TCP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig {
+                name: None,
+                source: "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
+                destination: SocketAddr::from_str("0.0.0.0:8081").unwrap(),
+                destination_host: Some(Arc::from("localhost")),
+                protocol: PortProtocol::Tcp,
+                remote: false,
+                peer: None,
+            }]
+        );
+    }
+
+    /// Tests that the most specific allowed IP range is matched.
+    #[test]
+    fn test_allowed_prefix_len() {
+        let peer = PeerConfig::test(
+            "192.168.4.3",
+            Some("10.0.0.0/8, 10.1.0.0/16, 192.168.4.2, fd00::/64"),
+        );
+        let prefix_len = |ip: &str| peer.allowed_prefix_len(IpAddr::from_str(ip).unwrap());
+        assert_eq!(prefix_len("10.2.0.7"), Some(8));
+        assert_eq!(prefix_len("10.1.0.7"), Some(16));
+        assert_eq!(prefix_len("192.168.4.2"), Some(32));
+        assert_eq!(prefix_len("fd00::7"), Some(64));
+        assert_eq!(prefix_len("192.168.4.1"), None);
+        assert!(parse_allowed_ips(Some("10.0.0.0/33")).is_err());
+    }
+
+    /// Tests that connections use the first source peer IP of the IP version of their destination.
+    #[test]
+    fn test_source_peer_ip() {
+        let peer = PeerConfig::test("192.168.4.3/24, fd00::3/64, 10.0.0.3", None);
+        assert_eq!(
+            peer.source_peer_ips[0],
+            IpCidr::from_str("192.168.4.3/24").unwrap()
+        );
+        assert_eq!(
+            peer.source_peer_ips[2],
+            IpCidr::from_str("10.0.0.3/32").unwrap()
+        );
+        let ip = |ip: &str| IpAddr::from_str(ip).unwrap();
+        assert_eq!(peer.source_peer_ip(ip("10.1.0.7")), Some(ip("192.168.4.3")));
+        assert_eq!(peer.source_peer_ip(ip("fd01::7")), Some(ip("fd00::3")));
+        assert!(peer.is_source_peer_ip(ip("10.0.0.3")));
+        assert!(!peer.is_source_peer_ip(ip("192.168.4.4")));
+        assert!(parse_source_peer_ips(Some(" , ")).is_err());
+        assert!(parse_source_peer_ips(Some("192.168.4.3/33")).is_err());
+    }
+
+    /// Tests that local port forwards are rejected if their peer has no source peer IP of the destination's IP version.
+    #[test]
+    fn test_validate_destination() {
+        let peers = vec![PeerConfig::test("192.168.4.3/24", None)];
+        let port_forward = |destination: &str, destination_host: Option<&str>| PortForwardConfig {
+            name: None,
+            source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+            destination: SocketAddr::from_str(destination).unwrap(),
+            destination_host: destination_host.map(Arc::from),
+            protocol: PortProtocol::Tcp,
+            remote: false,
+            peer: None,
+        };
+        assert!(validate_destination(&port_forward("192.168.4.2:80", None), &peers).is_ok());
+        let error = validate_destination(&port_forward("[fd00::2]:80", None), &peers)
+            .expect_err("IPv6 destination should be rejected");
+        assert_eq!(
+            error.to_string(),
+            "Port forward 127.0.0.1:8080:[fd00::2]:80:TCP has an IPv6 destination, \
+            but its peer 'default' has no IPv6 source peer IP (192.168.4.3/24)"
+        );
+        assert!(
+            validate_destination(&port_forward("0.0.0.0:80", Some("intranet")), &peers).is_ok()
+        );
+    }
+
+    /// Tests that the endpoints keep their order, and the host names to be resolved again but not the IP addresses.
+    #[test]
+    fn test_parse_endpoints() {
+        let endpoints =
+            parse_endpoints(Some("140.30.3.182:51820, localhost:443")).expect("Failed to parse");
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(
+            endpoints[0],
+            PeerEndpoint::from(SocketAddr::from_str("140.30.3.182:51820").unwrap())
+        );
+        assert!(endpoints[1].addr.ip().is_loopback());
+        assert_eq!(endpoints[1].addr.port(), 443);
+        assert_eq!(endpoints[1].host, Some(Arc::from("localhost:443")));
+
+        assert!(parse_endpoints(Some(" , ")).is_err());
+        assert!(parse_endpoints(Some("140.30.3.182:51820,140.30.3.182")).is_err());
+    }
+
+    /// Tests that only a dual-stack socket reaches endpoints of both IP versions.
+    #[test]
+    fn test_can_reach() {
+        let v4 = SocketAddr::from_str("140.30.3.182:51820").unwrap();
+        let v6 = SocketAddr::from_str("[2001:db8::1]:51820").unwrap();
+        let any_v4 = SocketAddr::from_str("0.0.0.0:0").unwrap();
+        let any_v6 = SocketAddr::from_str("[::]:0").unwrap();
+        let local_v6 = SocketAddr::from_str("[::1]:0").unwrap();
+
+        assert!(can_reach(any_v4, v4));
+        assert!(!can_reach(any_v4, v6));
+        assert!(can_reach(any_v6, v4));
+        assert!(can_reach(any_v6, v6));
+        assert!(!can_reach(local_v6, v4));
+        assert!(can_reach(local_v6, v6));
+    }
+}
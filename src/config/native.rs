@@ -0,0 +1,340 @@
+use serde::Deserialize;
+
+use crate::config::file::{ConfigFile, FilePeer, FilePortForward};
+
+/// The onetun configuration file format, in TOML or YAML.
+///
+/// The keys match the names of the CLI arguments, with underscores instead of dashes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NativeConfig {
+    private_key: Option<String>,
+    private_key_file: Option<String>,
+    endpoint_public_key: Option<String>,
+    preshared_key: Option<String>,
+    endpoint_addr: Option<NativeStrings>,
+    endpoint_bind_addr: Option<String>,
+    source_peer_ip: Option<NativeStrings>,
+    allowed_ips: Option<Vec<String>>,
+    keep_alive: Option<u16>,
+    max_transmission_unit: Option<usize>,
+    log: Option<String>,
+    pcap: Option<String>,
+    socks5: Option<String>,
+    http_proxy: Option<String>,
+    dns: Option<String>,
+    dns_forwarder: Option<String>,
+    #[serde(default)]
+    dns_forwarder_cache: bool,
+    http_proxy_auth: Option<String>,
+    control: Option<String>,
+    metrics: Option<String>,
+    wait_handshake: Option<u64>,
+    max_restarts: Option<u32>,
+    restart_backoff: Option<u64>,
+    #[serde(default)]
+    peers: Vec<NativePeer>,
+    #[serde(default)]
+    port_forwards: Vec<NativePortForward>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NativePeer {
+    name: String,
+    endpoint_public_key: String,
+    preshared_key: Option<String>,
+    endpoint_addr: NativeStrings,
+    source_peer_ip: Option<NativeStrings>,
+    allowed_ips: Option<Vec<String>>,
+    keep_alive: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NativePortForward {
+    name: Option<String>,
+    source: NativeAddress,
+    destination: String,
+    #[serde(default)]
+    protocols: Vec<String>,
+    #[serde(default)]
+    remote: bool,
+    peer: Option<String>,
+}
+
+/// A source address, which may be given as only a port number.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NativeAddress {
+    Port(u16),
+    Address(String),
+}
+
+/// A value that may be given as a single string, or as a list of strings (e.g. the endpoints of a peer).
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NativeStrings {
+    One(String),
+    List(Vec<String>),
+}
+
+impl NativeStrings {
+    /// Joins the strings with commas, like the CLI arguments with multiple values.
+    fn join(self) -> String {
+        match self {
+            NativeStrings::One(s) => s,
+            NativeStrings::List(list) => list.join(","),
+        }
+    }
+}
+
+/// Parses the contents of a onetun configuration file in the TOML format.
+pub fn parse_toml(s: &str) -> anyhow::Result<ConfigFile> {
+    let config: NativeConfig = toml::from_str(s).map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(config.into())
+}
+
+/// Parses the contents of a onetun configuration file in the YAML format.
+pub fn parse_yaml(s: &str) -> anyhow::Result<ConfigFile> {
+    let config: NativeConfig = serde_yaml::from_str(s).map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(config.into())
+}
+
+/// Parses a port forward definition in JSON, with the same keys as a `port_forwards` entry.
+pub fn parse_json_port_forward(s: &str) -> anyhow::Result<FilePortForward> {
+    let port_forward: NativePortForward =
+        serde_json::from_str(s).map_err(|e| anyhow::anyhow!("Invalid port forward: {}", e))?;
+    Ok(port_forward.into_file_port_forward("port_forward".into()))
+}
+
+impl NativePortForward {
+    fn into_file_port_forward(self, key: String) -> FilePortForward {
+        FilePortForward {
+            key,
+            name: self.name,
+            source: match self.source {
+                NativeAddress::Port(port) => port.to_string(),
+                NativeAddress::Address(address) => address,
+            },
+            destination: self.destination,
+            protocols: self.protocols,
+            remote: self.remote,
+            peer: self.peer,
+        }
+    }
+}
+
+impl From<NativeConfig> for ConfigFile {
+    fn from(config: NativeConfig) -> Self {
+        Self {
+            private_key: config.private_key,
+            private_key_file: config.private_key_file,
+            endpoint_public_key: config.endpoint_public_key,
+            preshared_key: config.preshared_key,
+            endpoint_addr: config.endpoint_addr.map(NativeStrings::join),
+            endpoint_bind_addr: config.endpoint_bind_addr,
+            listen_port: None,
+            source_peer_ip: config.source_peer_ip.map(NativeStrings::join),
+            allowed_ips: config.allowed_ips.map(|ips| ips.join(",")),
+            keep_alive: config.keep_alive.map(|v| v.to_string()),
+            max_transmission_unit: config.max_transmission_unit.map(|v| v.to_string()),
+            log: config.log,
+            pcap: config.pcap,
+            socks5: config.socks5,
+            http_proxy: config.http_proxy,
+            dns: config.dns,
+            dns_forwarder: config.dns_forwarder,
+            dns_forwarder_cache: config.dns_forwarder_cache,
+            http_proxy_auth: config.http_proxy_auth,
+            control: config.control,
+            metrics: config.metrics,
+            wait_handshake: config.wait_handshake.map(|v| v.to_string()),
+            max_restarts: config.max_restarts.map(|v| v.to_string()),
+            restart_backoff: config.restart_backoff.map(|v| v.to_string()),
+            peers: config
+                .peers
+                .into_iter()
+                .enumerate()
+                .map(|(index, peer)| FilePeer {
+                    key: format!("peers[{}]", index),
+                    name: peer.name,
+                    endpoint_public_key: Some(peer.endpoint_public_key),
+                    preshared_key: peer.preshared_key,
+                    endpoint_addr: Some(peer.endpoint_addr.join()),
+                    source_peer_ip: peer.source_peer_ip.map(NativeStrings::join),
+                    allowed_ips: peer.allowed_ips.map(|ips| ips.join(",")),
+                    keep_alive: peer.keep_alive.map(|v| v.to_string()),
+                })
+                .collect(),
+            port_forwards: config
+                .port_forwards
+                .into_iter()
+                .enumerate()
+                .map(|(index, pf)| pf.into_file_port_forward(format!("port_forwards[{}]", index)))
+                .collect(),
+            warnings: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the parsing of a TOML config file.
+    #[test]
+    fn test_parse_toml_config() {
+        let config = parse_toml(
+            r#"
+            private_key_file = "/etc/onetun/private.key"
+            endpoint_public_key = "t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk="
+            endpoint_addr = "140.30.3.182:51820"
+            source_peer_ip = "192.168.4.3"
+            keep_alive = 10
+            max_transmission_unit = 1400
+
+            [[port_forwards]]
+            name = "web"
+            source = 8080
+            destination = "192.168.4.2:80"
+
+            [[port_forwards]]
+            name = "dns"
+            source = "[::1]:5353"
+            destination = "192.168.4.2:53"
+            protocols = ["tcp", "udp"]
+            "#,
+        )
+        .expect("Failed to parse");
+
+        assert_eq!(
+            config.private_key_file,
+            Some("/etc/onetun/private.key".into())
+        );
+        assert_eq!(config.keep_alive, Some("10".into()));
+        assert_eq!(config.max_transmission_unit, Some("1400".into()));
+        assert_eq!(
+            config.port_forwards,
+            vec![
+                FilePortForward {
+                    key: "port_forwards[0]".into(),
+                    name: Some("web".into()),
+                    source: "8080".into(),
+                    destination: "192.168.4.2:80".into(),
+                    protocols: vec![],
+                    remote: false,
+                    peer: None,
+                },
+                FilePortForward {
+                    key: "port_forwards[1]".into(),
+                    name: Some("dns".into()),
+                    source: "[::1]:5353".into(),
+                    destination: "192.168.4.2:53".into(),
+                    protocols: vec!["tcp".into(), "udp".into()],
+                    remote: false,
+                    peer: None,
+                }
+            ]
+        );
+    }
+
+    /// Tests the parsing of a YAML config file.
+    #[test]
+    fn test_parse_yaml_config() {
+        let config = parse_yaml(
+            "endpoint_addr: 140.30.3.182:51820\n\
+            port_forwards:\n\
+            \x20 - name: ssh\n\
+            \x20   source: 2222\n\
+            \x20   destination: 127.0.0.1:22\n\
+            \x20   remote: true\n",
+        )
+        .expect("Failed to parse");
+
+        assert_eq!(config.endpoint_addr, Some("140.30.3.182:51820".into()));
+        assert_eq!(config.port_forwards.len(), 1);
+        assert!(config.port_forwards[0].remote);
+    }
+
+    /// Tests the parsing of multiple peers, selected by the port forwards.
+    #[test]
+    fn test_parse_toml_config_peers() {
+        let config = parse_toml(
+            r#"
+            [[peers]]
+            name = "office"
+            endpoint_public_key = "t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk="
+            endpoint_addr = "140.30.3.182:51820"
+            source_peer_ip = "192.168.4.3"
+
+            [[peers]]
+            name = "lab"
+            endpoint_public_key = "bVNhbJ9yHqfB4YWAx8HNy0R5XUk1sMvpmUiH+l5nCXY="
+            endpoint_addr = ["140.30.3.183:51820", "140.30.3.183:443"]
+            source_peer_ip = "10.0.0.3"
+            allowed_ips = ["10.0.0.0/24", "fd00::/64"]
+            keep_alive = 25
+
+            [[port_forwards]]
+            source = 8080
+            destination = "10.0.0.2:80"
+            peer = "lab"
+            "#,
+        )
+        .expect("Failed to parse");
+
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(
+            config.peers[1],
+            FilePeer {
+                key: "peers[1]".into(),
+                name: "lab".into(),
+                endpoint_public_key: Some("bVNhbJ9yHqfB4YWAx8HNy0R5XUk1sMvpmUiH+l5nCXY=".into()),
+                preshared_key: None,
+                endpoint_addr: Some("140.30.3.183:51820,140.30.3.183:443".into()),
+                source_peer_ip: Some("10.0.0.3".into()),
+                allowed_ips: Some("10.0.0.0/24,fd00::/64".into()),
+                keep_alive: Some("25".into()),
+            }
+        );
+        assert_eq!(config.port_forwards[0].peer, Some("lab".into()));
+    }
+
+    /// Tests that errors point to the unknown key and its line.
+    #[test]
+    fn test_parse_toml_config_unknown_key() {
+        let error = parse_toml("endpoint_addr = \"140.30.3.182:51820\"\nendpoint_adr = \"\"\n")
+            .expect_err("Unknown key should be rejected");
+        let error = error.to_string();
+        assert!(error.contains("endpoint_adr"), "{}", error);
+        assert!(error.contains("line 2"), "{}", error);
+    }
+
+    /// Tests the parsing of a port forward given to the control API in JSON.
+    #[test]
+    fn test_parse_json_port_forward() {
+        let port_forward = parse_json_port_forward(
+            r#"{"name": "web", "source": 8080, "destination": "192.168.4.2:80", "protocols": ["udp"]}"#,
+        )
+        .expect("Failed to parse");
+        assert_eq!(
+            port_forward,
+            FilePortForward {
+                key: "port_forward".into(),
+                name: Some("web".into()),
+                source: "8080".into(),
+                destination: "192.168.4.2:80".into(),
+                protocols: vec!["udp".into()],
+                remote: false,
+                peer: None,
+            }
+        );
+
+        assert!(parse_json_port_forward(r#"{"source": 8080}"#).is_err());
+        assert!(parse_json_port_forward(
+            r#"{"source": 8080, "destination": "192.168.4.2:80", "port": 1}"#
+        )
+        .is_err());
+    }
+}
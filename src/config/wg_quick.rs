@@ -0,0 +1,288 @@
+use std::net::IpAddr;
+
+use crate::config::file::{ConfigFile, FilePeer};
+
+/// Keys used by `wg-quick` to configure the host, which onetun never does.
+const UNSUPPORTED_INTERFACE_KEYS: &[&str] = &[
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+    "fwmark",
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+/// Parses the contents of a `wg-quick` configuration file (e.g. `/etc/wireguard/wg0.conf`).
+///
+/// Only the values that are meaningful to onetun are accepted. Keys that would
+/// configure the system network (e.g. `PostUp` or `Table`) are rejected.
+///
+/// The first [Peer] section is the default peer, which can be overridden by the CLI arguments.
+/// The next ones are named after their position: `peer2`, `peer3`, etc.
+pub fn parse(s: &str) -> anyhow::Result<ConfigFile> {
+    let mut config = parse_sections(s)?;
+    if !config.peers.is_empty() {
+        let peer = config.peers.remove(0);
+        config.endpoint_public_key = peer.endpoint_public_key;
+        config.preshared_key = peer.preshared_key;
+        config.endpoint_addr = peer.endpoint_addr;
+        config.allowed_ips = peer.allowed_ips;
+        config.keep_alive = peer.keep_alive;
+    }
+    Ok(config)
+}
+
+/// Parses the sections of the file, with every [Peer] section in `ConfigFile::peers`.
+fn parse_sections(s: &str) -> anyhow::Result<ConfigFile> {
+    let mut config = ConfigFile::default();
+    let mut section = Section::None;
+
+    for (index, line) in s.lines().enumerate() {
+        let line_number = index + 1;
+
+        // Everything after '#' is a comment
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = match line[1..line.len() - 1].trim().to_lowercase().as_str() {
+                "interface" => Section::Interface,
+                "peer" => {
+                    let name = format!("peer{}", config.peers.len() + 1);
+                    config.peers.push(FilePeer {
+                        key: name.clone(),
+                        name,
+                        endpoint_public_key: None,
+                        preshared_key: None,
+                        endpoint_addr: None,
+                        source_peer_ip: None,
+                        allowed_ips: None,
+                        keep_alive: None,
+                    });
+                    Section::Peer
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Line {}: unknown section {}",
+                        line_number,
+                        line
+                    ))
+                }
+            };
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim().to_string()))
+            .ok_or_else(|| anyhow::anyhow!("Line {}: expected 'Key = Value'", line_number))?;
+
+        match (section, key.to_lowercase().as_str()) {
+            (Section::None, _) => {
+                return Err(anyhow::anyhow!(
+                    "Line {}: '{}' must be in an [Interface] or [Peer] section",
+                    line_number,
+                    key
+                ))
+            }
+            (Section::Interface, "privatekey") => config.private_key = Some(value),
+            // Same syntax as the source peer IPs: comma-separated IPs with prefix lengths
+            (Section::Interface, "address") => config.source_peer_ip = Some(value),
+            (Section::Interface, "listenport") => config.listen_port = Some(value),
+            (Section::Interface, "mtu") => config.max_transmission_unit = Some(value),
+            (Section::Interface, "dns") => {
+                // wg-quick also accepts search domains, which onetun has no use for
+                let mut servers = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| entry.parse::<IpAddr>().is_ok());
+                match servers.next() {
+                    Some(server) if config.dns.is_none() => {
+                        config.dns = Some(server.to_string());
+                        if servers.next().is_some() {
+                            config.warnings.push(format!(
+                                "Multiple DNS servers are not supported; only {} is used.",
+                                server
+                            ));
+                        }
+                    }
+                    Some(_) => config.warnings.push(format!(
+                        "Only the first DNS server in the config file is used (ignored {}).",
+                        value
+                    )),
+                    None => config.warnings.push(format!(
+                        "DNS search domains in the config file are ignored ({}).",
+                        value
+                    )),
+                }
+            }
+            (Section::Interface, lowercase) if UNSUPPORTED_INTERFACE_KEYS.contains(&lowercase) => {
+                return Err(anyhow::anyhow!(
+                    "Line {}: '{}' is not supported, since onetun does not configure the system network",
+                    line_number,
+                    key
+                ))
+            }
+            (Section::Peer, "publickey") => current_peer(&mut config).endpoint_public_key = Some(value),
+            (Section::Peer, "presharedkey") => current_peer(&mut config).preshared_key = Some(value),
+            (Section::Peer, "endpoint") => current_peer(&mut config).endpoint_addr = Some(value),
+            (Section::Peer, "persistentkeepalive") => {
+                // wg-quick accepts 'off' to disable the keep-alive
+                if !value.eq_ignore_ascii_case("off") {
+                    current_peer(&mut config).keep_alive = Some(value);
+                }
+            }
+            (Section::Peer, "allowedips") => {
+                // The key may be repeated, in which case the ranges are combined
+                let peer = current_peer(&mut config);
+                peer.allowed_ips = Some(match peer.allowed_ips.take() {
+                    Some(allowed_ips) => format!("{},{}", allowed_ips, value),
+                    None => value,
+                });
+            }
+            (Section::Interface, _) => {
+                return Err(anyhow::anyhow!(
+                    "Line {}: unknown key '{}' in [Interface] section",
+                    line_number,
+                    key
+                ))
+            }
+            (Section::Peer, _) => {
+                return Err(anyhow::anyhow!(
+                    "Line {}: unknown key '{}' in [Peer] section",
+                    line_number,
+                    key
+                ))
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// The peer of the current [Peer] section.
+fn current_peer(config: &mut ConfigFile) -> &mut FilePeer {
+    config
+        .peers
+        .last_mut()
+        .expect("A peer is added for each [Peer] section")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the parsing of a typical `wg-quick` config.
+    #[test]
+    fn test_parse_wg_quick_config() {
+        let config = parse(
+            "# onetun peer\n\
+            [Interface]\n\
+            PrivateKey = 8DBwQAHy0cnOlYcBcmI5TU8pT7XVS3NYq7JoYtVcHmU=\n\
+            Address = 192.168.4.3/32\n\
+            MTU = 1400 # Lower than default\n\
+            \n\
+            [Peer]\n\
+            PublicKey = t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk=\n\
+            PresharedKey = QmtV9mDLgTYFRXzeaQ0JlIH4b1Sz9fX3cUqNEFD7OWI=\n\
+            Endpoint = 140.30.3.182:51820\n\
+            AllowedIPs = 192.168.4.0/24\n\
+            PersistentKeepalive = 25\n",
+        )
+        .expect("Failed to parse");
+
+        assert_eq!(
+            config,
+            ConfigFile {
+                private_key: Some("8DBwQAHy0cnOlYcBcmI5TU8pT7XVS3NYq7JoYtVcHmU=".into()),
+                endpoint_public_key: Some("t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk=".into()),
+                preshared_key: Some("QmtV9mDLgTYFRXzeaQ0JlIH4b1Sz9fX3cUqNEFD7OWI=".into()),
+                endpoint_addr: Some("140.30.3.182:51820".into()),
+                source_peer_ip: Some("192.168.4.3/32".into()),
+                allowed_ips: Some("192.168.4.0/24".into()),
+                keep_alive: Some("25".into()),
+                max_transmission_unit: Some("1400".into()),
+                ..Default::default()
+            }
+        );
+    }
+
+    /// Tests that keys configuring the system network are rejected.
+    #[test]
+    fn test_parse_wg_quick_config_unsupported_key() {
+        let error = parse("[Interface]\nAddress = 192.168.4.3/32\nPostUp = iptables -A FORWARD\n")
+            .expect_err("PostUp should be rejected");
+        assert_eq!(
+            error.to_string(),
+            "Line 3: 'PostUp' is not supported, since onetun does not configure the system network"
+        );
+
+        let error = parse("[Interface]\nTable = off\n").expect_err("Table should be rejected");
+        assert!(error.to_string().starts_with("Line 2: 'Table'"));
+    }
+
+    /// Tests that additional [Peer] sections are parsed as named peers.
+    #[test]
+    fn test_parse_wg_quick_config_multiple_peers() {
+        let config = parse(
+            "[Interface]\n\
+            Address = 192.168.4.3/32\n\
+            [Peer]\n\
+            PublicKey = t5fRGfK6n3Q7MBf8RJnCJwaDqmbCyKfdmtkNPKbdjVk=\n\
+            Endpoint = 140.30.3.182:51820\n\
+            AllowedIPs = 10.0.0.0/24\n\
+            [Peer]\n\
+            PublicKey = bVNhbJ9yHqfB4YWAx8HNy0R5XUk1sMvpmUiH+l5nCXY=\n\
+            Endpoint = 140.30.3.183:51820\n\
+            AllowedIPs = 10.1.0.0/24\n\
+            AllowedIPs = fd00::/64\n",
+        )
+        .expect("Failed to parse");
+
+        assert_eq!(config.allowed_ips, Some("10.0.0.0/24".into()));
+        assert_eq!(
+            config.peers,
+            vec![FilePeer {
+                key: "peer2".into(),
+                name: "peer2".into(),
+                endpoint_public_key: Some("bVNhbJ9yHqfB4YWAx8HNy0R5XUk1sMvpmUiH+l5nCXY=".into()),
+                preshared_key: None,
+                endpoint_addr: Some("140.30.3.183:51820".into()),
+                source_peer_ip: None,
+                allowed_ips: Some("10.1.0.0/24,fd00::/64".into()),
+                keep_alive: None,
+            }]
+        );
+    }
+
+    /// Tests that the first DNS server is used, without the search domains.
+    #[test]
+    fn test_parse_wg_quick_config_dns() {
+        let config = parse("[Interface]\nDNS = 192.168.4.1, 192.168.4.2, intranet\n")
+            .expect("Failed to parse");
+        assert_eq!(config.dns, Some("192.168.4.1".into()));
+        assert_eq!(config.warnings.len(), 1);
+    }
+
+    /// Tests that all the interface addresses are used, with their prefix lengths.
+    #[test]
+    fn test_parse_wg_quick_config_multiple_addresses() {
+        let config =
+            parse("[Interface]\nAddress = 192.168.4.3/24, fd00::3/128\n").expect("Failed to parse");
+        assert_eq!(
+            config.source_peer_ip,
+            Some("192.168.4.3/24, fd00::3/128".into())
+        );
+        assert!(config.warnings.is_empty());
+    }
+}
@@ -0,0 +1,481 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use bytes::BytesMut;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+use crate::config::{
+    parse_port_forward_json, Config, ControlAddr, PortForwardConfig, PortProtocol,
+};
+use crate::events::{Bus, LocalMessage};
+use crate::tunnel::manager::PortForwardManager;
+use crate::tunnel::tcp::TcpPortPool;
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+use crate::wg::WireGuardTunnel;
+
+/// The maximum size of the request head (request line and headers) sent by a client.
+const MAX_HEAD_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 65536;
+const MAX_HEADERS: usize = 32;
+
+/// Starts the control API, which serves JSON over HTTP/1.1 to manage the running tunnel:
+///
+/// - `GET /port-forwards`: lists the running port forwards.
+/// - `POST /port-forwards`: adds a port forward, defined like a `port_forwards` entry of the config file.
+/// - `DELETE /port-forwards`: removes a port forward, defined the same way.
+/// - `POST /reload`: reloads the port forwards from the config file, like `SIGHUP`.
+/// - `GET /connections`: lists the virtual ports in use, with the address of their client.
+/// - `DELETE /connections/<tcp|udp>/<port>`: closes the connection of a virtual port.
+/// - `GET /peers`: lists the WireGuard peers, with the time since their last handshake.
+///
+/// Each connection serves a single request. Request bodies must be JSON (`Content-Type: application/json`), and
+/// requests with an `Origin` header are rejected, so that web pages cannot send requests to the API from a browser.
+pub async fn control_server(addr: ControlAddr, control: Arc<Control>) -> anyhow::Result<()> {
+    match addr {
+        ControlAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| "Failed to listen on control API address")?;
+            loop {
+                let (socket, client_addr) = listener
+                    .accept()
+                    .await
+                    .with_context(|| "Failed to accept connection on control API")?;
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = control.handle_connection(socket).await {
+                        warn!("Control API request from {} failed: {:?}", client_addr, e);
+                    }
+                });
+            }
+        }
+        #[cfg(unix)]
+        ControlAddr::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            // Remove the socket left by a previous run, but never another kind of file
+            if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(&path).with_context(|| {
+                        format!("Failed to remove stale control socket {}", path.display())
+                    })?;
+                }
+            }
+            let listener = tokio::net::UnixListener::bind(&path)
+                .with_context(|| "Failed to listen on control API socket")?;
+            loop {
+                let (socket, _) = listener
+                    .accept()
+                    .await
+                    .with_context(|| "Failed to accept connection on control API")?;
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = control.handle_connection(socket).await {
+                        warn!("Control API request failed: {:?}", e);
+                    }
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        ControlAddr::Unix(path) => Err(anyhow::anyhow!(
+            "Unix sockets are not supported on this platform ({})",
+            path.display()
+        )),
+    }
+}
+
+/// The state of the running tunnel, managed by the control API.
+pub struct Control {
+    config: Arc<Config>,
+    manager: Arc<Mutex<PortForwardManager>>,
+    wg: Arc<WireGuardTunnel>,
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    bus: Bus,
+}
+
+/// The response body of a request, or the status and cause of its failure.
+type ControlResult = Result<Value, (&'static str, anyhow::Error)>;
+
+/// A request to the control API.
+#[derive(Debug, Eq, PartialEq)]
+struct ControlRequest {
+    method: String,
+    path: String,
+    /// The media type of the `Content-Type` header, without its parameters.
+    content_type: Option<String>,
+    /// The `Origin` header, sent by browsers.
+    origin: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Control {
+    pub fn new(
+        config: Arc<Config>,
+        manager: Arc<Mutex<PortForwardManager>>,
+        wg: Arc<WireGuardTunnel>,
+        tcp_port_pool: TcpPortPool,
+        udp_port_pool: UdpPortPool,
+        bus: Bus,
+    ) -> Self {
+        Self {
+            config,
+            manager,
+            wg,
+            tcp_port_pool,
+            udp_port_pool,
+            bus,
+        }
+    }
+
+    async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut socket: S,
+    ) -> anyhow::Result<()> {
+        let (status, body) = match read_request(&mut socket).await {
+            Ok(Some(request)) => {
+                debug!("Control API request: {} {}", request.method, request.path);
+                self.handle_request(&request).await
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => ("400 Bad Request", json!({ "error": format!("{:#}", e) })),
+        };
+
+        let body = body.to_string();
+        socket
+            .write_all(
+                format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .with_context(|| "Failed to send control API response")
+    }
+
+    async fn handle_request(&self, request: &ControlRequest) -> (&'static str, Value) {
+        if let Some(origin) = &request.origin {
+            warn!("Rejected control API request from origin {}", origin);
+            return (
+                "403 Forbidden",
+                json!({ "error": "Requests from web pages are not allowed" }),
+            );
+        }
+        if !request.body.is_empty() && request.content_type.as_deref() != Some("application/json") {
+            return (
+                "415 Unsupported Media Type",
+                json!({ "error": "Request body must be application/json" }),
+            );
+        }
+
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["port-forwards"]) => Ok(self.list_port_forwards().await),
+            ("POST", ["port-forwards"]) => self.add_port_forwards(&request.body).await,
+            ("DELETE", ["port-forwards"]) => self.remove_port_forwards(&request.body).await,
+            ("POST", ["reload"]) => self.reload().await,
+            ("GET", ["connections"]) => Ok(self.list_connections().await),
+            ("DELETE", ["connections", protocol, port]) => {
+                self.close_connection(protocol, port).await
+            }
+            ("GET", ["peers"]) => Ok(self.list_peers()),
+            (_, ["port-forwards"])
+            | (_, ["reload"])
+            | (_, ["connections", ..])
+            | (_, ["peers"]) => Err((
+                "405 Method Not Allowed",
+                anyhow::anyhow!("Method not allowed"),
+            )),
+            _ => Err(("404 Not Found", anyhow::anyhow!("Not found"))),
+        };
+        match result {
+            Ok(body) => ("200 OK", body),
+            Err((status, e)) => (status, json!({ "error": format!("{:#}", e) })),
+        }
+    }
+
+    async fn list_port_forwards(&self) -> Value {
+        let port_forwards = self.manager.lock().await.port_forwards();
+        Value::Array(port_forwards.iter().map(port_forward_json).collect())
+    }
+
+    /// Adds all the port forwards of the request, or none of them if one cannot be added.
+    /// The response is only sent once their sources are bound.
+    async fn add_port_forwards(&self, body: &[u8]) -> ControlResult {
+        let port_forwards = self.parse_port_forwards(body)?;
+        let mut manager = self.manager.lock().await;
+        manager
+            .validate(&port_forwards)
+            .map_err(|e| ("400 Bad Request", e))?;
+        // The source of a port forward may already be in use
+        let added = manager
+            .add_all(&port_forwards)
+            .await
+            .map_err(|e| ("409 Conflict", e))?;
+        // The port forwards that were already running are left as-is
+        Ok(json!({
+            "added": added.iter().map(port_forward_json).collect::<Vec<_>>(),
+            "running": port_forwards
+                .iter()
+                .filter(|pf| !added.contains(pf))
+                .map(port_forward_json)
+                .collect::<Vec<_>>(),
+        }))
+    }
+
+    async fn remove_port_forwards(&self, body: &[u8]) -> ControlResult {
+        let port_forwards = self.parse_port_forwards(body)?;
+        let mut manager = self.manager.lock().await;
+        let mut removed = vec![];
+        for port_forward in port_forwards.iter() {
+            if manager.remove(port_forward).await {
+                removed.push(port_forward_json(port_forward));
+            }
+        }
+        if removed.is_empty() {
+            return Err((
+                "404 Not Found",
+                anyhow::anyhow!("No such port forward is running"),
+            ));
+        }
+        Ok(json!({ "removed": removed }))
+    }
+
+    fn parse_port_forwards(
+        &self,
+        body: &[u8],
+    ) -> Result<Vec<PortForwardConfig>, (&'static str, anyhow::Error)> {
+        std::str::from_utf8(body)
+            .with_context(|| "Request body is not UTF-8")
+            .and_then(|body| parse_port_forward_json(body, &self.config.peers))
+            .map_err(|e| ("400 Bad Request", e))
+    }
+
+    async fn reload(&self) -> ControlResult {
+        crate::reload_port_forwards(&self.config, &self.manager)
+            .await
+            .map_err(|e| ("409 Conflict", e))?;
+        Ok(self.list_port_forwards().await)
+    }
+
+    async fn list_connections(&self) -> Value {
+        let tcp_ports = self.tcp_port_pool.active_ports().await;
+        let udp_ports = self.udp_port_pool.active_ports().await;
+        Value::Array(
+            tcp_ports
+                .iter()
+                .chain(udp_ports.iter())
+                .map(|(port, client_addr)| {
+                    json!({
+                        "protocol": port.proto().to_string(),
+                        "virtual_port": port.num(),
+                        "client": client_addr.to_string(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    async fn close_connection(&self, protocol: &str, port: &str) -> ControlResult {
+        let port: u16 = port.parse().map_err(|_| {
+            (
+                "400 Bad Request",
+                anyhow::anyhow!("Invalid port '{}'", port),
+            )
+        })?;
+        let active_ports = match protocol.to_lowercase().as_str() {
+            "tcp" => self.tcp_port_pool.active_ports().await,
+            "udp" => self.udp_port_pool.active_ports().await,
+            _ => {
+                return Err((
+                    "400 Bad Request",
+                    anyhow::anyhow!("Invalid protocol '{}'", protocol),
+                ))
+            }
+        };
+        let (virtual_port, _): &(VirtualPort, _) = active_ports
+            .iter()
+            .find(|(virtual_port, _)| virtual_port.num() == port)
+            .ok_or_else(|| {
+                (
+                    "404 Not Found",
+                    anyhow::anyhow!("Virtual port {} is not in use", port),
+                )
+            })?;
+
+        info!("[{}] Closing connection from the control API", virtual_port);
+        // The virtual interface tells the local server, which releases the port of a TCP connection once it is closed
+        if let Ok(interface) = self.bus.interface(virtual_port.proto()) {
+            let _ = interface.send(LocalMessage::Close(*virtual_port)).await;
+        }
+        if virtual_port.proto() == PortProtocol::Udp {
+            // UDP flows are only closed in the virtual interface; their port is not released by a local connection
+            self.udp_port_pool.release(*virtual_port).await;
+        }
+        Ok(json!({ "closed": virtual_port.to_string() }))
+    }
+
+    fn list_peers(&self) -> Value {
+        Value::Array(
+            self.wg
+                .stats()
+                .iter()
+                .zip(self.config.peers.iter())
+                .map(|(stats, peer)| {
+                    json!({
+                        "name": stats.name.as_ref(),
+                        "endpoint": stats.endpoint_addr.to_string(),
+                        "source_peer_ips": peer
+                            .source_peer_ips
+                            .iter()
+                            .map(|cidr| cidr.to_string())
+                            .collect::<Vec<_>>(),
+                        "handshake_completed": stats.last_handshake.is_some(),
+                        "seconds_since_last_handshake": stats.last_handshake.map(|d| d.as_secs()),
+                        "tx_bytes": stats.tx_bytes,
+                        "rx_bytes": stats.rx_bytes,
+                        "estimated_loss": stats.estimated_loss,
+                        "rtt_ms": stats.rtt.map(|rtt| rtt.as_millis() as u64),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+fn port_forward_json(port_forward: &PortForwardConfig) -> Value {
+    json!({
+        "name": port_forward.name.as_deref(),
+        "protocol": port_forward.protocol.to_string(),
+        "source": port_forward.source.to_string(),
+        "destination": port_forward.display_destination(),
+        "remote": port_forward.remote,
+        "peer": port_forward.peer.as_deref(),
+    })
+}
+
+/// Reads a request with its body, or returns `None` if the client closed the connection without sending one.
+async fn read_request<S: AsyncRead + Unpin>(
+    socket: &mut S,
+) -> anyhow::Result<Option<ControlRequest>> {
+    let mut buffer = BytesMut::with_capacity(MAX_HEAD_SIZE);
+    loop {
+        if socket.read_buf(&mut buffer).await? == 0 {
+            return if buffer.is_empty() {
+                Ok(None)
+            } else {
+                Err(anyhow::anyhow!(
+                    "Connection closed before the end of the request"
+                ))
+            };
+        }
+        if let Some(request) = parse_request(&buffer)? {
+            return Ok(Some(request));
+        }
+        if buffer.len() >= MAX_HEAD_SIZE + MAX_BODY_SIZE {
+            return Err(anyhow::anyhow!("Request is too large"));
+        }
+    }
+}
+
+/// Parses a request, returning `None` if the head or the body is incomplete.
+fn parse_request(buffer: &[u8]) -> anyhow::Result<Option<ControlRequest>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    let head_len = match request
+        .parse(buffer)
+        .with_context(|| "Invalid HTTP request")?
+    {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial if buffer.len() >= MAX_HEAD_SIZE => {
+            return Err(anyhow::anyhow!("Request head is too large"))
+        }
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).trim().to_string())
+    };
+    let content_length = match header("content-length") {
+        Some(value) => value
+            .parse::<usize>()
+            .ok()
+            .with_context(|| "Invalid Content-Length")?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err(anyhow::anyhow!("Request body is too large"));
+    }
+    if buffer.len() < head_len + content_length {
+        return Ok(None);
+    }
+
+    let content_type = header("content-type").map(|value| {
+        let media_type = value.split(';').next().unwrap_or_default();
+        media_type.trim().to_ascii_lowercase()
+    });
+    Ok(Some(ControlRequest {
+        method: request.method.unwrap_or_default().to_uppercase(),
+        path: request.path.unwrap_or_default().to_string(),
+        content_type,
+        origin: header("origin"),
+        body: buffer[head_len..head_len + content_length].to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the parsing of requests, which are incomplete until the whole body is received.
+    #[test]
+    fn test_parse_control_request() {
+        let request = b"POST /port-forwards HTTP/1.1\r\n\
+            Content-Type: Application/JSON; charset=utf-8\r\n\
+            Content-Length: 4\r\n\r\n{}\r\n";
+        assert_eq!(parse_request(&request[..20]).unwrap(), None);
+        assert_eq!(parse_request(&request[..request.len() - 1]).unwrap(), None);
+        assert_eq!(
+            parse_request(request).unwrap(),
+            Some(ControlRequest {
+                method: "POST".into(),
+                path: "/port-forwards".into(),
+                content_type: Some("application/json".into()),
+                origin: None,
+                body: b"{}\r\n".to_vec(),
+            })
+        );
+
+        assert_eq!(
+            parse_request(b"get /peers HTTP/1.1\r\n\r\n").unwrap(),
+            Some(ControlRequest {
+                method: "GET".into(),
+                path: "/peers".into(),
+                content_type: None,
+                origin: None,
+                body: vec![],
+            })
+        );
+        assert!(parse_request(b"GET /peers HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+
+        let request = parse_request(b"POST /reload HTTP/1.1\r\nOrigin: http://example.com\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.origin.as_deref(), Some("http://example.com"));
+    }
+}
@@ -1,31 +1,33 @@
 use bytes::Bytes;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use tokio::sync::{mpsc, Semaphore};
 
 use crate::config::PortForwardConfig;
 use crate::virtual_iface::VirtualPort;
 use crate::PortProtocol;
 
-/// Events that go on the bus between the local server, smoltcp, and WireGuard.
+/// The capacity of the channel of the messages to a virtual interface.
+const INTERFACE_CAPACITY: usize = 1_000;
+
+/// Control events that go on the bus between the local servers, the virtual interfaces, and the other tasks.
+///
+/// Only the events that concern every task go on the bus. The connections are not: they are opened and closed with
+/// `LocalMessage`, and the virtual interfaces reply on the channel of each connection with `RemoteMessage`.
 #[derive(Debug, Clone)]
 pub enum Event {
     /// Dumb event with no data.
     Dumb,
-    /// A new connection with the local server was initiated, and the given virtual port was assigned.
-    ClientConnectionInitiated(PortForwardConfig, VirtualPort),
-    /// A connection was dropped from the pool and should be closed in all interfaces.
-    ClientConnectionDropped(VirtualPort),
-    /// Data received by the local server that should be sent to the virtual server.
-    LocalData(PortForwardConfig, VirtualPort, Bytes),
-    /// Data received by the remote server that should be sent to the local client.
-    RemoteData(VirtualPort, Bytes),
-    /// IP packet received from the WireGuard tunnel that should be passed through the corresponding virtual device.
-    InboundInternetPacket(PortProtocol, Bytes),
-    /// IP packet to be sent through the WireGuard tunnel as crafted by the virtual device.
-    OutboundInternetPacket(Bytes),
-    /// Notifies that a virtual device read an IP packet.
-    VirtualDeviceFed(PortProtocol),
+    /// A port forward was added at runtime (reload or control API), and its virtual sockets should be created.
+    PortForwardAdded(PortForwardConfig),
+    /// A port forward was removed at runtime, and its virtual sockets should be removed.
+    PortForwardRemoved(PortForwardConfig),
+    /// The tunnels are shutting down: virtual TCP connections should be closed, and local clients stop being read.
+    Shutdown,
 }
 
 impl Display for Event {
@@ -34,43 +36,77 @@ impl Display for Event {
             Event::Dumb => {
                 write!(f, "Dumb{{}}")
             }
-            Event::ClientConnectionInitiated(pf, vp) => {
-                write!(f, "ClientConnectionInitiated{{ pf={} vp={} }}", pf, vp)
-            }
-            Event::ClientConnectionDropped(vp) => {
-                write!(f, "ClientConnectionDropped{{ vp={} }}", vp)
-            }
-            Event::LocalData(pf, vp, data) => {
-                let size = data.len();
-                write!(f, "LocalData{{ pf={} vp={} size={} }}", pf, vp, size)
+            Event::PortForwardAdded(pf) => {
+                write!(f, "PortForwardAdded{{ pf={} }}", pf)
             }
-            Event::RemoteData(vp, data) => {
-                let size = data.len();
-                write!(f, "RemoteData{{ vp={} size={} }}", vp, size)
+            Event::PortForwardRemoved(pf) => {
+                write!(f, "PortForwardRemoved{{ pf={} }}", pf)
             }
-            Event::InboundInternetPacket(proto, data) => {
-                let size = data.len();
-                write!(
-                    f,
-                    "InboundInternetPacket{{ proto={} size={} }}",
-                    proto, size
-                )
-            }
-            Event::OutboundInternetPacket(data) => {
-                let size = data.len();
-                write!(f, "OutboundInternetPacket{{ size={} }}", size)
-            }
-            Event::VirtualDeviceFed(proto) => {
-                write!(f, "VirtualDeviceFed{{ proto={} }}", proto)
+            Event::Shutdown => {
+                write!(f, "Shutdown{{}}")
             }
         }
     }
 }
 
+/// Messages sent by the local servers to a virtual interface, on the channel of the interface.
+/// The messages of a connection are received in the order they were sent.
+#[derive(Debug)]
+pub enum LocalMessage {
+    /// The local side of a connection or UDP flow is ready. A TCP connection is initiated in the virtual interface,
+    /// unless it was accepted by a remote port forward. The virtual interface sends the messages of the virtual port
+    /// on the given channel, which is dropped once the virtual connection is closed.
+    /// A TCP connection also comes with its send credit.
+    Connect(
+        PortForwardConfig,
+        VirtualPort,
+        RemoteDataSender,
+        Option<SendCredit>,
+    ),
+    /// Data received by the local server that should be sent to the virtual server.
+    Data(VirtualPort, Bytes),
+    /// The local side of the connection was closed, or should be (e.g. from the control API): the virtual connection
+    /// is closed once its data is sent, and its channel is dropped.
+    Close(VirtualPort),
+    /// The server of a remote port forward is ready: the virtual ports of the connections and UDP flows accepted
+    /// by the port forward are sent on the given channel, replacing any previous one.
+    Listen(PortForwardConfig, RemoteConnectionSender),
+}
+
+/// Messages sent by a virtual interface to the local server of a connection or UDP flow, on its channel.
+#[derive(Debug)]
+pub enum RemoteMessage {
+    /// The virtual TCP connection was established with its destination.
+    Established,
+    /// Data received by the virtual interface, that should be sent to the local client.
+    Data(Bytes),
+    /// The UDP flow was closed (e.g. from the control API). The flows of a local server share their channel,
+    /// so it is not dropped; the next datagrams of the client need a new flow.
+    Closed,
+}
+
+/// The channel of the messages of a virtual interface for a connection, that should be handled by the local server.
+/// A channel can be shared by the UDP flows of a local server, so the messages come with their virtual port.
+pub type RemoteDataSender = mpsc::Sender<(VirtualPort, RemoteMessage)>;
+
+/// The channel of the virtual ports of the connections and UDP flows accepted by a remote port forward in a virtual
+/// interface, that should be connected to the real destination.
+pub type RemoteConnectionSender = mpsc::Sender<VirtualPort>;
+
+/// The bytes that a local server can send on a TCP connection, before they are in the buffer of the virtual socket.
+/// The local server acquires a permit for each byte it sends, and the virtual interface adds them back once the bytes
+/// are in the buffer of the virtual socket. This stops the local server from reading its client while the virtual
+/// socket is full.
+pub type SendCredit = Arc<Semaphore>;
+
 #[derive(Clone)]
 pub struct Bus {
     counter: Arc<AtomicU32>,
     bus: Arc<tokio::sync::broadcast::Sender<(u32, Event)>>,
+    /// The number of times an endpoint failed to read the bus, because it lagged behind.
+    lag_errors: Arc<AtomicU64>,
+    /// The channels of the running virtual interfaces, by protocol.
+    interfaces: Arc<RwLock<HashMap<PortProtocol, mpsc::Sender<LocalMessage>>>>,
 }
 
 impl Bus {
@@ -79,7 +115,13 @@ impl Bus {
         let (bus, _) = tokio::sync::broadcast::channel(1000);
         let bus = Arc::new(bus);
         let counter = Arc::new(AtomicU32::default());
-        Self { bus, counter }
+        let lag_errors = Arc::new(AtomicU64::default());
+        Self {
+            bus,
+            counter,
+            lag_errors,
+            interfaces: Default::default(),
+        }
     }
 
     /// Creates a new endpoint on the event bus.
@@ -89,7 +131,36 @@ impl Bus {
         let rx = self.bus.subscribe();
 
         let tx = BusSender { id, tx };
-        BusEndpoint { id, tx, rx }
+        let lag_errors = self.lag_errors.clone();
+        BusEndpoint {
+            id,
+            tx,
+            rx,
+            lag_errors,
+        }
+    }
+
+    /// Returns the number of times an endpoint failed to read the bus, because it lagged behind.
+    pub fn lag_errors(&self) -> u64 {
+        self.lag_errors.load(Ordering::Relaxed)
+    }
+
+    /// Creates the channel of the virtual interface of the protocol, replacing any previous one.
+    /// Returns the receiver of the messages sent to the interface.
+    pub fn register_interface(&self, protocol: PortProtocol) -> mpsc::Receiver<LocalMessage> {
+        let (tx, rx) = mpsc::channel(INTERFACE_CAPACITY);
+        self.interfaces.write().unwrap().insert(protocol, tx);
+        rx
+    }
+
+    /// Returns the channel of the virtual interface of the protocol.
+    pub fn interface(&self, protocol: PortProtocol) -> anyhow::Result<mpsc::Sender<LocalMessage>> {
+        self.interfaces
+            .read()
+            .unwrap()
+            .get(&protocol)
+            .cloned()
+            .with_context(|| format!("No {} virtual interface is running", protocol))
     }
 }
 
@@ -103,6 +174,7 @@ pub struct BusEndpoint {
     id: u32,
     tx: BusSender,
     rx: tokio::sync::broadcast::Receiver<(u32, Event)>,
+    lag_errors: Arc<AtomicU64>,
 }
 
 impl BusEndpoint {
@@ -128,7 +200,16 @@ impl BusEndpoint {
                         return event;
                     }
                 }
-                Err(_) => {
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    // The oldest events were overwritten, the next ones can still be read
+                    self.lag_errors.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Endpoint #{} lagged behind the event bus and missed {} events",
+                        self.id, missed
+                    );
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    // The bus owns a sender, so this cannot happen
                     error!("Failed to read event bus from endpoint #{}", self.id);
                     return futures::future::pending().await;
                 }
@@ -187,4 +268,26 @@ mod tests {
         assert!(matches!(recv_1, Event::Dumb));
         assert!(matches!(recv_3, Event::Dumb));
     }
+
+    /// Tests that an endpoint lagging behind the bus misses the oldest events, but keeps reading the next ones.
+    #[tokio::test]
+    async fn test_bus_lagged() {
+        let bus = Bus::new();
+
+        let endpoint_1 = bus.new_endpoint();
+        let mut endpoint_2 = bus.new_endpoint();
+
+        // The capacity of the bus is rounded up to 1024 events
+        for _ in 0..1_024 {
+            endpoint_1.send(Event::Dumb);
+        }
+        endpoint_1.send(Event::Shutdown);
+        assert!(matches!(endpoint_2.recv().await, Event::Dumb));
+        assert_eq!(bus.lag_errors(), 1);
+
+        for _ in 0..1_022 {
+            endpoint_2.recv().await;
+        }
+        assert!(matches!(endpoint_2.recv().await, Event::Shutdown));
+    }
 }
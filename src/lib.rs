@@ -2,121 +2,466 @@
 extern crate log;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-use crate::config::{Config, PortProtocol};
-use crate::events::Bus;
+use crate::config::{Config, PortForwardConfig, PortProtocol};
+use crate::control::Control;
+use crate::events::{Bus, Event};
+use crate::metrics::{Metrics, Traffic};
+use crate::supervisor::{Supervisor, Task};
+use crate::tunnel::dns::DnsResolver;
+use crate::tunnel::manager::PortForwardManager;
 use crate::tunnel::tcp::TcpPortPool;
 use crate::tunnel::udp::UdpPortPool;
 use crate::virtual_device::VirtualIpDevice;
 use crate::virtual_iface::tcp::TcpVirtualInterface;
 use crate::virtual_iface::udp::UdpVirtualInterface;
 use crate::virtual_iface::VirtualInterfacePoll;
-use crate::wg::WireGuardTunnel;
+use crate::wg::{PacketDispatch, Readiness, WireGuardTunnel, DISPATCH_CAPACITY};
 
+mod buffer;
 pub mod config;
+pub mod control;
 pub mod events;
+pub mod metrics;
 #[cfg(feature = "pcap")]
 pub mod pcap;
+mod supervisor;
 pub mod tunnel;
 pub mod virtual_device;
 pub mod virtual_iface;
 pub mod wg;
 
+/// The maximum time given to the virtual interfaces to close their connections on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Starts the onetun tunnels in separate tokio tasks.
+/// The returned handle is used to wait for the first WireGuard handshake, and to shut the tunnels down.
+///
+/// The port forwards, proxies and servers are restarted when they fail, following the restart policy.
+/// Other failures are fatal, and reported by `Tunnels::failed()`. If the tunnels fail to start, the tasks already
+/// started are shut down before the error is returned.
 ///
-/// Note: This future completes immediately.
-pub async fn start_tunnels(config: Config, bus: Bus) -> anyhow::Result<()> {
+/// Note: This future completes immediately, unless `wait_handshake` is configured.
+pub async fn start_tunnels(config: Config, bus: Bus) -> anyhow::Result<Tunnels> {
+    let (supervisor, failures) = Supervisor::new(config.restart_policy);
+    let mut wireguard_tasks = Vec::new();
+
     // Initialize the port pool for each protocol
     let tcp_port_pool = TcpPortPool::new();
     let udp_port_pool = UdpPortPool::new();
 
+    // The connections and traffic of the port forwards are counted by the virtual interfaces
+    let traffic = Arc::new(Traffic::default());
+
+    // IP packets go on dedicated channels between the WireGuard tunnel and the virtual devices
+    let (outbound_packets, outbound_packets_rx) = mpsc::channel(DISPATCH_CAPACITY);
+    let (tcp_packets, tcp_packets_rx) = mpsc::channel(DISPATCH_CAPACITY);
+    let (udp_packets, udp_packets_rx) = mpsc::channel(DISPATCH_CAPACITY);
+
     #[cfg(feature = "pcap")]
-    if let Some(pcap_file) = config.pcap_file.clone() {
+    let capture = config.pcap_file.clone().map(|pcap_file| {
         // Start packet capture
-        let bus = bus.clone();
-        tokio::spawn(async move { pcap::capture(pcap_file, bus).await });
-    }
+        let (capture, capture_rx) = mpsc::channel(DISPATCH_CAPACITY);
+        wireguard_tasks.push(supervisor.spawn(
+            "Packet capture".into(),
+            pcap::capture(pcap_file, capture_rx),
+        ));
+        capture
+    });
+    #[cfg(not(feature = "pcap"))]
+    let capture = None;
 
-    let wg = WireGuardTunnel::new(&config, bus.clone())
-        .await
-        .with_context(|| "Failed to initialize WireGuard tunnel")?;
-    let wg = Arc::new(wg);
+    let dispatch = PacketDispatch {
+        tcp: tcp_packets,
+        udp: udp_packets,
+        capture,
+    };
+    let wg = match WireGuardTunnel::new(&config, dispatch).await {
+        Ok(wg) => Arc::new(wg),
+        Err(e) => {
+            for task in wireguard_tasks {
+                task.stop().await;
+            }
+            return Err(e).with_context(|| "Failed to initialize WireGuard tunnel");
+        }
+    };
+
+    // Host names of destinations are resolved for each connection, through the tunnel if a DNS server is configured
+    let resolver = DnsResolver::new(
+        config.dns_server,
+        config.peers.clone(),
+        udp_port_pool.clone(),
+        bus.clone(),
+    );
+    let (manager, failed_port_forwards) = PortForwardManager::new(
+        config.peers.clone(),
+        tcp_port_pool.clone(),
+        udp_port_pool.clone(),
+        resolver.clone(),
+        bus.clone(),
+        supervisor.clone(),
+    );
+    let manager = Arc::new(tokio::sync::Mutex::new(manager));
+
+    // From now on, the tasks started so far are shut down if the tunnels fail to start
+    let mut tunnels = Tunnels {
+        readiness: wg.readiness(),
+        failures,
+        bus: bus.clone(),
+        manager: manager.clone(),
+        wireguard_tasks,
+        interface_tasks: Vec::new(),
+        server_tasks: Vec::new(),
+    };
+
+    // The port forwards added at runtime are removed when they fail, instead of stopping the tunnels
+    tunnels.server_tasks.push(supervisor.spawn(
+        "Port forward cleanup".into(),
+        tunnel::manager::remove_failed(manager.clone(), failed_port_forwards),
+    ));
 
     {
         // Start routine task for WireGuard
         let wg = wg.clone();
-        tokio::spawn(async move { wg.routine_task().await });
+        tunnels
+            .wireguard_tasks
+            .push(supervisor.spawn("WireGuard routine".into(), async move {
+                wg.routine_task().await
+            }));
+    }
+
+    {
+        // Start resolution task for WireGuard
+        let wg = wg.clone();
+        tunnels
+            .wireguard_tasks
+            .push(supervisor.spawn("WireGuard resolution".into(), async move {
+                wg.resolve_task().await
+            }));
     }
 
     {
         // Start consumption task for WireGuard
         let wg = wg.clone();
-        tokio::spawn(Box::pin(async move { wg.consume_task().await }));
+        tunnels.wireguard_tasks.push(supervisor.spawn(
+            "WireGuard consumption".into(),
+            Box::pin(async move { wg.consume_task().await }),
+        ));
     }
 
     {
         // Start production task for WireGuard
         let wg = wg.clone();
-        tokio::spawn(async move { wg.produce_task().await });
+        tunnels
+            .wireguard_tasks
+            .push(supervisor.spawn("WireGuard production".into(), async move {
+                wg.produce_task(outbound_packets_rx).await
+            }));
     }
 
-    if config
+    if let Some(metrics_addr) = config.metrics_addr {
+        let metrics = Arc::new(Metrics::new(
+            wg.clone(),
+            traffic.clone(),
+            tcp_port_pool.clone(),
+            udp_port_pool.clone(),
+            bus.clone(),
+        ));
+        info!("Starting metrics server on [{}]", metrics_addr);
+        tunnels.server_tasks.push(
+            supervisor
+                .spawn_restartable(format!("Metrics server on {}", metrics_addr), move || {
+                    metrics::metrics_server(metrics_addr, metrics.clone())
+                }),
+        );
+    }
+
+    if let Some(timeout) = config.wait_handshake {
+        // The port forwards and proxies only listen once the tunnel is usable
+        info!("Waiting for a WireGuard handshake");
+        let mut readiness = wg.readiness();
+        if tokio::time::timeout(timeout, readiness.wait())
+            .await
+            .is_err()
+        {
+            let e = anyhow::anyhow!(
+                "No WireGuard handshake completed within {} seconds",
+                timeout.as_secs()
+            );
+            return Err(tunnels.abort_start(e).await);
+        }
+    }
+
+    // Local and remote port forwards share the same virtual interfaces
+    let port_forwards: Vec<PortForwardConfig> = config
         .port_forwards
         .iter()
-        .any(|pf| pf.protocol == PortProtocol::Tcp)
+        .chain(config.remote_port_forwards.iter())
+        .cloned()
+        .collect();
+
+    // The SOCKS5 proxy uses both virtual interfaces, the HTTP proxy uses the TCP one, and DNS queries the UDP one.
+    // Both are started if port forwards of either protocol can be added by a reload or the control API.
+    let dynamic_port_forwards = config.config_file.is_some() || config.control_addr.is_some();
+    if dynamic_port_forwards
+        || port_forwards
+            .iter()
+            .any(|pf| pf.protocol == PortProtocol::Tcp)
+        || config.socks5_addr.is_some()
+        || config.http_proxy_addr.is_some()
     {
         // TCP device
-        let bus = bus.clone();
-        let device =
-            VirtualIpDevice::new(PortProtocol::Tcp, bus.clone(), config.max_transmission_unit);
+        let device = VirtualIpDevice::new(
+            tcp_packets_rx,
+            outbound_packets.clone(),
+            config.max_transmission_unit,
+        );
 
         // Start TCP Virtual Interface
-        let port_forwards = config.port_forwards.clone();
-        let iface = TcpVirtualInterface::new(port_forwards, bus, config.source_peer_ip);
-        tokio::spawn(async move { iface.poll_loop(device).await });
+        let port_forwards = port_forwards.clone();
+        let iface = TcpVirtualInterface::new(
+            port_forwards,
+            bus.clone(),
+            config.peers.clone(),
+            tcp_port_pool.clone(),
+            traffic.clone(),
+        );
+        tunnels
+            .interface_tasks
+            .push(supervisor.spawn("TCP virtual interface".into(), iface.poll_loop(device)));
     }
 
-    if config
-        .port_forwards
-        .iter()
-        .any(|pf| pf.protocol == PortProtocol::Udp)
+    if dynamic_port_forwards
+        || port_forwards
+            .iter()
+            .any(|pf| pf.protocol == PortProtocol::Udp)
+        || config.socks5_addr.is_some()
+        || config.dns_server.is_some()
     {
         // UDP device
-        let bus = bus.clone();
-        let device =
-            VirtualIpDevice::new(PortProtocol::Udp, bus.clone(), config.max_transmission_unit);
+        let device = VirtualIpDevice::new(
+            udp_packets_rx,
+            outbound_packets,
+            config.max_transmission_unit,
+        );
 
         // Start UDP Virtual Interface
-        let port_forwards = config.port_forwards.clone();
-        let iface = UdpVirtualInterface::new(port_forwards, bus, config.source_peer_ip);
-        tokio::spawn(async move { iface.poll_loop(device).await });
+        let port_forwards = port_forwards.clone();
+        let iface = UdpVirtualInterface::new(
+            port_forwards,
+            bus.clone(),
+            config.peers.clone(),
+            udp_port_pool.clone(),
+            traffic,
+        );
+        tunnels
+            .interface_tasks
+            .push(supervisor.spawn("UDP virtual interface".into(), iface.poll_loop(device)));
     }
 
-    {
-        let port_forwards = config.port_forwards;
-        let source_peer_ip = config.source_peer_ip;
-
-        port_forwards
-            .into_iter()
-            .map(|pf| {
-                (
-                    pf,
-                    wg.clone(),
+    if let Some(dns_forwarder_addr) = config.dns_forwarder_addr {
+        info!("Starting DNS forwarder on [{}]", dns_forwarder_addr);
+        let resolver = resolver.clone();
+        let cache = config.dns_forwarder_cache;
+        tunnels.server_tasks.push(supervisor.spawn_restartable(
+            format!("DNS forwarder on {}", dns_forwarder_addr),
+            move || {
+                tunnel::dns_forwarder::dns_forwarder_server(
+                    dns_forwarder_addr,
+                    resolver.clone(),
+                    cache,
+                )
+            },
+        ));
+    }
+
+    if let Some(socks5_addr) = config.socks5_addr {
+        info!("Starting SOCKS5 proxy on [{}]", socks5_addr);
+        let peers = config.peers.clone();
+        let tcp_port_pool = tcp_port_pool.clone();
+        let udp_port_pool = udp_port_pool.clone();
+        let resolver = resolver.clone();
+        let bus = bus.clone();
+        tunnels.server_tasks.push(supervisor.spawn_restartable(
+            format!("SOCKS5 proxy on {}", socks5_addr),
+            move || {
+                tunnel::socks::socks5_proxy_server(
+                    socks5_addr,
+                    peers.clone(),
                     tcp_port_pool.clone(),
                     udp_port_pool.clone(),
+                    resolver.clone(),
                     bus.clone(),
                 )
-            })
-            .for_each(move |(pf, wg, tcp_port_pool, udp_port_pool, bus)| {
-                tokio::spawn(async move {
-                    tunnel::port_forward(pf, source_peer_ip, tcp_port_pool, udp_port_pool, wg, bus)
-                        .await
-                        .unwrap_or_else(|e| error!("Port-forward failed for {} : {}", pf, e))
-                });
-            });
+            },
+        ));
     }
 
-    Ok(())
+    if let Some(http_proxy_addr) = config.http_proxy_addr {
+        info!("Starting HTTP proxy on [{}]", http_proxy_addr);
+        let peers = config.peers.clone();
+        let auth = config.http_proxy_auth.clone();
+        let tcp_port_pool = tcp_port_pool.clone();
+        let resolver = resolver.clone();
+        let bus = bus.clone();
+        tunnels.server_tasks.push(supervisor.spawn_restartable(
+            format!("HTTP proxy on {}", http_proxy_addr),
+            move || {
+                tunnel::http::http_proxy_server(
+                    http_proxy_addr,
+                    peers.clone(),
+                    auth.clone(),
+                    tcp_port_pool.clone(),
+                    resolver.clone(),
+                    bus.clone(),
+                )
+            },
+        ));
+    }
+
+    for pf in port_forwards {
+        let result = manager.lock().await.start(pf).await;
+        if let Err(e) = result {
+            return Err(tunnels.abort_start(e).await);
+        }
+    }
+    let config = Arc::new(config);
+
+    if let Some(control_addr) = config.control_addr.clone() {
+        info!("Starting control API on [{}]", control_addr);
+        let control = Arc::new(Control::new(
+            config.clone(),
+            manager.clone(),
+            wg,
+            tcp_port_pool,
+            udp_port_pool,
+            bus.clone(),
+        ));
+        tunnels.server_tasks.push(
+            supervisor.spawn_restartable(format!("Control API on {}", control_addr), move || {
+                control::control_server(control_addr.clone(), control.clone())
+            }),
+        );
+    }
+
+    #[cfg(unix)]
+    if config.config_file.is_some() {
+        // Reload the port forwards of the config file on SIGHUP
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                let e = anyhow::Error::new(e).context("Failed to listen for SIGHUP");
+                return Err(tunnels.abort_start(e).await);
+            }
+        };
+        let config = config.clone();
+        let manager = manager.clone();
+        tunnels
+            .server_tasks
+            .push(supervisor.spawn("SIGHUP handler".into(), async move {
+                while hangup.recv().await.is_some() {
+                    info!("Received SIGHUP, reloading port forwards");
+                    if let Err(e) = reload_port_forwards(&config, &manager).await {
+                        error!("Failed to reload port forwards: {:?}", e);
+                    }
+                }
+                Ok(())
+            }));
+    }
+
+    Ok(tunnels)
+}
+
+/// The running tunnels, as started by `start_tunnels`.
+/// Dropping this handle leaves the tunnels running; use `shutdown()` to stop them.
+pub struct Tunnels {
+    readiness: Readiness,
+    /// The fatal failures of the tasks.
+    failures: mpsc::UnboundedReceiver<anyhow::Error>,
+    bus: Bus,
+    manager: Arc<tokio::sync::Mutex<PortForwardManager>>,
+    /// The WireGuard tasks, stopped last so that the virtual connections can be closed through the tunnel.
+    wireguard_tasks: Vec<Task>,
+    /// The virtual interfaces, which stop once their connections are closed.
+    interface_tasks: Vec<Task>,
+    /// The proxies and servers listening for local clients, other than the port forwards.
+    server_tasks: Vec<Task>,
+}
+
+impl Tunnels {
+    /// The readiness of the tunnels, set once the first WireGuard handshake is completed.
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Waits for a fatal failure of the tunnels, e.g. a port forward that could not be restarted.
+    /// The tunnels should then be shut down.
+    pub async fn failed(&mut self) -> anyhow::Error {
+        match self.failures.recv().await {
+            Some(e) => e,
+            // The tasks cannot fail anymore
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Shuts down the tasks started so far, when starting the tunnels failed with the given error.
+    async fn abort_start(self, e: anyhow::Error) -> anyhow::Error {
+        if let Err(shutdown_error) = self.shutdown().await {
+            warn!("{:#}", shutdown_error);
+        }
+        e
+    }
+
+    /// Stops the tunnels:
+    /// 1. The port forwards, proxies and servers stop listening.
+    /// 2. The virtual TCP connections are closed with FIN once their pending data is sent, for up to 10 seconds.
+    /// 3. The WireGuard tasks are stopped.
+    ///
+    /// Returns an error if any of the tasks had a fatal failure, not already returned by `failed()`.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        info!("Shutting down");
+        for task in self.server_tasks {
+            task.stop().await;
+        }
+        self.manager.lock().await.stop().await;
+
+        self.bus.new_endpoint().send(Event::Shutdown);
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for task in self.interface_tasks {
+            task.stop_at(deadline).await;
+        }
+
+        for task in self.wireguard_tasks {
+            task.stop().await;
+        }
+
+        let mut errors = Vec::new();
+        while let Ok(e) = self.failures.try_recv() {
+            errors.push(e);
+        }
+        if errors.is_empty() {
+            info!("Shut down");
+            return Ok(());
+        }
+        let errors: Vec<String> = errors.iter().map(|e| format!("{:#}", e)).collect();
+        Err(anyhow::anyhow!(
+            "Some tasks had failed: {}",
+            errors.join("; ")
+        ))
+    }
+}
+
+/// Reads the port forwards from the config file again, and applies the changes to the running tunnel.
+pub async fn reload_port_forwards(
+    config: &Config,
+    manager: &tokio::sync::Mutex<PortForwardManager>,
+) -> anyhow::Result<()> {
+    let port_forwards = config.reload_port_forwards()?;
+    manager.lock().await.apply(port_forwards).await
 }
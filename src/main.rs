@@ -16,9 +16,56 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let bus = Bus::default();
-    onetun::start_tunnels(config, bus).await?;
+    let mut tunnels = onetun::start_tunnels(config, bus).await?;
 
-    futures::future::pending().await
+    // Stop on a signal, or when a task failed for good
+    let failure = tokio::select! {
+        result = shutdown_signal() => {
+            result?;
+            None
+        }
+        failure = tunnels.failed() => Some(failure),
+    };
+    let result = tokio::select! {
+        result = tunnels.shutdown() => result,
+        result = shutdown_signal() => {
+            result?;
+            Err(anyhow::anyhow!("Shutdown interrupted"))
+        }
+    };
+    match failure {
+        Some(failure) => {
+            if let Err(e) = result {
+                error!("{:#}", e);
+            }
+            Err(failure)
+        }
+        None => result,
+    }
+}
+
+/// Waits for SIGINT (Ctrl+C), or SIGTERM on Unix.
+#[cfg(feature = "bin")]
+async fn shutdown_signal() -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).with_context(|| "Failed to listen for SIGTERM")?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.with_context(|| "Failed to listen for SIGINT")?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .with_context(|| "Failed to listen for Ctrl+C")?;
+
+    info!("Received shutdown signal");
+    Ok(())
 }
 
 #[cfg(not(feature = "bin"))]
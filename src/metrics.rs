@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{PortForwardConfig, PortProtocol};
+use crate::events::Bus;
+use crate::tunnel::tcp::TcpPortPool;
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+use crate::wg::WireGuardTunnel;
+
+/// The maximum size of the request head (request line and headers) sent by a client.
+const MAX_HEAD_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+/// Statistics of the tunnel and its connections, exported in the Prometheus text format.
+///
+/// The connections and traffic of the port forwards are counted by the virtual interfaces. The state of the port pools
+/// and WireGuard tunnel is read when the metrics are rendered.
+pub struct Metrics {
+    traffic: Arc<Traffic>,
+    wg: Arc<WireGuardTunnel>,
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    bus: Bus,
+}
+
+/// The counters of the connections of the port forwards.
+#[derive(Debug, Default)]
+struct Counters {
+    /// The port forward of each active connection.
+    connections: HashMap<VirtualPort, PortForwardKey>,
+    connections_total: HashMap<PortProtocol, u64>,
+}
+
+/// Identifies a port forward in the labels of the metrics. The connections of the SOCKS5 and HTTP proxies
+/// have the address of the proxy as source, so they are counted together regardless of their destination.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct PortForwardKey {
+    protocol: PortProtocol,
+    source: SocketAddr,
+    name: Option<Arc<str>>,
+}
+
+impl From<&PortForwardConfig> for PortForwardKey {
+    fn from(port_forward: &PortForwardConfig) -> Self {
+        Self {
+            protocol: port_forward.protocol,
+            source: port_forward.source,
+            name: port_forward.name.clone(),
+        }
+    }
+}
+
+/// The connections of the port forwards, and their payload bytes sent into (outbound) and received from (inbound)
+/// the tunnel. They are counted by the virtual interfaces, as the connections and their data go through them.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    port_forward_bytes: Mutex<HashMap<PortForwardKey, (u64, u64)>>,
+    connections: Mutex<Counters>,
+}
+
+impl Traffic {
+    /// Counts a connection or UDP flow of the port forward, opened with the virtual port.
+    pub fn connection_opened(&self, port_forward: &PortForwardConfig, virtual_port: VirtualPort) {
+        self.connections
+            .lock()
+            .unwrap()
+            .connection(port_forward, virtual_port);
+    }
+
+    /// Counts the end of the connection or UDP flow of the virtual port.
+    pub fn connection_closed(&self, virtual_port: VirtualPort) {
+        self.connections
+            .lock()
+            .unwrap()
+            .connections
+            .remove(&virtual_port);
+    }
+
+    /// Counts the data of a connection of the port forward, sent into the tunnel.
+    pub fn outbound(&self, port_forward: &PortForwardConfig, size: usize) {
+        self.port_forward_bytes
+            .lock()
+            .unwrap()
+            .entry(port_forward.into())
+            .or_default()
+            .0 += size as u64;
+    }
+
+    /// Counts the data of a connection of the port forward, received from the tunnel.
+    pub fn inbound(&self, port_forward: &PortForwardConfig, size: usize) {
+        self.port_forward_bytes
+            .lock()
+            .unwrap()
+            .entry(port_forward.into())
+            .or_default()
+            .1 += size as u64;
+    }
+
+    fn render(&self, out: &mut String) {
+        let mut port_forwards: Vec<(String, (u64, u64))> = self
+            .port_forward_bytes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, bytes)| {
+                let labels = format!(
+                    "protocol=\"{}\",source=\"{}\",name=\"{}\"",
+                    key.protocol,
+                    key.source,
+                    escape_label(key.name.as_deref().unwrap_or_default())
+                );
+                (labels, *bytes)
+            })
+            .collect();
+        port_forwards.sort();
+        write_metric(
+            out,
+            "onetun_port_forward_bytes_total",
+            "Payload bytes sent into (outbound) and received from (inbound) the tunnel, by port forward.",
+            "counter",
+            &port_forwards
+                .iter()
+                .flat_map(|(labels, (outbound, inbound))| {
+                    [
+                        (format!("{},direction=\"inbound\"", labels), *inbound),
+                        (format!("{},direction=\"outbound\"", labels), *outbound),
+                    ]
+                })
+                .collect::<Vec<_>>(),
+        );
+        self.connections.lock().unwrap().render(out);
+    }
+}
+
+impl Metrics {
+    pub fn new(
+        wg: Arc<WireGuardTunnel>,
+        traffic: Arc<Traffic>,
+        tcp_port_pool: TcpPortPool,
+        udp_port_pool: UdpPortPool,
+        bus: Bus,
+    ) -> Self {
+        Self {
+            traffic,
+            wg,
+            tcp_port_pool,
+            udp_port_pool,
+            bus,
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub async fn render(&self) -> String {
+        let mut out = String::new();
+        let packets = self.wg.packet_stats();
+        write_metric(
+            &mut out,
+            "onetun_tunnel_packets_total",
+            "IP packets sent (outbound) and received (inbound) through the WireGuard tunnel.",
+            "counter",
+            &[
+                ("direction=\"inbound\"".into(), packets.inbound_packets),
+                ("direction=\"outbound\"".into(), packets.outbound_packets),
+            ],
+        );
+        write_metric(
+            &mut out,
+            "onetun_tunnel_bytes_total",
+            "Size of the IP packets sent (outbound) and received (inbound) through the WireGuard tunnel.",
+            "counter",
+            &[
+                ("direction=\"inbound\"".into(), packets.inbound_bytes),
+                ("direction=\"outbound\"".into(), packets.outbound_bytes),
+            ],
+        );
+        self.traffic.render(&mut out);
+
+        let tcp_ports = self.tcp_port_pool.active_ports().await.len();
+        let udp_ports = self.udp_port_pool.active_ports().await.len();
+        write_metric(
+            &mut out,
+            "onetun_virtual_ports_in_use",
+            "Virtual ports assigned to a connection or UDP flow.",
+            "gauge",
+            &[
+                (
+                    format!("protocol=\"{}\"", PortProtocol::Tcp),
+                    tcp_ports as u64,
+                ),
+                (
+                    format!("protocol=\"{}\"", PortProtocol::Udp),
+                    udp_ports as u64,
+                ),
+            ],
+        );
+        write_metric(
+            &mut out,
+            "onetun_virtual_ports_capacity",
+            "Size of the virtual port pool.",
+            "gauge",
+            &[
+                (
+                    format!("protocol=\"{}\"", PortProtocol::Tcp),
+                    self.tcp_port_pool.capacity() as u64,
+                ),
+                (
+                    format!("protocol=\"{}\"", PortProtocol::Udp),
+                    self.udp_port_pool.capacity() as u64,
+                ),
+            ],
+        );
+
+        let peers = self.wg.stats();
+        let peer_label = |name: &str| format!("peer=\"{}\"", escape_label(name));
+        write_metric(
+            &mut out,
+            "onetun_wireguard_last_handshake_seconds",
+            "Time since the last handshake with the peer, if any was completed.",
+            "gauge",
+            &peers
+                .iter()
+                .filter_map(|peer| {
+                    peer.last_handshake
+                        .map(|age| (peer_label(&peer.name), age.as_secs()))
+                })
+                .collect::<Vec<_>>(),
+        );
+        write_metric(
+            &mut out,
+            "onetun_wireguard_peer_bytes_total",
+            "Data bytes sent to (tx) and received from (rx) the peer.",
+            "counter",
+            &peers
+                .iter()
+                .flat_map(|peer| {
+                    [
+                        (
+                            format!("{},direction=\"rx\"", peer_label(&peer.name)),
+                            peer.rx_bytes,
+                        ),
+                        (
+                            format!("{},direction=\"tx\"", peer_label(&peer.name)),
+                            peer.tx_bytes,
+                        ),
+                    ]
+                })
+                .collect::<Vec<_>>(),
+        );
+        write_metric(
+            &mut out,
+            "onetun_wireguard_handshakes_total",
+            "Handshake messages from the peer that were processed successfully.",
+            "counter",
+            &peers
+                .iter()
+                .map(|peer| (peer_label(&peer.name), peer.handshakes))
+                .collect::<Vec<_>>(),
+        );
+        write_metric(
+            &mut out,
+            "onetun_wireguard_encapsulate_errors_total",
+            "IP packets that could not be encapsulated for the peer.",
+            "counter",
+            &peers
+                .iter()
+                .map(|peer| (peer_label(&peer.name), peer.encapsulate_errors))
+                .collect::<Vec<_>>(),
+        );
+        write_metric(
+            &mut out,
+            "onetun_wireguard_decapsulate_errors_total",
+            "WireGuard datagrams from the peer that could not be decapsulated.",
+            "counter",
+            &peers
+                .iter()
+                .map(|peer| (peer_label(&peer.name), peer.decapsulate_errors))
+                .collect::<Vec<_>>(),
+        );
+
+        write_metric(
+            &mut out,
+            "onetun_bus_lag_errors_total",
+            "Times a task lagged behind the event bus and missed events.",
+            "counter",
+            &[(String::new(), self.bus.lag_errors())],
+        );
+        out
+    }
+}
+
+impl Counters {
+    /// Tracks a connection, unless it is already known.
+    fn connection(&mut self, port_forward: &PortForwardConfig, virtual_port: VirtualPort) {
+        if self.connections.contains_key(&virtual_port) {
+            return;
+        }
+        self.connections.insert(virtual_port, port_forward.into());
+        *self
+            .connections_total
+            .entry(virtual_port.proto())
+            .or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let protocols = [PortProtocol::Tcp, PortProtocol::Udp];
+        write_metric(
+            out,
+            "onetun_connections_active",
+            "Open connections. UDP flows are counted until their virtual port is dropped.",
+            "gauge",
+            &protocols
+                .iter()
+                .map(|protocol| {
+                    let count = self
+                        .connections
+                        .keys()
+                        .filter(|virtual_port| virtual_port.proto() == *protocol)
+                        .count();
+                    (format!("protocol=\"{}\"", protocol), count as u64)
+                })
+                .collect::<Vec<_>>(),
+        );
+        write_metric(
+            out,
+            "onetun_connections_total",
+            "Connections and UDP flows opened.",
+            "counter",
+            &protocols
+                .iter()
+                .map(|protocol| {
+                    let count = self
+                        .connections_total
+                        .get(protocol)
+                        .copied()
+                        .unwrap_or_default();
+                    (format!("protocol=\"{}\"", protocol), count)
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+}
+
+/// Writes a metric family, with one sample for each set of labels.
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Starts the metrics server, which serves the metrics on `GET /metrics`, and health checks for probes:
+/// `GET /healthz` always succeeds, and `GET /readyz` succeeds while the session of a WireGuard handshake with any peer
+/// did not expire.
+pub async fn metrics_server(bind_addr: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind_addr)
+        .await
+        .with_context(|| "Failed to listen on metrics address")?;
+    loop {
+        let (socket, client_addr) = listener
+            .accept()
+            .await
+            .with_context(|| "Failed to accept connection on metrics server")?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_metrics_request(socket, &metrics).await {
+                warn!("Metrics request from {} failed: {:?}", client_addr, e);
+            }
+        });
+    }
+}
+
+/// Serves a single request, after which the connection is closed.
+async fn handle_metrics_request(mut socket: TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    let mut buffer = BytesMut::with_capacity(MAX_HEAD_SIZE);
+    let path = loop {
+        if socket.read_buf(&mut buffer).await? == 0 {
+            return Ok(());
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        if request
+            .parse(&buffer)
+            .with_context(|| "Invalid HTTP request")?
+            .is_complete()
+        {
+            break request.path.unwrap_or_default().to_string();
+        }
+        if buffer.len() >= MAX_HEAD_SIZE {
+            return Err(anyhow::anyhow!("Request head is too large"));
+        }
+    };
+
+    let (status, body) = match path.as_str() {
+        "/metrics" => ("200 OK", metrics.render().await),
+        "/healthz" => ("200 OK", "ok\n".into()),
+        "/readyz" if metrics.wg.is_ready() => ("200 OK", "ready\n".into()),
+        "/readyz" => (
+            "503 Service Unavailable",
+            "waiting for a WireGuard handshake\n".into(),
+        ),
+        _ => ("404 Not Found", String::new()),
+    };
+    socket
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await
+        .with_context(|| "Failed to send metrics response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the counters of the traffic and connections of a port forward.
+    #[test]
+    fn test_traffic() {
+        let port_forward = PortForwardConfig {
+            name: Some("web".into()),
+            source: "127.0.0.1:8080".parse().unwrap(),
+            destination: "192.168.4.2:80".parse().unwrap(),
+            destination_host: None,
+            protocol: PortProtocol::Tcp,
+            remote: false,
+            peer: None,
+        };
+        let virtual_port = VirtualPort::new(40000, PortProtocol::Tcp);
+
+        let traffic = Traffic::default();
+        traffic.connection_opened(&port_forward, virtual_port);
+        // The UDP flows reused by another local server are only counted once
+        traffic.connection_opened(&port_forward, virtual_port);
+        traffic.outbound(&port_forward, 10);
+        traffic.inbound(&port_forward, 25);
+
+        let mut out = String::new();
+        traffic.render(&mut out);
+        let labels = "protocol=\"TCP\",source=\"127.0.0.1:8080\",name=\"web\"";
+        assert!(out.contains(&format!(
+            "onetun_port_forward_bytes_total{{{},direction=\"outbound\"}} 10\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "onetun_port_forward_bytes_total{{{},direction=\"inbound\"}} 25\n",
+            labels
+        )));
+        assert!(out.contains("onetun_connections_active{protocol=\"TCP\"} 1\n"));
+
+        traffic.connection_closed(virtual_port);
+        let mut out = String::new();
+        traffic.render(&mut out);
+        assert!(out.contains("onetun_connections_active{protocol=\"TCP\"} 0\n"));
+        assert!(out.contains("onetun_connections_total{protocol=\"TCP\"} 1\n"));
+    }
+}
@@ -1,9 +1,9 @@
-use crate::events::Event;
-use crate::Bus;
 use anyhow::Context;
+use bytes::Bytes;
 use smoltcp::time::Instant;
 use tokio::fs::File;
 use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
 
 struct Pcap {
     writer: BufWriter<File>,
@@ -76,9 +76,8 @@ impl Pcap {
     }
 }
 
-/// Listens on the event bus for IP packets sent from and to the WireGuard tunnel.
-pub async fn capture(pcap_file: String, bus: Bus) -> anyhow::Result<()> {
-    let mut endpoint = bus.new_endpoint();
+/// Writes the IP packets sent from and to the WireGuard tunnel, as received on the given channel.
+pub async fn capture(pcap_file: String, mut packets: mpsc::Receiver<Bytes>) -> anyhow::Result<()> {
     let file = File::create(&pcap_file)
         .await
         .with_context(|| "Failed to create pcap file")?;
@@ -91,23 +90,12 @@ pub async fn capture(pcap_file: String, bus: Bus) -> anyhow::Result<()> {
         .with_context(|| "Failed to write global header to pcap writer")?;
 
     info!("Capturing WireGuard IP packets to {}", &pcap_file);
-    loop {
-        match endpoint.recv().await {
-            Event::InboundInternetPacket(_proto, ip) => {
-                let instant = Instant::now();
-                writer
-                    .packet(instant, &ip)
-                    .await
-                    .with_context(|| "Failed to write inbound IP packet to pcap writer")?;
-            }
-            Event::OutboundInternetPacket(ip) => {
-                let instant = Instant::now();
-                writer
-                    .packet(instant, &ip)
-                    .await
-                    .with_context(|| "Failed to write output IP packet to pcap writer")?;
-            }
-            _ => {}
-        }
+    while let Some(ip) = packets.recv().await {
+        let instant = Instant::now();
+        writer
+            .packet(instant, &ip)
+            .await
+            .with_context(|| "Failed to write IP packet to pcap writer")?;
     }
+    Ok(())
 }
@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use futures::FutureExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::config::RestartPolicy;
+
+/// The maximum delay between two restarts of a task. A task that ran for this long before failing is considered
+/// healthy again, so its restarts are counted from zero.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Spawns the tasks of the tunnels, and reports their fatal failures.
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    policy: RestartPolicy,
+    failures: mpsc::UnboundedSender<anyhow::Error>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor, and the receiver of the fatal failures of its tasks.
+    pub fn new(policy: RestartPolicy) -> (Self, mpsc::UnboundedReceiver<anyhow::Error>) {
+        let (failures, failures_rx) = mpsc::unbounded_channel();
+        (Self { policy, failures }, failures_rx)
+    }
+
+    /// Spawns a task that cannot be restarted: its failure is fatal.
+    pub fn spawn<F>(&self, name: String, future: F) -> Task
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let task_name = name.clone();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = run(future).await {
+                supervisor.fail(&task_name, e);
+            }
+        });
+        Task { name, handle }
+    }
+
+    /// Spawns a task that is started again with exponential backoff when it fails, as allowed by the restart policy.
+    /// Its failure is fatal once it was restarted too many times.
+    pub fn spawn_restartable<F, Fut>(&self, name: String, start: F) -> Task
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let task_name = name.clone();
+        self.spawn_restartable_with(name, start, move |e| supervisor.fail(&task_name, e))
+    }
+
+    /// Spawns a task that is restarted like `spawn_restartable`, but whose failure is handed to `on_failure`
+    /// instead of being fatal (e.g. a port forward added at runtime).
+    pub fn spawn_restartable_with<F, Fut, H>(&self, name: String, start: F, on_failure: H) -> Task
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+        H: FnOnce(anyhow::Error) + Send + 'static,
+    {
+        let policy = self.policy;
+        let task_name = name.clone();
+        let handle = tokio::spawn(async move {
+            let mut restarts = 0;
+            let mut backoff = policy.backoff;
+            loop {
+                let started_at = Instant::now();
+                let e = match run(start()).await {
+                    Ok(()) => return,
+                    Err(e) => e,
+                };
+                if started_at.elapsed() >= MAX_BACKOFF {
+                    restarts = 0;
+                    backoff = policy.backoff;
+                }
+                if restarts >= policy.max_restarts {
+                    on_failure(e);
+                    return;
+                }
+
+                restarts += 1;
+                warn!(
+                    "{} failed, restarting in {:?} ({}/{}): {:?}",
+                    task_name, backoff, restarts, policy.max_restarts, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+        Task { name, handle }
+    }
+
+    fn fail(&self, name: &str, e: anyhow::Error) {
+        error!("{} failed: {:?}", name, e);
+        // The receiver is only dropped after the tasks are stopped
+        let _ = self.failures.send(e.context(format!("{} failed", name)));
+    }
+}
+
+/// Runs the future of a task, turning a panic into an error.
+async fn run<F>(future: F) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Task panicked")))
+}
+
+/// A task spawned by the supervisor.
+pub(crate) struct Task {
+    name: String,
+    handle: JoinHandle<()>,
+}
+
+impl Task {
+    /// Stops the task.
+    pub async fn stop(self) {
+        self.handle.abort();
+        let _ = self.handle.await;
+    }
+
+    /// Waits until the deadline for the task to end, then stops it.
+    pub async fn stop_at(mut self, deadline: Instant) {
+        if tokio::time::timeout_at(deadline, &mut self.handle)
+            .await
+            .is_err()
+        {
+            warn!("{} did not stop in time, aborting it", self.name);
+            self.stop().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Tests that a failing task is restarted as allowed by the policy, before its failure is reported.
+    #[tokio::test]
+    async fn test_spawn_restartable() {
+        let (supervisor, mut failures) = Supervisor::new(RestartPolicy {
+            max_restarts: 2,
+            backoff: Duration::from_millis(1),
+        });
+        let attempts = Arc::new(AtomicU32::new(0));
+        let task_attempts = attempts.clone();
+        let _task = supervisor.spawn_restartable("Listener".into(), move || {
+            task_attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err(anyhow::anyhow!("Address already in use")) }
+        });
+
+        let failure = failures.recv().await.unwrap();
+        assert_eq!(
+            format!("{:#}", failure),
+            "Listener failed: Address already in use"
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    /// Tests that the failure of a recoverable task is handed to its handler, instead of being fatal.
+    #[tokio::test]
+    async fn test_spawn_restartable_with() {
+        let (supervisor, mut failures) = Supervisor::new(RestartPolicy {
+            max_restarts: 1,
+            backoff: Duration::from_millis(1),
+        });
+        let (failed, mut failed_rx) = mpsc::unbounded_channel();
+        let task = supervisor.spawn_restartable_with(
+            "Port-forward".into(),
+            || async { Err(anyhow::anyhow!("Address already in use")) },
+            move |e| failed.send(format!("{:#}", e)).unwrap(),
+        );
+
+        assert_eq!(
+            failed_rx.recv().await.as_deref(),
+            Some("Address already in use")
+        );
+        task.handle.await.unwrap();
+        assert!(failures.try_recv().is_err());
+    }
+
+    /// Tests that the panic of a task is reported as its failure.
+    #[tokio::test]
+    async fn test_spawn_panic() {
+        let (supervisor, mut failures) = Supervisor::new(RestartPolicy::default());
+        let _task = supervisor.spawn("Interface".into(), async { panic!("bug") });
+
+        let failure = failures.recv().await.unwrap();
+        assert_eq!(format!("{:#}", failure), "Interface failed: Task panicked");
+    }
+}
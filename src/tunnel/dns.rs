@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{route_peer, PeerConfig, PortForwardConfig, PortProtocol};
+use crate::events::{Bus, LocalMessage, RemoteMessage};
+use crate::tunnel::dynamic_port_forward;
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+
+/// How long to wait for a response from the DNS server, before sending the query again.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_ATTEMPTS: usize = 3;
+/// How many responses can be queued before they are dropped.
+const MAX_QUEUED_RESPONSES: usize = 100;
+/// The maximum time a resolved name is cached, regardless of the TTL of its records.
+const MAX_TTL_SECONDS: u32 = 3600;
+
+const HEADER_SIZE: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NAME_ERROR: u16 = 3;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+/// The flow of the virtual port used for the DNS queries, in the UDP port pool.
+const QUERY_FLOW_SOURCE: ([u8; 4], u16) = ([0, 0, 0, 0], 0);
+
+/// Resolves the host names of destinations when connections are made.
+///
+/// If a DNS server is configured, it is queried through the WireGuard tunnel (over the UDP virtual interface),
+/// and the results are cached for the TTL of the records. Otherwise, names are resolved by onetun's host.
+#[derive(Clone)]
+pub struct DnsResolver {
+    inner: Arc<DnsResolverInner>,
+}
+
+struct DnsResolverInner {
+    server: Option<SocketAddr>,
+    peers: Vec<PeerConfig>,
+    udp_port_pool: UdpPortPool,
+    bus: Bus,
+    /// The virtual port used for all the queries, with the channel of the UDP virtual interface, assigned on the first one.
+    connection: tokio::sync::Mutex<Option<(VirtualPort, mpsc::Sender<LocalMessage>)>>,
+    /// The queries waiting for their response, by ID in flight.
+    queries: Arc<Mutex<HashMap<u16, oneshot::Sender<Bytes>>>>,
+    /// Resolved addresses by lowercase name.
+    cache: Mutex<HashMap<String, CachedAddress>>,
+}
+
+struct CachedAddress {
+    address: IpAddr,
+    expires_at: Instant,
+}
+
+impl DnsResolver {
+    pub fn new(
+        server: Option<SocketAddr>,
+        peers: Vec<PeerConfig>,
+        udp_port_pool: UdpPortPool,
+        bus: Bus,
+    ) -> Self {
+        Self {
+            inner: Arc::new(DnsResolverInner {
+                server,
+                peers,
+                udp_port_pool,
+                bus,
+                connection: tokio::sync::Mutex::new(None),
+                queries: Default::default(),
+                cache: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Returns the port forward with its destination resolved, if it was given as a host name.
+    pub async fn resolve_port_forward(
+        &self,
+        port_forward: &PortForwardConfig,
+    ) -> anyhow::Result<PortForwardConfig> {
+        let mut port_forward = port_forward.clone();
+        if let Some(host) = port_forward.destination_host.take() {
+            let peer = port_forward.select_peer(&self.inner.peers);
+            let ip = self.resolve_through(&host, peer).await?;
+            port_forward.destination = SocketAddr::new(ip, port_forward.destination.port());
+        }
+        Ok(port_forward)
+    }
+
+    /// Resolves a host name, or parses it if it is an IP address.
+    /// The destination is expected to be reached through the same peer as the DNS server.
+    pub async fn resolve(&self, host: &str) -> anyhow::Result<IpAddr> {
+        let peer = self
+            .inner
+            .server
+            .and_then(|server| route_peer(&self.inner.peers, server.ip()));
+        self.resolve_through(host, peer).await
+    }
+
+    /// Resolves a host name for a destination reached through the given peer,
+    /// querying only the record types of the IP versions of its source peer IPs.
+    async fn resolve_through(
+        &self,
+        host: &str,
+        peer: Option<&PeerConfig>,
+    ) -> anyhow::Result<IpAddr> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(ip);
+        }
+        let server = match self.inner.server {
+            Some(server) => server,
+            None => {
+                return tokio::net::lookup_host((host, 0))
+                    .await
+                    .with_context(|| format!("Failed to resolve {}", host))?
+                    .next()
+                    .map(|addr| addr.ip())
+                    .with_context(|| format!("Could not resolve {}", host))
+            }
+        };
+
+        // Only the IP versions of the source peer IPs can be reached through the peer
+        let peer = peer.with_context(|| format!("No peer to resolve {} for", host))?;
+        let record_types = record_types(peer);
+
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(ip) = self.cached(&name) {
+            if record_types.contains(&record_type(ip)) {
+                return Ok(ip);
+            }
+        }
+
+        for record_type in record_types {
+            let answer = self
+                .query(server, &name, record_type)
+                .await
+                .with_context(|| format!("Failed to resolve {}", host))?;
+            if let Some((addresses, ttl)) = answer {
+                let address = addresses[0];
+                debug!("Resolved {} to {} (TTL {}s)", name, address, ttl);
+                self.inner.cache.lock().unwrap().insert(
+                    name,
+                    CachedAddress {
+                        address,
+                        expires_at: Instant::now()
+                            + Duration::from_secs(ttl.min(MAX_TTL_SECONDS) as u64),
+                    },
+                );
+                return Ok(address);
+            }
+        }
+        Err(anyhow::anyhow!("Could not resolve {}", host))
+    }
+
+    fn cached(&self, name: &str) -> Option<IpAddr> {
+        let mut cache = self.inner.cache.lock().unwrap();
+        match cache.get(name) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.address),
+            Some(_) => {
+                cache.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Sends a query to the DNS server through the tunnel, and parses the addresses in its response.
+    async fn query(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        record_type: u16,
+    ) -> anyhow::Result<Option<(Vec<IpAddr>, u32)>> {
+        let query = build_query(0, name, record_type)?;
+        let response = self.exchange(server, &query).await?;
+        parse_response(&response, record_type)
+    }
+
+    /// Forwards a query from a local client to the DNS server, and returns the response as-is.
+    pub async fn forward(&self, query: &[u8]) -> anyhow::Result<Bytes> {
+        let server = self
+            .inner
+            .server
+            .with_context(|| "No DNS server is configured")?;
+        self.exchange(server, query).await
+    }
+
+    /// Sends a message to the DNS server through the tunnel, and waits for its response.
+    ///
+    /// The ID of the message is replaced while in flight, since all the queries share the same virtual port;
+    /// the response has the original ID.
+    async fn exchange(&self, server: SocketAddr, message: &[u8]) -> anyhow::Result<Bytes> {
+        if message.len() < HEADER_SIZE {
+            return Err(anyhow::anyhow!("DNS message is too short"));
+        }
+        let source = SocketAddr::from(QUERY_FLOW_SOURCE);
+        let port_forward =
+            dynamic_port_forward(&self.inner.peers, source, server, PortProtocol::Udp)?;
+        let (virtual_port, interface) = {
+            let mut connection = self.inner.connection.lock().await;
+            match &*connection {
+                Some(connection) => connection.clone(),
+                None => {
+                    let port = self
+                        .inner
+                        .udp_port_pool
+                        .next_for_destination(source, server)
+                        .await
+                        .with_context(|| "Failed to assign virtual port number for DNS queries")?;
+                    let interface = self.inner.bus.interface(PortProtocol::Udp)?;
+                    let (sender, responses) = mpsc::channel(MAX_QUEUED_RESPONSES);
+                    interface
+                        .send(LocalMessage::Connect(port_forward, port, sender, None))
+                        .await
+                        .with_context(|| "The UDP virtual interface stopped")?;
+                    // A new port is assigned for the next queries if the flow is closed (e.g. by the control API)
+                    let resolver = self.clone();
+                    tokio::spawn(async move {
+                        dispatch_responses(responses, resolver.inner.queries.clone()).await;
+                        let mut connection = resolver.inner.connection.lock().await;
+                        if matches!(&*connection, Some((p, _)) if *p == port) {
+                            *connection = None;
+                        }
+                    });
+                    *connection = Some((port, interface.clone()));
+                    (port, interface)
+                }
+            }
+        };
+
+        for attempt in 1..=QUERY_ATTEMPTS {
+            let id: u16 = rand::random();
+            let mut query = BytesMut::from(message);
+            query[..2].copy_from_slice(&id.to_be_bytes());
+            let (response_sender, response) = oneshot::channel();
+            self.inner
+                .queries
+                .lock()
+                .unwrap()
+                .insert(id, response_sender);
+            self.inner
+                .udp_port_pool
+                .update_last_transmit(virtual_port)
+                .await;
+            interface
+                .send(LocalMessage::Data(virtual_port, query.freeze()))
+                .await
+                .with_context(|| "The UDP virtual interface stopped")?;
+
+            let response = tokio::time::timeout(QUERY_TIMEOUT, response).await;
+            self.inner.queries.lock().unwrap().remove(&id);
+            match response {
+                Ok(Ok(data)) => {
+                    let mut response = BytesMut::from(&data[..]);
+                    response[..2].copy_from_slice(&message[..2]);
+                    return Ok(response.freeze());
+                }
+                _ => debug!(
+                    "[{}] DNS query timed out (attempt {}/{})",
+                    virtual_port, attempt, QUERY_ATTEMPTS
+                ),
+            }
+        }
+        Err(anyhow::anyhow!("DNS server {} did not respond", server))
+    }
+}
+
+/// Sends the responses received from the DNS server to the queries waiting for them, by ID.
+async fn dispatch_responses(
+    mut responses: mpsc::Receiver<(VirtualPort, RemoteMessage)>,
+    queries: Arc<Mutex<HashMap<u16, oneshot::Sender<Bytes>>>>,
+) {
+    while let Some((_, message)) = responses.recv().await {
+        let response = match message {
+            RemoteMessage::Data(response) => response,
+            RemoteMessage::Closed => break,
+            RemoteMessage::Established => continue,
+        };
+        let id = match response.get(..2) {
+            Some(id) => u16::from_be_bytes([id[0], id[1]]),
+            None => continue,
+        };
+        if let Some(query) = queries.lock().unwrap().remove(&id) {
+            let _ = query.send(response);
+        }
+    }
+}
+
+/// The record types to query for a destination reached through the peer, in the order of its source peer IPs.
+fn record_types(peer: &PeerConfig) -> Vec<u16> {
+    let mut record_types = Vec::new();
+    for cidr in &peer.source_peer_ips {
+        let record_type = record_type(cidr.address().into());
+        if !record_types.contains(&record_type) {
+            record_types.push(record_type);
+        }
+    }
+    record_types
+}
+
+/// The type of the records holding addresses of the IP version of `ip`.
+fn record_type(ip: IpAddr) -> u16 {
+    if ip.is_ipv4() {
+        TYPE_A
+    } else {
+        TYPE_AAAA
+    }
+}
+
+/// Builds a recursive query for the given name and record type.
+fn build_query(id: u16, name: &str, record_type: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // One question, no records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow::anyhow!("Invalid host name '{}'", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Parses the addresses in a response, with the lowest TTL among them.
+/// Returns `None` if the name does not exist, or has no record of the requested type.
+fn parse_response(response: &[u8], record_type: u16) -> anyhow::Result<Option<(Vec<IpAddr>, u32)>> {
+    let mut reader = Reader {
+        data: response,
+        position: 0,
+    };
+    reader.u16()?; // ID
+    let flags = reader.u16()?;
+    let question_count = reader.u16()?;
+    let answer_count = reader.u16()?;
+    reader.skip(4)?; // Authority and additional record counts
+
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(anyhow::anyhow!("Invalid DNS response"));
+    }
+    if flags & FLAG_TRUNCATED != 0 {
+        return Err(anyhow::anyhow!("DNS response is truncated"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NAME_ERROR => return Ok(None),
+        rcode => return Err(anyhow::anyhow!("DNS server returned error code {}", rcode)),
+    }
+
+    for _ in 0..question_count {
+        reader.skip_name()?;
+        reader.skip(4)?; // Type and class
+    }
+
+    // Answers may include other records, such as the CNAME records leading to the addresses
+    let mut addresses = Vec::new();
+    let mut min_ttl = u32::MAX;
+    for _ in 0..answer_count {
+        reader.skip_name()?;
+        let answer_type = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let data_len = reader.u16()? as usize;
+        let data = reader.take(data_len)?;
+        if class != CLASS_IN || answer_type != record_type {
+            continue;
+        }
+        let address = match (answer_type, data.len()) {
+            (TYPE_A, 4) => IpAddr::from(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                IpAddr::from(Ipv6Addr::from(octets))
+            }
+            _ => return Err(anyhow::anyhow!("Invalid DNS record")),
+        };
+        addresses.push(address);
+        min_ttl = min_ttl.min(ttl);
+    }
+
+    if addresses.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some((addresses, min_ttl)))
+    }
+}
+
+/// Returns the question of a query, with the name in lowercase, to identify its answers.
+/// Returns `None` unless the query has exactly one question.
+pub(crate) fn question_key(query: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = Reader {
+        data: query,
+        position: 4,
+    };
+    if reader.u16().ok()? != 1 {
+        return None;
+    }
+    reader.position = HEADER_SIZE;
+    reader.skip_name().ok()?;
+    reader.skip(4).ok()?; // Type and class
+    Some(query[HEADER_SIZE..reader.position].to_ascii_lowercase())
+}
+
+/// Returns the position and value of the TTL of each record in a response, to age them when cached.
+/// The OPT pseudo-record is skipped, since its TTL field holds flags.
+pub(crate) fn record_ttls(response: &[u8]) -> anyhow::Result<Vec<(usize, u32)>> {
+    let mut reader = Reader {
+        data: response,
+        position: 4,
+    };
+    let question_count = reader.u16()?;
+    let record_count = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+
+    for _ in 0..question_count {
+        reader.skip_name()?;
+        reader.skip(4)?; // Type and class
+    }
+
+    let mut ttls = Vec::with_capacity(record_count);
+    for _ in 0..record_count {
+        reader.skip_name()?;
+        let record_type = reader.u16()?;
+        reader.skip(2)?; // Class
+        let position = reader.position;
+        let ttl = reader.u32()?;
+        let data_len = reader.u16()? as usize;
+        reader.skip(data_len)?;
+        if record_type != TYPE_OPT {
+            ttls.push((position, ttl));
+        }
+    }
+    Ok(ttls)
+}
+
+/// Reads the fields of a DNS message.
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .with_context(|| "DNS message is too short")?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> anyhow::Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Skips a name, which ends with an empty label or a pointer to another name.
+    fn skip_name(&mut self) -> anyhow::Result<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => return self.skip(1),
+                len => self.skip(len as usize)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the encoding of a query.
+    #[test]
+    fn test_build_query() {
+        assert_eq!(
+            build_query(0x1234, "peer.intranet", TYPE_A).expect("Failed to build"),
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+            \x04peer\x08intranet\x00\x00\x01\x00\x01"
+                .to_vec()
+        );
+        assert!(build_query(0x1234, "peer..intranet", TYPE_A).is_err());
+    }
+
+    /// Tests the parsing of a response with a CNAME record and compressed names.
+    #[test]
+    fn test_parse_response() {
+        let mut response = b"\x12\x34\x81\x80\x00\x01\x00\x03\x00\x00\x00\x00\
+            \x03www\x08intranet\x00\x00\x01\x00\x01"
+            .to_vec();
+        // www.intranet CNAME peer.intranet
+        response
+            .extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x07\x04peer\xc0\x10");
+        // peer.intranet A 192.168.4.2 and 192.168.4.3
+        response
+            .extend_from_slice(b"\xc0\x2a\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\xc0\xa8\x04\x02");
+        response
+            .extend_from_slice(b"\xc0\x2a\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\xa8\x04\x03");
+
+        assert_eq!(
+            parse_response(&response, TYPE_A).expect("Failed to parse"),
+            Some((
+                vec![
+                    IpAddr::from([192, 168, 4, 2]),
+                    IpAddr::from([192, 168, 4, 3])
+                ],
+                60
+            ))
+        );
+        assert_eq!(
+            parse_response(&response, TYPE_AAAA).expect("Failed to parse"),
+            None
+        );
+
+        // NXDOMAIN
+        let response = b"\x12\x34\x81\x83\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            parse_response(response, TYPE_A).expect("Failed to parse"),
+            None
+        );
+        assert!(parse_response(&response[..8], TYPE_A).is_err());
+    }
+
+    /// Tests finding the question and TTLs of a message, for the forwarder cache.
+    #[test]
+    fn test_question_key_and_record_ttls() {
+        let mut query = build_query(0x1234, "Peer.Intranet", TYPE_A).expect("Failed to build");
+        assert_eq!(
+            question_key(&query),
+            Some(query[HEADER_SIZE..].to_ascii_lowercase())
+        );
+
+        // Response with an A record and an OPT pseudo-record
+        query[2] = 0x81;
+        query[3] = 0x80;
+        query[7] = 1;
+        query[11] = 1;
+        query
+            .extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\xa8\x04\x02");
+        query.extend_from_slice(b"\x00\x00\x29\x10\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(
+            record_ttls(&query).expect("Failed to parse"),
+            vec![(37, 60)]
+        );
+    }
+
+    /// Tests that only the record types of the IP versions of the peer's source peer IPs are queried.
+    #[test]
+    fn test_record_types() {
+        use smoltcp::wire::{IpAddress, IpCidr};
+
+        let mut peer = PeerConfig::test("fd00::3/64", None);
+        assert_eq!(record_types(&peer), vec![TYPE_AAAA]);
+
+        peer.source_peer_ips
+            .push(IpCidr::new(IpAddress::v4(192, 168, 4, 3), 24));
+        peer.source_peer_ips
+            .push(IpCidr::new(IpAddress::v4(10, 0, 0, 3), 32));
+        assert_eq!(record_types(&peer), vec![TYPE_AAAA, TYPE_A]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::tunnel::dns::{question_key, record_ttls, DnsResolver};
+
+const MAX_PACKET: usize = 65536;
+/// The maximum time a response is cached, regardless of the TTL of its records.
+const MAX_TTL_SECONDS: u32 = 3600;
+
+/// Starts the DNS forwarder, which answers the queries of local clients (over UDP and TCP) with the
+/// DNS server reached through the tunnel. Queries received over TCP are forwarded over UDP as well.
+pub async fn dns_forwarder_server(
+    bind_addr: SocketAddr,
+    resolver: DnsResolver,
+    cache: bool,
+) -> anyhow::Result<()> {
+    let udp_socket = UdpSocket::bind(bind_addr)
+        .await
+        .with_context(|| "Failed to bind on DNS forwarder address (UDP)")?;
+    let tcp_listener = TcpListener::bind(bind_addr)
+        .await
+        .with_context(|| "Failed to listen on DNS forwarder address (TCP)")?;
+
+    let forwarder = Arc::new(DnsForwarder {
+        resolver,
+        cache: cache.then(|| Mutex::new(HashMap::new())),
+    });
+    tokio::select! {
+        result = forwarder.clone().serve_udp(udp_socket) => result,
+        result = forwarder.serve_tcp(tcp_listener) => result,
+    }
+}
+
+struct DnsForwarder {
+    resolver: DnsResolver,
+    /// Cached responses by question, if enabled.
+    cache: Option<Mutex<HashMap<Vec<u8>, CachedResponse>>>,
+}
+
+struct CachedResponse {
+    response: Bytes,
+    cached_at: Instant,
+    expires_at: Instant,
+}
+
+impl DnsForwarder {
+    async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> anyhow::Result<()> {
+        let socket = Arc::new(socket);
+        let mut buffer = [0u8; MAX_PACKET];
+        loop {
+            let (size, client_addr) = socket
+                .recv_from(&mut buffer)
+                .await
+                .with_context(|| "Failed to receive DNS query")?;
+            let query = Bytes::copy_from_slice(&buffer[..size]);
+
+            let forwarder = self.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                match forwarder.answer(&query).await {
+                    Ok(response) => {
+                        if let Err(e) = socket.send_to(&response, client_addr).await {
+                            warn!("Failed to send DNS response to {}: {:?}", client_addr, e);
+                        }
+                    }
+                    Err(e) => warn!("DNS query from {} failed: {:?}", client_addr, e),
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        loop {
+            let (socket, client_addr) = listener
+                .accept()
+                .await
+                .with_context(|| "Failed to accept connection on DNS forwarder")?;
+
+            let forwarder = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = forwarder.handle_tcp_client(socket).await {
+                    warn!("DNS query from {} failed: {:?}", client_addr, e);
+                }
+            });
+        }
+    }
+
+    /// Answers the queries of a TCP client, each prefixed with its length, until the connection is closed.
+    async fn handle_tcp_client(&self, mut socket: TcpStream) -> anyhow::Result<()> {
+        loop {
+            let len = match socket.read_u16().await {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut query = vec![0u8; len];
+            socket.read_exact(&mut query).await?;
+
+            let response = self.answer(&query).await?;
+            socket.write_u16(response.len() as u16).await?;
+            socket.write_all(&response).await?;
+        }
+    }
+
+    /// Answers a query from the cache, or through the tunnel.
+    async fn answer(&self, query: &[u8]) -> anyhow::Result<Bytes> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.resolver.forward(query).await,
+        };
+        let key = match question_key(query) {
+            Some(key) => key,
+            None => return self.resolver.forward(query).await,
+        };
+
+        if let Some(response) = Self::cached(cache, &key, query) {
+            return Ok(response);
+        }
+
+        let response = self.resolver.forward(query).await?;
+        // Only responses with answers are cached, for the lowest TTL among their records
+        let answer_count = response.get(6..8).map(|c| u16::from_be_bytes([c[0], c[1]]));
+        let rcode = response.get(3).map(|flags| flags & 0x0f);
+        if answer_count.unwrap_or_default() > 0 && rcode == Some(0) {
+            if let Some(ttl) = record_ttls(&response)
+                .ok()
+                .and_then(|ttls| ttls.iter().map(|(_, ttl)| *ttl).min())
+            {
+                let now = Instant::now();
+                cache.lock().unwrap().insert(
+                    key,
+                    CachedResponse {
+                        response: response.clone(),
+                        cached_at: now,
+                        expires_at: now + Duration::from_secs(ttl.min(MAX_TTL_SECONDS) as u64),
+                    },
+                );
+            }
+        }
+        Ok(response)
+    }
+
+    /// Returns the cached response to the query, with its ID and the TTLs aged since it was cached.
+    fn cached(
+        cache: &Mutex<HashMap<Vec<u8>, CachedResponse>>,
+        key: &[u8],
+        query: &[u8],
+    ) -> Option<Bytes> {
+        let mut cache = cache.lock().unwrap();
+        let entry = cache.get(key)?;
+        let now = Instant::now();
+        if entry.expires_at <= now {
+            cache.remove(key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.cached_at).as_secs() as u32;
+        let mut response = BytesMut::from(&entry.response[..]);
+        response[..2].copy_from_slice(&query[..2]);
+        for (position, ttl) in record_ttls(&entry.response).ok()? {
+            response[position..position + 4]
+                .copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        Some(response.freeze())
+    }
+}
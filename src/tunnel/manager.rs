@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::config::{PeerConfig, PortForwardConfig};
+use crate::events::{Bus, Event};
+use crate::supervisor::{Supervisor, Task};
+use crate::tunnel::dns::DnsResolver;
+use crate::tunnel::tcp::TcpPortPool;
+use crate::tunnel::udp::UdpPortPool;
+use crate::tunnel::{bind_source, SourceSocket};
+
+/// Keeps track of the running port forwards, so that they can be replaced without restarting the tunnel.
+pub struct PortForwardManager {
+    peers: Vec<PeerConfig>,
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    resolver: DnsResolver,
+    bus: Bus,
+    supervisor: Supervisor,
+    /// The task of each running port forward, with the number of its start.
+    running: HashMap<PortForwardConfig, (u64, Task)>,
+    /// The number of port forwards started so far.
+    started: u64,
+    /// The port forwards added at runtime that failed for good, to be removed by `remove_failed`.
+    failed: mpsc::UnboundedSender<(PortForwardConfig, u64)>,
+}
+
+impl PortForwardManager {
+    /// Creates a manager, and the receiver of the port forwards added at runtime that failed for good.
+    pub(crate) fn new(
+        peers: Vec<PeerConfig>,
+        tcp_port_pool: TcpPortPool,
+        udp_port_pool: UdpPortPool,
+        resolver: DnsResolver,
+        bus: Bus,
+        supervisor: Supervisor,
+    ) -> (Self, mpsc::UnboundedReceiver<(PortForwardConfig, u64)>) {
+        let (failed, failed_rx) = mpsc::unbounded_channel();
+        let manager = Self {
+            peers,
+            tcp_port_pool,
+            udp_port_pool,
+            resolver,
+            bus,
+            supervisor,
+            running: HashMap::new(),
+            started: 0,
+            failed,
+        };
+        (manager, failed_rx)
+    }
+
+    /// Starts a port forward whose sockets are already in the virtual interfaces (i.e. on startup).
+    /// Fails if its source cannot be bound. Once started, the port forward is restarted if it fails, as allowed by
+    /// the restart policy, and its failure is fatal after that.
+    pub async fn start(&mut self, port_forward: PortForwardConfig) -> anyhow::Result<()> {
+        if self.running.contains_key(&port_forward) {
+            return Ok(());
+        }
+        let source = bind_source(&port_forward).await?;
+        self.spawn(port_forward, source, false)
+    }
+
+    /// Spawns the task of a port forward, with its source already bound. The failure of a port forward added at
+    /// runtime only removes it, so that the tunnel and the other connections stay up.
+    fn spawn(
+        &mut self,
+        port_forward: PortForwardConfig,
+        source: Option<SourceSocket>,
+        added_at_runtime: bool,
+    ) -> anyhow::Result<()> {
+        let peer = port_forward
+            .select_peer(&self.peers)
+            .cloned()
+            .with_context(|| format!("Unknown peer for port forward {}", port_forward))?;
+
+        let tcp_port_pool = self.tcp_port_pool.clone();
+        let udp_port_pool = self.udp_port_pool.clone();
+        let resolver = self.resolver.clone();
+        let bus = self.bus.clone();
+        let pf = port_forward.clone();
+        // The source is only used by the first run: it is bound again when the port forward is restarted
+        let source = std::sync::Mutex::new(source);
+        let start = move || {
+            super::port_forward(
+                pf.clone(),
+                peer.clone(),
+                source.lock().unwrap().take(),
+                tcp_port_pool.clone(),
+                udp_port_pool.clone(),
+                resolver.clone(),
+                bus.clone(),
+            )
+        };
+        let name = format!("Port-forward {}", port_forward);
+        self.started += 1;
+        let id = self.started;
+        let task = if added_at_runtime {
+            let failed = self.failed.clone();
+            let pf = port_forward.clone();
+            self.supervisor
+                .spawn_restartable_with(name, start, move |e| {
+                    error!("Port forward {} failed, removing it: {:?}", pf, e);
+                    // The receiver is only dropped on shutdown
+                    let _ = failed.send((pf, id));
+                })
+        } else {
+            self.supervisor.spawn_restartable(name, start)
+        };
+        self.running.insert(port_forward, (id, task));
+        Ok(())
+    }
+
+    /// Returns the running port forwards, in a stable order.
+    pub fn port_forwards(&self) -> Vec<PortForwardConfig> {
+        let mut port_forwards: Vec<PortForwardConfig> = self.running.keys().cloned().collect();
+        port_forwards.sort_by_key(|pf| pf.to_string());
+        port_forwards
+    }
+
+    /// Checks that the peers of port forwards are known, before changing the running ones.
+    pub fn validate(&self, port_forwards: &[PortForwardConfig]) -> anyhow::Result<()> {
+        for port_forward in port_forwards.iter() {
+            port_forward
+                .select_peer(&self.peers)
+                .with_context(|| format!("Unknown peer for port forward {}", port_forward))?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new port forward, after binding its source and registering its sockets in the virtual interfaces.
+    /// Returns false if the port forward is already running, and fails if its source cannot be bound.
+    pub async fn add(&mut self, port_forward: PortForwardConfig) -> anyhow::Result<bool> {
+        if self.running.contains_key(&port_forward) {
+            return Ok(false);
+        }
+        self.validate(std::slice::from_ref(&port_forward))?;
+        let source = bind_source(&port_forward).await?;
+        self.add_bound(port_forward, source)?;
+        Ok(true)
+    }
+
+    /// Starts the port forwards that are not running yet, or none of them if one cannot be started.
+    /// Returns the port forwards that were started.
+    pub async fn add_all(
+        &mut self,
+        port_forwards: &[PortForwardConfig],
+    ) -> anyhow::Result<Vec<PortForwardConfig>> {
+        self.validate(port_forwards)?;
+        let mut added = Vec::new();
+        for port_forward in port_forwards.iter() {
+            match self.add(port_forward.clone()).await {
+                Ok(true) => added.push(port_forward.clone()),
+                Ok(false) => {}
+                Err(e) => {
+                    for port_forward in added.iter() {
+                        self.remove(port_forward).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    /// Starts a new port forward whose source is already bound.
+    fn add_bound(
+        &mut self,
+        port_forward: PortForwardConfig,
+        source: Option<SourceSocket>,
+    ) -> anyhow::Result<()> {
+        self.bus
+            .new_endpoint()
+            .send(Event::PortForwardAdded(port_forward.clone()));
+        self.spawn(port_forward, source, true)
+    }
+
+    /// Stops a port forward from accepting clients, and removes its sockets from the virtual interfaces.
+    /// Its listener is closed on return, so that the source can be bound again.
+    /// The connections already established through it stay up. Returns false if the port forward is not running.
+    pub async fn remove(&mut self, port_forward: &PortForwardConfig) -> bool {
+        let task = match self.running.remove(port_forward) {
+            Some((_, task)) => task,
+            None => return false,
+        };
+        task.stop().await;
+        info!("Stopped port forward {}", port_forward);
+        self.bus
+            .new_endpoint()
+            .send(Event::PortForwardRemoved(port_forward.clone()));
+        true
+    }
+
+    /// Stops all the port forwards from accepting clients, without changing the virtual interfaces (i.e. on shutdown).
+    pub async fn stop(&mut self) {
+        for (_, (_, task)) in self.running.drain() {
+            task.stop().await;
+        }
+    }
+
+    /// Whether the source of a port forward is bound by a running port forward.
+    fn holds_source(&self, port_forward: &PortForwardConfig) -> bool {
+        !port_forward.remote
+            && self.running.keys().any(|pf| {
+                !pf.remote
+                    && pf.protocol == port_forward.protocol
+                    && pf.source == port_forward.source
+            })
+    }
+
+    /// Replaces the running port forwards with the given ones: removed port forwards stop listening, and new
+    /// ones are started. The connections already established through a removed port forward stay up.
+    /// If a new port forward cannot be started, the running ones are left as they were.
+    pub async fn apply(&mut self, port_forwards: Vec<PortForwardConfig>) -> anyhow::Result<()> {
+        // Validate the new port forwards and bind their sources before stopping any of the running ones,
+        // except the sources of the port forwards they replace
+        self.validate(&port_forwards)?;
+        let mut sources = HashMap::new();
+        for port_forward in port_forwards.iter() {
+            if !self.running.contains_key(port_forward) && !self.holds_source(port_forward) {
+                sources.insert(port_forward.clone(), bind_source(port_forward).await?);
+            }
+        }
+
+        let removed: Vec<PortForwardConfig> = self
+            .running
+            .keys()
+            .filter(|pf| !port_forwards.contains(pf))
+            .cloned()
+            .collect();
+        for port_forward in removed.iter() {
+            self.remove(port_forward).await;
+        }
+
+        let mut added = Vec::new();
+        for port_forward in port_forwards {
+            if self.running.contains_key(&port_forward) {
+                continue;
+            }
+            let source = match sources.remove(&port_forward) {
+                Some(source) => source,
+                None => match bind_source(&port_forward).await {
+                    Ok(source) => source,
+                    Err(e) => {
+                        self.restore(added, removed).await;
+                        return Err(e);
+                    }
+                },
+            };
+            self.add_bound(port_forward.clone(), source)?;
+            added.push(port_forward);
+        }
+        Ok(())
+    }
+
+    /// Restores the port forwards replaced by `apply`, when a new one could not be started.
+    async fn restore(&mut self, added: Vec<PortForwardConfig>, removed: Vec<PortForwardConfig>) {
+        for port_forward in added.iter() {
+            self.remove(port_forward).await;
+        }
+        for port_forward in removed {
+            if let Err(e) = self.add(port_forward.clone()).await {
+                error!("Failed to restore port forward {}: {:?}", port_forward, e);
+            }
+        }
+    }
+
+    /// Removes a port forward that failed for good, unless it was started again since.
+    async fn remove_failed_port_forward(&mut self, port_forward: &PortForwardConfig, id: u64) {
+        if matches!(self.running.get(port_forward), Some((running_id, _)) if *running_id == id) {
+            self.remove(port_forward).await;
+        }
+    }
+}
+
+/// Removes the port forwards added at runtime once they failed for good, as received from the manager.
+pub(crate) async fn remove_failed(
+    manager: Arc<Mutex<PortForwardManager>>,
+    mut failed: mpsc::UnboundedReceiver<(PortForwardConfig, u64)>,
+) -> anyhow::Result<()> {
+    while let Some((port_forward, id)) = failed.recv().await {
+        manager
+            .lock()
+            .await
+            .remove_failed_port_forward(&port_forward, id)
+            .await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::{PortProtocol, RestartPolicy};
+
+    fn port_forward(
+        source: SocketAddr,
+        destination: &str,
+        peer: Option<&str>,
+    ) -> PortForwardConfig {
+        PortForwardConfig {
+            name: None,
+            source,
+            destination: SocketAddr::from_str(destination).unwrap(),
+            destination_host: None,
+            protocol: PortProtocol::Tcp,
+            remote: false,
+            peer: peer.map(Arc::from),
+        }
+    }
+
+    /// Returns a local address that is free to listen on.
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+    }
+
+    fn sorted(mut port_forwards: Vec<PortForwardConfig>) -> Vec<PortForwardConfig> {
+        port_forwards.sort_by_key(|pf| pf.to_string());
+        port_forwards
+    }
+
+    /// Waits until a port forward listens on the address.
+    async fn wait_listening(addr: SocketAddr) {
+        while TcpListener::bind(addr).is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Tests that applying port forwards starts the new ones, stops the removed ones, replaces the changed ones
+    /// on the same source, and leaves the others running, or changes nothing if one cannot be started.
+    #[tokio::test]
+    async fn test_apply() {
+        let peers = vec![PeerConfig::test("192.168.4.3/24", None)];
+        let bus = Bus::new();
+        let udp_port_pool = UdpPortPool::new();
+        let resolver = DnsResolver::new(None, peers.clone(), udp_port_pool.clone(), bus.clone());
+        let (supervisor, mut failures) = Supervisor::new(RestartPolicy {
+            max_restarts: 0,
+            backoff: Duration::from_millis(1),
+        });
+        let (mut manager, _) = PortForwardManager::new(
+            peers,
+            TcpPortPool::new(),
+            udp_port_pool,
+            resolver,
+            bus.clone(),
+            supervisor,
+        );
+        let mut endpoint = bus.new_endpoint();
+
+        let (addr_a, addr_b) = (free_addr(), free_addr());
+        let pf_a = port_forward(addr_a, "192.168.4.2:80", None);
+        let pf_b = port_forward(addr_b, "192.168.4.2:81", None);
+        manager
+            .apply(vec![pf_a.clone(), pf_b.clone()])
+            .await
+            .expect("Failed to apply");
+        assert!(matches!(endpoint.recv().await, Event::PortForwardAdded(pf) if pf == pf_a));
+        assert!(matches!(endpoint.recv().await, Event::PortForwardAdded(pf) if pf == pf_b));
+        wait_listening(addr_a).await;
+        wait_listening(addr_b).await;
+
+        // The changed port forward listens on the same source, once the previous one stopped
+        let pf_a_changed = port_forward(addr_a, "192.168.4.2:8080", None);
+        manager
+            .apply(vec![pf_b.clone(), pf_a_changed.clone()])
+            .await
+            .expect("Failed to apply");
+        assert!(matches!(endpoint.recv().await, Event::PortForwardRemoved(pf) if pf == pf_a));
+        assert!(matches!(endpoint.recv().await, Event::PortForwardAdded(pf) if pf == pf_a_changed));
+        assert_eq!(
+            manager.port_forwards(),
+            sorted(vec![pf_a_changed.clone(), pf_b.clone()])
+        );
+        wait_listening(addr_a).await;
+        assert!(failures.try_recv().is_err(), "No port forward should fail");
+
+        // Nothing changes if a port forward is invalid
+        let pf_unknown = port_forward(free_addr(), "192.168.4.2:82", Some("home"));
+        assert!(manager.apply(vec![pf_b.clone(), pf_unknown]).await.is_err());
+        assert_eq!(
+            manager.port_forwards(),
+            sorted(vec![pf_a_changed.clone(), pf_b.clone()])
+        );
+
+        // Nothing changes if the source of a new port forward is in use
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pf_taken = port_forward(taken.local_addr().unwrap(), "192.168.4.2:83", None);
+        assert!(manager
+            .apply(vec![pf_b.clone(), pf_taken.clone()])
+            .await
+            .is_err());
+        assert!(manager.add(pf_taken.clone()).await.is_err());
+        assert_eq!(
+            manager.port_forwards(),
+            sorted(vec![pf_a_changed.clone(), pf_b.clone()])
+        );
+
+        // A port forward is not added alone if another one cannot be started
+        let addr_c = free_addr();
+        let pf_c = port_forward(addr_c, "192.168.4.2:84", None);
+        assert!(manager
+            .add_all(&[pf_b.clone(), pf_c.clone(), pf_taken.clone()])
+            .await
+            .is_err());
+        assert!(matches!(endpoint.recv().await, Event::PortForwardAdded(pf) if pf == pf_c));
+        assert!(matches!(endpoint.recv().await, Event::PortForwardRemoved(pf) if pf == pf_c));
+        assert!(TcpListener::bind(addr_c).is_ok());
+        assert_eq!(
+            manager
+                .add_all(&[pf_b.clone(), pf_c.clone()])
+                .await
+                .unwrap(),
+            vec![pf_c.clone()]
+        );
+        assert!(matches!(endpoint.recv().await, Event::PortForwardAdded(pf) if pf == pf_c));
+        assert!(manager.remove(&pf_c).await);
+        assert!(matches!(endpoint.recv().await, Event::PortForwardRemoved(pf) if pf == pf_c));
+
+        // The source of a removed port forward is free again
+        manager
+            .apply(vec![pf_b.clone()])
+            .await
+            .expect("Failed to apply");
+        assert!(
+            matches!(endpoint.recv().await, Event::PortForwardRemoved(pf) if pf == pf_a_changed)
+        );
+        assert!(TcpListener::bind(addr_a).is_ok());
+        assert_eq!(manager.port_forwards(), vec![pf_b]);
+
+        manager.stop().await;
+    }
+}
@@ -1,34 +1,116 @@
-use std::net::IpAddr;
-use std::sync::Arc;
+use std::net::SocketAddr;
 
-use crate::config::{PortForwardConfig, PortProtocol};
+use anyhow::Context;
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::{route_peer, PeerConfig, PortForwardConfig, PortProtocol};
 use crate::events::Bus;
+use crate::tunnel::dns::DnsResolver;
 use crate::tunnel::tcp::TcpPortPool;
 use crate::tunnel::udp::UdpPortPool;
-use crate::wg::WireGuardTunnel;
 
+pub mod dns;
+pub mod dns_forwarder;
+pub mod http;
+pub mod manager;
+pub mod socks;
 pub mod tcp;
 pub mod udp;
 
-pub async fn port_forward(
+/// The local socket of a port forward, bound on its source.
+pub(crate) enum SourceSocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+/// Binds the source of a port forward, so that a source already in use is reported before the port forward starts.
+/// Remote port forwards listen in the virtual interfaces, so they have no local source.
+pub(crate) async fn bind_source(
+    port_forward: &PortForwardConfig,
+) -> anyhow::Result<Option<SourceSocket>> {
+    if port_forward.remote {
+        return Ok(None);
+    }
+    let socket = match port_forward.protocol {
+        PortProtocol::Tcp => TcpListener::bind(port_forward.source)
+            .await
+            .map(SourceSocket::Tcp),
+        PortProtocol::Udp => UdpSocket::bind(port_forward.source)
+            .await
+            .map(SourceSocket::Udp),
+    };
+    socket
+        .map(Some)
+        .with_context(|| format!("Failed to bind port forward {}", port_forward))
+}
+
+/// Runs a port forward. Its source is bound unless it is given already bound.
+pub(crate) async fn port_forward(
     port_forward: PortForwardConfig,
-    source_peer_ip: IpAddr,
+    peer: PeerConfig,
+    source: Option<SourceSocket>,
     tcp_port_pool: TcpPortPool,
     udp_port_pool: UdpPortPool,
-    wg: Arc<WireGuardTunnel>,
+    resolver: DnsResolver,
     bus: Bus,
 ) -> anyhow::Result<()> {
     info!(
-        "Tunneling {} [{}]->[{}] (via [{}] as peer {})",
+        "{} {} [{}]->[{}] (via [{}] as peer {})",
+        if port_forward.remote {
+            "Remote tunneling"
+        } else {
+            "Tunneling"
+        },
         port_forward.protocol,
         port_forward.source,
-        port_forward.destination,
-        &wg.endpoint,
-        source_peer_ip
+        port_forward.display_destination(),
+        peer.display_endpoints(),
+        peer.display_source_peer_ips()
     );
 
-    match port_forward.protocol {
-        PortProtocol::Tcp => tcp::tcp_proxy_server(port_forward, tcp_port_pool, bus).await,
-        PortProtocol::Udp => udp::udp_proxy_server(port_forward, udp_port_pool, bus).await,
+    let source = match source {
+        Some(source) => Some(source),
+        None => bind_source(&port_forward).await?,
+    };
+    match (source, port_forward.protocol) {
+        (Some(SourceSocket::Tcp(listener)), _) => {
+            tcp::tcp_proxy_server(listener, port_forward, tcp_port_pool, resolver, bus).await
+        }
+        (Some(SourceSocket::Udp(socket)), _) => {
+            udp::udp_proxy_server(socket, port_forward, udp_port_pool, resolver, bus).await
+        }
+        (None, PortProtocol::Tcp) => {
+            tcp::tcp_remote_proxy_server(port_forward, tcp_port_pool, bus).await
+        }
+        (None, PortProtocol::Udp) => {
+            udp::udp_remote_proxy_server(port_forward, udp_port_pool, bus).await
+        }
     }
 }
+
+/// Whether a destination chosen at runtime (e.g. by a SOCKS5 client) can be connected to: not port 0, nor an
+/// unspecified IP, which the virtual interfaces cannot address.
+pub(crate) fn is_valid_destination(destination: SocketAddr) -> bool {
+    destination.port() != 0 && !destination.ip().is_unspecified()
+}
+
+/// Creates the port forward for a connection to a destination chosen at runtime (e.g. by a SOCKS5 client),
+/// through the peer that routes the destination.
+pub(crate) fn dynamic_port_forward(
+    peers: &[PeerConfig],
+    source: SocketAddr,
+    destination: SocketAddr,
+    protocol: PortProtocol,
+) -> anyhow::Result<PortForwardConfig> {
+    let peer = route_peer(peers, destination.ip())
+        .with_context(|| format!("No peer allows the destination {}", destination))?;
+    Ok(PortForwardConfig {
+        name: None,
+        source,
+        destination,
+        destination_host: None,
+        protocol,
+        remote: false,
+        peer: Some(peer.name.clone()),
+    })
+}
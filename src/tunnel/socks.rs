@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+use crate::buffer::BufferPool;
+use crate::config::{PeerConfig, PortProtocol};
+use crate::events::{Bus, Event, LocalMessage, RemoteMessage};
+use crate::tunnel::dns::DnsResolver;
+use crate::tunnel::tcp::{proxy_virtual_connection, TcpPortPool, VirtualConnection};
+use crate::tunnel::udp::UdpPortPool;
+use crate::tunnel::{dynamic_port_forward, is_valid_destination};
+use crate::virtual_iface::VirtualPort;
+
+const MAX_PACKET: usize = 65536;
+/// How many datagrams can be queued for a UDP association before they are dropped.
+const MAX_ASSOCIATION_QUEUE: usize = 100;
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+const COMMAND_CONNECT: u8 = 0x01;
+const COMMAND_UDP_ASSOCIATE: u8 = 0x03;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Starts the SOCKS5 proxy server (RFC 1928), which connects to destinations chosen by the clients.
+/// Each connection or UDP destination gets a virtual port, like the connections of a port forward.
+pub async fn socks5_proxy_server(
+    bind_addr: SocketAddr,
+    peers: Vec<PeerConfig>,
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    resolver: DnsResolver,
+    bus: Bus,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind_addr)
+        .await
+        .with_context(|| "Failed to listen on SOCKS5 proxy server")?;
+    let peers: Arc<[PeerConfig]> = peers.into();
+
+    loop {
+        let (socket, client_addr) = listener
+            .accept()
+            .await
+            .with_context(|| "Failed to accept connection on SOCKS5 proxy server")?;
+
+        let server = Socks5Server {
+            peers: peers.clone(),
+            tcp_port_pool: tcp_port_pool.clone(),
+            udp_port_pool: udp_port_pool.clone(),
+            resolver: resolver.clone(),
+            bus: bus.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = server.handle_connection(socket, client_addr).await {
+                warn!("SOCKS5 request from {} failed: {:?}", client_addr, e);
+            }
+        });
+    }
+}
+
+/// The state shared by the connections of the SOCKS5 proxy server.
+struct Socks5Server {
+    peers: Arc<[PeerConfig]>,
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    resolver: DnsResolver,
+    bus: Bus,
+}
+
+impl Socks5Server {
+    async fn handle_connection(
+        &self,
+        mut socket: TcpStream,
+        client_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        // Method negotiation
+        let version = socket.read_u8().await?;
+        if version != SOCKS_VERSION {
+            return Err(anyhow::anyhow!("Unsupported SOCKS version {}", version));
+        }
+        let mut methods = vec![0u8; socket.read_u8().await? as usize];
+        socket.read_exact(&mut methods).await?;
+        if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+            socket
+                .write_all(&[SOCKS_VERSION, METHOD_NO_ACCEPTABLE])
+                .await?;
+            return Err(anyhow::anyhow!(
+                "Client does not support connecting without authentication"
+            ));
+        }
+        socket
+            .write_all(&[SOCKS_VERSION, METHOD_NO_AUTHENTICATION])
+            .await?;
+
+        // Request
+        let mut header = [0u8; 3];
+        socket.read_exact(&mut header).await?;
+        if header[0] != SOCKS_VERSION {
+            return Err(anyhow::anyhow!("Unsupported SOCKS version {}", header[0]));
+        }
+        let address = match SocksAddr::read(&mut socket).await {
+            Ok(address) => address,
+            Err(e) => {
+                send_reply(&mut socket, REPLY_ADDRESS_NOT_SUPPORTED, None).await?;
+                return Err(e);
+            }
+        };
+
+        match header[1] {
+            COMMAND_CONNECT if !address.is_valid_destination() => {
+                send_reply(&mut socket, REPLY_ADDRESS_NOT_SUPPORTED, None).await?;
+                Err(anyhow::anyhow!("Invalid destination {}", address))
+            }
+            COMMAND_CONNECT => self.connect(socket, client_addr, address).await,
+            COMMAND_UDP_ASSOCIATE => self.udp_associate(socket, client_addr).await,
+            command => {
+                send_reply(&mut socket, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+                Err(anyhow::anyhow!("Unsupported SOCKS command {}", command))
+            }
+        }
+    }
+
+    /// Handles the CONNECT command, by proxying the connection through a virtual client socket.
+    async fn connect(
+        &self,
+        mut socket: TcpStream,
+        client_addr: SocketAddr,
+        address: SocksAddr,
+    ) -> anyhow::Result<()> {
+        let destination = match address.resolve(&self.resolver).await {
+            Ok(destination) => destination,
+            Err(e) => {
+                send_reply(&mut socket, REPLY_HOST_UNREACHABLE, None).await?;
+                return Err(e);
+            }
+        };
+        if !is_valid_destination(destination) {
+            send_reply(&mut socket, REPLY_ADDRESS_NOT_SUPPORTED, None).await?;
+            return Err(anyhow::anyhow!(
+                "{} resolved to an invalid destination {}",
+                address,
+                destination
+            ));
+        }
+        let port_forward = match dynamic_port_forward(
+            &self.peers,
+            socket.local_addr()?,
+            destination,
+            PortProtocol::Tcp,
+        ) {
+            Ok(port_forward) => port_forward,
+            Err(e) => {
+                send_reply(&mut socket, REPLY_NETWORK_UNREACHABLE, None).await?;
+                return Err(e);
+            }
+        };
+        let virtual_port = match self.tcp_port_pool.next(client_addr).await {
+            Ok(port) => port,
+            Err(e) => {
+                send_reply(&mut socket, REPLY_GENERAL_FAILURE, None).await?;
+                return Err(e).with_context(|| "Failed to assign virtual port number");
+            }
+        };
+
+        info!(
+            "[{}] SOCKS5 connection from {} to {} ({})",
+            virtual_port, client_addr, address, destination
+        );
+        // The client is told whether the virtual connection could be established
+        let mut connection =
+            match VirtualConnection::open(port_forward, virtual_port, &self.bus).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.tcp_port_pool.release(virtual_port).await;
+                    send_reply(&mut socket, REPLY_GENERAL_FAILURE, None).await?;
+                    return Err(e);
+                }
+            };
+        let result = match connection.wait_established().await {
+            Ok(()) => match socket.local_addr() {
+                Ok(bound_addr) => send_reply(&mut socket, REPLY_SUCCEEDED, Some(bound_addr)).await,
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => {
+                let _ = send_reply(&mut socket, REPLY_HOST_UNREACHABLE, None).await;
+                Err(e)
+            }
+        };
+        if let Err(e) = result {
+            connection.close().await;
+            self.tcp_port_pool.release(virtual_port).await;
+            return Err(e).with_context(|| format!("Failed to connect to {}", destination));
+        }
+        proxy_virtual_connection(socket, connection, None, self.tcp_port_pool.clone()).await;
+        Ok(())
+    }
+
+    /// Handles the UDP ASSOCIATE command, by relaying datagrams between a local UDP socket and
+    /// the virtual interface. Each destination gets its own virtual port, so that replies can be
+    /// attributed to it. The association ends when the control connection is closed.
+    async fn udp_associate(
+        &self,
+        mut socket: TcpStream,
+        client_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let interface = match self.bus.interface(PortProtocol::Udp) {
+            Ok(interface) => interface,
+            Err(e) => {
+                send_reply(&mut socket, REPLY_GENERAL_FAILURE, None).await?;
+                return Err(e);
+            }
+        };
+        let relay = match UdpSocket::bind((socket.local_addr()?.ip(), 0)).await {
+            Ok(relay) => relay,
+            Err(e) => {
+                send_reply(&mut socket, REPLY_GENERAL_FAILURE, None).await?;
+                return Err(e).with_context(|| "Failed to bind UDP relay socket");
+            }
+        };
+        let relay_addr = relay.local_addr()?;
+        send_reply(&mut socket, REPLY_SUCCEEDED, Some(relay_addr)).await?;
+        info!(
+            "SOCKS5 UDP association from {} relayed on {}",
+            client_addr, relay_addr
+        );
+
+        let mut endpoint = self.bus.new_endpoint();
+
+        // The datagrams received by the virtual interface for the virtual ports of this association
+        let (remote_sender, mut remote_data) = mpsc::channel(MAX_ASSOCIATION_QUEUE);
+
+        // The client address and destination of each virtual port of this association
+        let mut flows: HashMap<VirtualPort, (SocketAddr, SocketAddr)> = HashMap::new();
+        // Destinations resolved for this association, by domain name and port
+        let mut resolved: HashMap<(String, u16), SocketAddr> = HashMap::new();
+
+        let mut control_buffer = [0u8; 1];
+        let mut buffer = [0u8; MAX_PACKET];
+        let mut buffers = BufferPool::default();
+        loop {
+            tokio::select! {
+                read = socket.read(&mut control_buffer) => {
+                    match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => continue,
+                    }
+                }
+                recv_result = relay.recv_from(&mut buffer) => {
+                    let (size, from) = recv_result.with_context(|| "Failed to read from UDP relay socket")?;
+                    // Only the client of the control connection may use the relay
+                    if from.ip() != client_addr.ip() {
+                        debug!("Ignoring SOCKS5 UDP datagram from unknown client {}", from);
+                        continue;
+                    }
+                    let (address, data) = match parse_udp_request(&buffer[..size]) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            debug!("Ignoring invalid SOCKS5 UDP datagram from {}: {:?}", from, e);
+                            continue;
+                        }
+                    };
+                    if !address.is_valid_destination() {
+                        debug!("Ignoring SOCKS5 UDP datagram to invalid destination {}", address);
+                        continue;
+                    }
+                    let destination = match &address {
+                        SocksAddr::Ip(addr) => *addr,
+                        SocksAddr::Domain(domain, port) => match resolved.get(&(domain.clone(), *port)) {
+                            Some(addr) => *addr,
+                            None => match address.resolve(&self.resolver).await {
+                                Ok(addr) => {
+                                    resolved.insert((domain.clone(), *port), addr);
+                                    addr
+                                }
+                                Err(e) => {
+                                    warn!("Dropping SOCKS5 UDP datagram to {}: {:?}", address, e);
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    if !is_valid_destination(destination) {
+                        warn!("Dropping SOCKS5 UDP datagram to {}: invalid destination {}", address, destination);
+                        continue;
+                    }
+                    let port_forward = match dynamic_port_forward(&self.peers, relay_addr, destination, PortProtocol::Udp) {
+                        Ok(port_forward) => port_forward,
+                        Err(e) => {
+                            warn!("Dropping SOCKS5 UDP datagram to {}: {:?}", address, e);
+                            continue;
+                        }
+                    };
+                    let virtual_port = match self.udp_port_pool.next_for_destination(from, destination).await {
+                        Ok(port) => port,
+                        Err(e) => {
+                            error!(
+                                "Failed to assign virtual port number for SOCKS5 UDP datagram from [{}]: {:?}",
+                                from, e
+                            );
+                            continue;
+                        }
+                    };
+                    debug!("[{}] Received SOCKS5 datagram of {} bytes for {}", virtual_port, data.len(), destination);
+                    self.udp_port_pool.update_last_transmit(virtual_port).await;
+                    if flows.insert(virtual_port, (from, destination)).is_none() {
+                        interface
+                            .send(LocalMessage::Connect(port_forward, virtual_port, remote_sender.clone(), None))
+                            .await
+                            .with_context(|| "The UDP virtual interface stopped")?;
+                    }
+                    interface
+                        .send(LocalMessage::Data(virtual_port, buffers.copy_from_slice(data)))
+                        .await
+                        .with_context(|| "The UDP virtual interface stopped")?;
+                }
+                Some((virtual_port, message)) = remote_data.recv() => {
+                    let data = match message {
+                        RemoteMessage::Data(data) => data,
+                        RemoteMessage::Closed => {
+                            // The flow was closed (e.g. by the control API), and its virtual port released
+                            flows.remove(&virtual_port);
+                            continue;
+                        }
+                        RemoteMessage::Established => continue,
+                    };
+                    if let Some((client, destination)) = flows.get(&virtual_port) {
+                        let mut datagram = vec![0u8, 0, 0];
+                        SocksAddr::Ip(*destination).write_to(&mut datagram);
+                        datagram.extend_from_slice(&data);
+                        if let Err(e) = relay.send_to(&datagram, client).await {
+                            error!("[{}] Failed to send {} bytes to SOCKS5 client: {:?}", virtual_port, data.len(), e);
+                        }
+                        self.udp_port_pool.update_last_transmit(virtual_port).await;
+                    }
+                }
+                event = endpoint.recv() => {
+                    if let Event::Shutdown = event {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // The flows of the association end with it, and their virtual ports can be reused
+        for virtual_port in flows.into_keys() {
+            let _ = interface.send(LocalMessage::Close(virtual_port)).await;
+            self.udp_port_pool.release(virtual_port).await;
+        }
+        info!("SOCKS5 UDP association from {} closed", client_addr);
+        Ok(())
+    }
+}
+
+/// Sends the reply to a SOCKS5 request. The bound address defaults to `0.0.0.0:0`.
+async fn send_reply(
+    socket: &mut TcpStream,
+    reply: u8,
+    bound_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut buffer = vec![SOCKS_VERSION, reply, 0x00];
+    SocksAddr::Ip(bound_addr.unwrap_or_else(|| ([0, 0, 0, 0], 0).into())).write_to(&mut buffer);
+    socket
+        .write_all(&buffer)
+        .await
+        .with_context(|| "Failed to send SOCKS5 reply")
+}
+
+/// Parses the header of a datagram sent by the client to the UDP relay,
+/// returning the destination and the data to send.
+fn parse_udp_request(datagram: &[u8]) -> anyhow::Result<(SocksAddr, &[u8])> {
+    if datagram.len() < 4 {
+        return Err(anyhow::anyhow!("Datagram is too short"));
+    }
+    // Fragmentation is not supported
+    if datagram[2] != 0 {
+        return Err(anyhow::anyhow!("Fragmented datagrams are not supported"));
+    }
+    let (address, size) = SocksAddr::parse(&datagram[3..])?;
+    Ok((address, &datagram[3 + size..]))
+}
+
+/// An address in a SOCKS5 request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum SocksAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl SocksAddr {
+    /// Reads the address type, address and port from a stream.
+    async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Self> {
+        let address_type = reader.read_u8().await?;
+        let mut buffer = vec![address_type];
+        match address_type {
+            ADDRESS_IPV4 => buffer.resize(1 + 4 + 2, 0),
+            ADDRESS_IPV6 => buffer.resize(1 + 16 + 2, 0),
+            ADDRESS_DOMAIN => {
+                let len = reader.read_u8().await?;
+                buffer.push(len);
+                buffer.resize(2 + len as usize + 2, 0);
+            }
+            _ => return Err(anyhow::anyhow!("Unsupported address type {}", address_type)),
+        }
+        let start = if address_type == ADDRESS_DOMAIN { 2 } else { 1 };
+        reader.read_exact(&mut buffer[start..]).await?;
+        Self::parse(&buffer).map(|(address, _)| address)
+    }
+
+    /// Parses the address type, address and port, returning the address and the number of bytes read.
+    fn parse(buffer: &[u8]) -> anyhow::Result<(Self, usize)> {
+        let port = |offset: usize| -> anyhow::Result<u16> {
+            buffer
+                .get(offset..offset + 2)
+                .map(|port| u16::from_be_bytes([port[0], port[1]]))
+                .with_context(|| "Address is too short")
+        };
+        match buffer.first() {
+            Some(&ADDRESS_IPV4) => {
+                let ip: [u8; 4] = buffer
+                    .get(1..5)
+                    .and_then(|ip| ip.try_into().ok())
+                    .with_context(|| "Address is too short")?;
+                Ok((Self::Ip((Ipv4Addr::from(ip), port(5)?).into()), 7))
+            }
+            Some(&ADDRESS_IPV6) => {
+                let ip: [u8; 16] = buffer
+                    .get(1..17)
+                    .and_then(|ip| ip.try_into().ok())
+                    .with_context(|| "Address is too short")?;
+                Ok((Self::Ip((Ipv6Addr::from(ip), port(17)?).into()), 19))
+            }
+            Some(&ADDRESS_DOMAIN) => {
+                let len = *buffer.get(1).with_context(|| "Address is too short")? as usize;
+                let domain = buffer
+                    .get(2..2 + len)
+                    .with_context(|| "Address is too short")?;
+                let domain = std::str::from_utf8(domain)
+                    .with_context(|| "Invalid domain name")?
+                    .to_string();
+                Ok((Self::Domain(domain, port(2 + len)?), 2 + len + 2))
+            }
+            Some(address_type) => Err(anyhow::anyhow!("Unsupported address type {}", address_type)),
+            None => Err(anyhow::anyhow!("Address is too short")),
+        }
+    }
+
+    /// Writes the address type, address and port.
+    fn write_to(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Self::Ip(SocketAddr::V4(addr)) => {
+                buffer.push(ADDRESS_IPV4);
+                buffer.extend_from_slice(&addr.ip().octets());
+            }
+            Self::Ip(SocketAddr::V6(addr)) => {
+                buffer.push(ADDRESS_IPV6);
+                buffer.extend_from_slice(&addr.ip().octets());
+            }
+            Self::Domain(domain, _) => {
+                buffer.push(ADDRESS_DOMAIN);
+                buffer.push(domain.len() as u8);
+                buffer.extend_from_slice(domain.as_bytes());
+            }
+        }
+        let port = match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Domain(_, port) => *port,
+        };
+        buffer.extend_from_slice(&port.to_be_bytes());
+    }
+
+    /// Whether the address can be a destination: not port 0, nor an unspecified IP.
+    /// A domain name may still resolve to an invalid destination.
+    fn is_valid_destination(&self) -> bool {
+        match self {
+            Self::Ip(addr) => is_valid_destination(*addr),
+            Self::Domain(_, port) => *port != 0,
+        }
+    }
+
+    /// Resolves the address, through the tunnel if a DNS server is configured.
+    async fn resolve(&self, resolver: &DnsResolver) -> anyhow::Result<SocketAddr> {
+        match self {
+            Self::Ip(addr) => Ok(*addr),
+            Self::Domain(domain, port) => {
+                Ok(SocketAddr::new(resolver.resolve(domain).await?, *port))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SocksAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// Tests the parsing of the addresses in SOCKS5 requests.
+    #[test]
+    fn test_parse_socks_addr() {
+        for address in [
+            SocksAddr::Ip(SocketAddr::from_str("192.168.4.2:8080").unwrap()),
+            SocksAddr::Ip(SocketAddr::from_str("[fd00::2]:53").unwrap()),
+            SocksAddr::Domain("peer.intranet".into(), 443),
+        ] {
+            let mut buffer = vec![];
+            address.write_to(&mut buffer);
+            assert_eq!(
+                SocksAddr::parse(&buffer).expect("Failed to parse"),
+                (address, buffer.len())
+            );
+        }
+        assert!(SocksAddr::parse(&[ADDRESS_IPV4, 192, 168]).is_err());
+        assert!(SocksAddr::parse(&[0x02, 0, 0]).is_err());
+    }
+
+    /// Tests that port 0 and unspecified IPs are not valid destinations.
+    #[test]
+    fn test_socks_addr_is_valid_destination() {
+        let ip = |addr: &str| SocksAddr::Ip(SocketAddr::from_str(addr).unwrap());
+        assert!(ip("192.168.4.2:8080").is_valid_destination());
+        assert!(!ip("192.168.4.2:0").is_valid_destination());
+        assert!(!ip("0.0.0.0:8080").is_valid_destination());
+        assert!(!ip("[::]:8080").is_valid_destination());
+        assert!(SocksAddr::Domain("peer.intranet".into(), 443).is_valid_destination());
+        assert!(!SocksAddr::Domain("peer.intranet".into(), 0).is_valid_destination());
+    }
+
+    /// Tests the parsing of the header of UDP relay datagrams.
+    #[test]
+    fn test_parse_udp_request() {
+        let datagram = [0, 0, 0, ADDRESS_IPV4, 192, 168, 4, 2, 0, 53, 0xab, 0xcd];
+        let (address, data) = parse_udp_request(&datagram).expect("Failed to parse");
+        assert_eq!(
+            address,
+            SocksAddr::Ip(SocketAddr::from_str("192.168.4.2:53").unwrap())
+        );
+        assert_eq!(data, &[0xab, 0xcd]);
+
+        let fragment = [0, 0, 1, ADDRESS_IPV4, 192, 168, 4, 2, 0, 53, 0xab, 0xcd];
+        assert!(parse_udp_request(&fragment).is_err());
+    }
+}
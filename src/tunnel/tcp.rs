@@ -1,17 +1,19 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::ops::Range;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
-use bytes::BytesMut;
+use bytes::{Buf, Bytes, BytesMut};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
 
 use crate::config::{PortForwardConfig, PortProtocol};
-use crate::events::{Bus, Event};
+use crate::events::{Bus, BusEndpoint, Event, LocalMessage, RemoteMessage, SendCredit};
+use crate::tunnel::dns::DnsResolver;
 use crate::virtual_iface::VirtualPort;
 
 const MAX_PACKET: usize = 65536;
@@ -19,16 +21,30 @@ const MIN_PORT: u16 = 1000;
 const MAX_PORT: u16 = 60999;
 const PORT_RANGE: Range<u16> = MIN_PORT..MAX_PORT;
 
-/// Starts the server that listens on TCP connections.
+/// How many chunks of data received by the virtual interface can be queued for a connection.
+const MAX_QUEUED_DATA: usize = 16;
+
+/// The minimum room in the buffer to read a client in, before getting a new one.
+const MIN_READ: usize = 4096;
+
+/// How many bytes read from a client can be queued in the virtual interface, before they are in the virtual socket.
+const MAX_UNSENT_DATA: usize = MAX_PACKET;
+
+/// How long to wait for a virtual connection to be established, when the client needs to know (e.g. SOCKS5),
+/// or for the real connection of a remote port forward.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How many connections accepted by a remote port forward can wait for its server.
+const MAX_PENDING_CONNECTIONS: usize = 16;
+
+/// Starts the server that accepts TCP connections on the listener bound on the source of the port forward.
 pub async fn tcp_proxy_server(
+    listener: TcpListener,
     port_forward: PortForwardConfig,
     port_pool: TcpPortPool,
+    resolver: DnsResolver,
     bus: Bus,
 ) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(port_forward.source)
-        .await
-        .with_context(|| "Failed to listen on TCP proxy server")?;
-
     loop {
         let port_pool = port_pool.clone();
         let (socket, peer_addr) = listener
@@ -39,7 +55,7 @@ pub async fn tcp_proxy_server(
         // Assign a 'virtual port': this is a unique port number used to route IP packets
         // received from the WireGuard tunnel. It is the port number that the virtual client will
         // listen on.
-        let virtual_port = match port_pool.next().await {
+        let virtual_port = match port_pool.next(peer_addr).await {
             Ok(port) => port,
             Err(e) => {
                 error!(
@@ -53,111 +69,356 @@ pub async fn tcp_proxy_server(
         info!("[{}] Incoming connection from {}", virtual_port, peer_addr);
 
         let bus = bus.clone();
+        let port_forward = port_forward.clone();
+        let resolver = resolver.clone();
         tokio::spawn(async move {
-            let port_pool = port_pool.clone();
-            let result = handle_tcp_proxy_connection(socket, virtual_port, port_forward, bus).await;
+            // The destination is resolved for each connection, if it is a host name
+            let port_forward = match resolver.resolve_port_forward(&port_forward).await {
+                Ok(port_forward) => port_forward,
+                Err(e) => {
+                    error!("[{}] Failed to resolve destination: {:?}", virtual_port, e);
+                    port_pool.release(virtual_port).await;
+                    return;
+                }
+            };
+            proxy_tcp_connection(socket, virtual_port, port_forward, None, port_pool, bus).await
+        });
+    }
+}
+
+/// Proxies a connection accepted by a local server until it is closed, then releases its virtual port.
+pub(crate) async fn proxy_tcp_connection(
+    socket: TcpStream,
+    virtual_port: VirtualPort,
+    port_forward: PortForwardConfig,
+    initial_data: Option<Bytes>,
+    port_pool: TcpPortPool,
+    bus: Bus,
+) {
+    match VirtualConnection::open(port_forward, virtual_port, &bus).await {
+        Ok(connection) => {
+            proxy_virtual_connection(socket, connection, initial_data, port_pool).await
+        }
+        Err(e) => {
+            error!("[{}] Failed to open connection: {:?}", virtual_port, e);
+            port_pool.release(virtual_port).await;
+        }
+    }
+}
+
+/// Proxies a connection accepted by a local server through its virtual connection until it is closed,
+/// then releases its virtual port.
+pub(crate) async fn proxy_virtual_connection(
+    socket: TcpStream,
+    connection: VirtualConnection,
+    initial_data: Option<Bytes>,
+    port_pool: TcpPortPool,
+) {
+    let virtual_port = connection.virtual_port;
+    let result = connection.proxy(socket, initial_data).await;
+
+    if let Err(e) = result {
+        error!(
+            "[{}] Connection dropped un-gracefully: {:?}",
+            virtual_port, e
+        );
+    } else {
+        info!("[{}] Connection closed by client", virtual_port);
+    }
+
+    tokio::time::sleep(Duration::from_millis(100)).await; // Make sure the other tasks have time to process the event
+    port_pool.release(virtual_port).await;
+}
+
+/// Starts the server that connects to the real destination for each connection accepted
+/// by a remote port forward in the virtual interface.
+pub async fn tcp_remote_proxy_server(
+    port_forward: PortForwardConfig,
+    port_pool: TcpPortPool,
+    bus: Bus,
+) -> anyhow::Result<()> {
+    let interface = bus.interface(PortProtocol::Tcp)?;
+    let (sender, mut connections) = mpsc::channel(MAX_PENDING_CONNECTIONS);
+    interface
+        .send(LocalMessage::Listen(port_forward.clone(), sender))
+        .await
+        .with_context(|| "The TCP virtual interface stopped")?;
+
+    // The channel is dropped once the port forward is removed, or the virtual interface stopped
+    while let Some(virtual_port) = connections.recv().await {
+        let port_pool = port_pool.clone();
+        let port_forward = port_forward.clone();
+        let bus = bus.clone();
+        tokio::spawn(async move {
+            // A destination that does not answer is given up on, like one that refuses the connection
+            let connect = TcpStream::connect(port_forward.destination);
+            let connected = match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+                Ok(connected) => connected,
+                Err(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("timed out after {:?}", CONNECT_TIMEOUT),
+                )),
+            };
+            let result = match connected {
+                Ok(socket) => {
+                    info!(
+                        "[{}] Remote connection forwarded to {}",
+                        virtual_port, port_forward.destination
+                    );
+                    handle_tcp_proxy_connection(socket, virtual_port, port_forward, None, bus).await
+                }
+                Err(e) => {
+                    // Notify the virtual interface that the remote connection should be closed
+                    if let Ok(interface) = bus.interface(PortProtocol::Tcp) {
+                        let _ = interface.send(LocalMessage::Close(virtual_port)).await;
+                    }
+                    Err(e).with_context(|| {
+                        format!("Failed to connect to {}", port_forward.destination)
+                    })
+                }
+            };
 
             if let Err(e) = result {
                 error!(
-                    "[{}] Connection dropped un-gracefully: {:?}",
+                    "[{}] Remote connection dropped un-gracefully: {:?}",
                     virtual_port, e
                 );
             } else {
-                info!("[{}] Connection closed by client", virtual_port);
+                info!("[{}] Remote connection closed", virtual_port);
             }
 
             tokio::time::sleep(Duration::from_millis(100)).await; // Make sure the other tasks have time to process the event
             port_pool.release(virtual_port).await;
         });
     }
+    Ok(())
 }
 
 /// Handles a new TCP connection with its assigned virtual port.
+/// The initial data, already read from the client (e.g. by the HTTP proxy), is sent before anything else.
 async fn handle_tcp_proxy_connection(
-    mut socket: TcpStream,
+    socket: TcpStream,
     virtual_port: VirtualPort,
     port_forward: PortForwardConfig,
+    initial_data: Option<Bytes>,
     bus: Bus,
 ) -> anyhow::Result<()> {
-    let mut endpoint = bus.new_endpoint();
-    endpoint.send(Event::ClientConnectionInitiated(port_forward, virtual_port));
+    VirtualConnection::open(port_forward, virtual_port, &bus)
+        .await?
+        .proxy(socket, initial_data)
+        .await
+}
 
-    let mut buffer = BytesMut::with_capacity(MAX_PACKET);
-    loop {
-        tokio::select! {
-            readable_result = socket.readable() => {
-                match readable_result {
-                    Ok(_) => {
-                        match socket.try_read_buf(&mut buffer) {
-                            Ok(size) if size > 0 => {
-                                let data = Vec::from(&buffer[..size]);
-                                endpoint.send(Event::LocalData(port_forward, virtual_port, data.into()));
-                                // Reset buffer
-                                buffer.clear();
-                            }
-                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                continue;
-                            }
-                            Err(e) => {
-                                error!(
-                                    "[{}] Failed to read from client TCP socket: {:?}",
-                                    virtual_port, e
-                                );
-                                break;
+/// The virtual side of a TCP connection of a local server, initiated in the TCP virtual interface
+/// (or accepted by a remote port forward).
+pub(crate) struct VirtualConnection {
+    virtual_port: VirtualPort,
+    interface: mpsc::Sender<LocalMessage>,
+    endpoint: BusEndpoint,
+    remote_data: mpsc::Receiver<(VirtualPort, RemoteMessage)>,
+    credit: SendCredit,
+    /// Data received by the virtual interface while waiting for the connection to be established.
+    received: Option<Bytes>,
+}
+
+impl VirtualConnection {
+    /// Initiates the virtual connection. It is established asynchronously, see `wait_established`.
+    pub(crate) async fn open(
+        port_forward: PortForwardConfig,
+        virtual_port: VirtualPort,
+        bus: &Bus,
+    ) -> anyhow::Result<Self> {
+        let interface = bus.interface(PortProtocol::Tcp)?;
+        let (sender, remote_data) = mpsc::channel(MAX_QUEUED_DATA);
+        let credit = Arc::new(Semaphore::new(MAX_UNSENT_DATA));
+
+        // The connection is established in the virtual interface, but stops waiting if the tunnel is shutting down
+        let endpoint = bus.new_endpoint();
+        interface
+            .send(LocalMessage::Connect(
+                port_forward,
+                virtual_port,
+                sender,
+                Some(credit.clone()),
+            ))
+            .await
+            .with_context(|| "The TCP virtual interface stopped")?;
+
+        Ok(Self {
+            virtual_port,
+            interface,
+            endpoint,
+            remote_data,
+            credit,
+            received: None,
+        })
+    }
+
+    /// Waits until the virtual connection is established with its destination.
+    /// Fails if it is refused, times out, or cannot be initiated (e.g. unreachable destination).
+    pub(crate) async fn wait_established(&mut self) -> anyhow::Result<()> {
+        let timeout = tokio::time::sleep(CONNECT_TIMEOUT);
+        tokio::pin!(timeout);
+        loop {
+            tokio::select! {
+                event = self.endpoint.recv() => {
+                    if let Event::Shutdown = event {
+                        return Err(anyhow::anyhow!("The tunnel is shutting down"));
+                    }
+                },
+                message = self.remote_data.recv() => match message {
+                    Some((_, RemoteMessage::Established)) => return Ok(()),
+                    // Data can only be received once established
+                    Some((_, RemoteMessage::Data(data))) => {
+                        self.received = Some(data);
+                        return Ok(());
+                    }
+                    Some((_, RemoteMessage::Closed)) | None => {
+                        return Err(anyhow::anyhow!("The connection could not be established"));
+                    }
+                },
+                _ = &mut timeout => {
+                    return Err(anyhow::anyhow!("The connection timed out after {:?}", CONNECT_TIMEOUT));
+                }
+            }
+        }
+    }
+
+    /// Closes the virtual connection without proxying it, e.g. if it could not be established.
+    pub(crate) async fn close(self) {
+        let _ = self
+            .interface
+            .send(LocalMessage::Close(self.virtual_port))
+            .await;
+    }
+
+    /// Proxies the data between the local client and the virtual connection until either is closed.
+    async fn proxy(self, socket: TcpStream, initial_data: Option<Bytes>) -> anyhow::Result<()> {
+        let VirtualConnection {
+            virtual_port,
+            interface,
+            mut endpoint,
+            mut remote_data,
+            credit,
+            received,
+        } = self;
+
+        // When shutting down, the client stops being read, but the remaining data is still written to it
+        let mut reading = true;
+
+        // Data read from the client, sent to the virtual interface once the connection has enough send credit.
+        // The client is not read until it is sent.
+        let mut unsent = initial_data.filter(|data| !data.is_empty());
+
+        // Data received by the virtual interface, being written to the client.
+        // The virtual interface keeps the next data in its socket until it is written.
+        let mut unwritten: Option<Bytes> = received;
+
+        // The client is read in the rest of the buffer, which is reused once the data read from it is sent
+        let mut buffer = BytesMut::with_capacity(MAX_PACKET);
+        loop {
+            tokio::select! {
+                readable_result = socket.readable(), if reading && unsent.is_none() => {
+                    match readable_result {
+                        Ok(_) => {
+                            if buffer.capacity() < MIN_READ {
+                                buffer.reserve(MAX_PACKET);
                             }
-                            _ => {
-                                break;
+                            match socket.try_read_buf(&mut buffer) {
+                                Ok(size) if size > 0 => {
+                                    unsent = Some(buffer.split().freeze());
+                                }
+                                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!(
+                                        "[{}] Failed to read from client TCP socket: {:?}",
+                                        virtual_port, e
+                                    );
+                                    break;
+                                }
+                                _ => {
+                                    break;
+                                }
                             }
                         }
+                        Err(e) => {
+                            error!("[{}] Failed to check if readable: {:?}", virtual_port, e);
+                            break;
+                        }
                     }
-                    Err(e) => {
-                        error!("[{}] Failed to check if readable: {:?}", virtual_port, e);
+                }
+                permit = credit.acquire_many(unsent.as_ref().map_or(0, |data| data.len().min(MAX_UNSENT_DATA)) as u32), if unsent.is_some() => {
+                    let mut data = match (permit, unsent.take()) {
+                        (Ok(permit), Some(data)) => {
+                            // The virtual interface gives the permits back once the data is in the virtual socket
+                            permit.forget();
+                            data
+                        }
+                        _ => break,
+                    };
+                    if data.len() > MAX_UNSENT_DATA {
+                        unsent = Some(data.split_off(MAX_UNSENT_DATA));
+                    }
+                    if interface.send(LocalMessage::Data(virtual_port, data)).await.is_err() {
                         break;
                     }
                 }
-            }
-            event = endpoint.recv() => {
-                match event {
-                    Event::ClientConnectionDropped(e_vp) if e_vp == virtual_port => {
-                        // This connection is supposed to be closed, stop the task.
+                writable_result = socket.writable(), if unwritten.is_some() => {
+                    if let Err(e) = writable_result {
+                        error!("[{}] Failed to check if writable: {:?}", virtual_port, e);
                         break;
                     }
-                    Event::RemoteData(e_vp, data) if e_vp == virtual_port => {
-                        // Have remote data to send to the local client
-                        if let Err(e) = socket.writable().await {
-                            error!("[{}] Failed to check if writable: {:?}", virtual_port, e);
-                        }
-                        let expected = data.len();
-                        let mut sent = 0;
-                        loop {
-                            if sent >= expected {
-                                break;
-                            }
-                            match socket.write(&data[sent..expected]).await {
-                                Ok(written) => {
-                                    debug!("[{}] Sent {} (expected {}) bytes to local client", virtual_port, written, expected);
-                                    sent += written;
-                                    if sent < expected {
-                                        debug!("[{}] Will try to resend remaining {} bytes to local client", virtual_port, (expected - written));
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("[{}] Failed to send {} bytes to local client: {:?}", virtual_port, expected, e);
-                                    break;
-                                }
+                    let data = match unwritten.as_mut() {
+                        Some(data) => data,
+                        None => continue,
+                    };
+                    match socket.try_write(data) {
+                        Ok(written) => {
+                            debug!(
+                                "[{}] Sent {} (expected {}) bytes to local client",
+                                virtual_port, written, data.len()
+                            );
+                            data.advance(written);
+                            if data.is_empty() {
+                                unwritten = None;
                             }
                         }
+                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            continue;
+                        }
+                        Err(e) => {
+                            error!(
+                                "[{}] Failed to send {} bytes to local client: {:?}",
+                                virtual_port, data.len(), e
+                            );
+                            break;
+                        }
+                    }
+                }
+                message = remote_data.recv(), if unwritten.is_none() => {
+                    match message {
+                        // Have remote data to send to the local client
+                        Some((_, RemoteMessage::Data(data))) => unwritten = Some(data),
+                        Some(_) => {}
+                        // The virtual connection was closed (e.g. by the control API), after the data already received
+                        None => break,
+                    }
+                }
+                event = endpoint.recv() => {
+                    if let Event::Shutdown = event {
+                        reading = false;
                     }
-                    _ => {}
                 }
             }
         }
-    }
 
-    // Notify other endpoints that this task has closed and no more data is to be sent to the local client
-    endpoint.send(Event::ClientConnectionDropped(virtual_port));
+        // Notify the virtual interface that this task has closed and no more data is to be sent to the local client
+        let _ = interface.send(LocalMessage::Close(virtual_port)).await;
 
-    Ok(())
+        Ok(())
+    }
 }
 
 /// A pool of virtual ports available for TCP connections.
@@ -186,21 +447,41 @@ impl TcpPortPool {
         }
     }
 
-    /// Requests a free port from the pool. An error is returned if none is available (exhausted max capacity).
-    pub async fn next(&self) -> anyhow::Result<VirtualPort> {
+    /// Requests a free port from the pool for a connection from the given client.
+    /// An error is returned if none is available (exhausted max capacity).
+    pub async fn next(&self, client_addr: SocketAddr) -> anyhow::Result<VirtualPort> {
         let mut inner = self.inner.write().await;
         let port = inner
             .queue
             .pop_front()
             .with_context(|| "TCP virtual port pool is exhausted")?;
+        inner.client_addr_by_port.insert(port, client_addr);
         Ok(VirtualPort::new(port, PortProtocol::Tcp))
     }
 
     /// Releases a port back into the pool.
     pub async fn release(&self, port: VirtualPort) {
         let mut inner = self.inner.write().await;
+        inner.client_addr_by_port.remove(&port.num());
         inner.queue.push_back(port.num());
     }
+
+    /// Returns the number of ports in the pool, including those in use.
+    pub fn capacity(&self) -> usize {
+        PORT_RANGE.len()
+    }
+
+    /// Returns the ports in use, with the address of their client.
+    pub async fn active_ports(&self) -> Vec<(VirtualPort, SocketAddr)> {
+        let inner = self.inner.read().await;
+        let mut ports: Vec<(VirtualPort, SocketAddr)> = inner
+            .client_addr_by_port
+            .iter()
+            .map(|(port, addr)| (VirtualPort::new(*port, PortProtocol::Tcp), *addr))
+            .collect();
+        ports.sort_by_key(|(port, _)| port.num());
+        ports
+    }
 }
 
 /// Non thread-safe inner logic for TCP port pool.
@@ -208,4 +489,236 @@ impl TcpPortPool {
 struct TcpPortPoolInner {
     /// Remaining ports in the pool.
     queue: VecDeque<u16>,
+    /// The address of the client of each port in use.
+    client_addr_by_port: HashMap<u16, SocketAddr>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::events::RemoteDataSender;
+
+    fn port_forward(destination: SocketAddr, remote: bool) -> PortForwardConfig {
+        PortForwardConfig {
+            name: None,
+            source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+            destination,
+            destination_host: None,
+            protocol: PortProtocol::Tcp,
+            remote,
+            peer: None,
+        }
+    }
+
+    /// Returns a connected pair of local sockets: the client, and the socket accepted by the local server.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        (client, socket)
+    }
+
+    /// Returns the next message sent to the virtual interface, or `None` if none is sent for a while.
+    async fn next_message(interface: &mut mpsc::Receiver<LocalMessage>) -> Option<LocalMessage> {
+        tokio::time::timeout(Duration::from_millis(200), interface.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Returns the channel and the send credit of the next connection initiated in the virtual interface.
+    async fn next_connect(
+        interface: &mut mpsc::Receiver<LocalMessage>,
+        virtual_port: VirtualPort,
+    ) -> (RemoteDataSender, SendCredit) {
+        match next_message(interface).await {
+            Some(LocalMessage::Connect(_, vp, sender, Some(credit))) if vp == virtual_port => {
+                (sender, credit)
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    /// Returns the size of the data sent to the virtual interface until none is sent for a while.
+    async fn sent_data(interface: &mut mpsc::Receiver<LocalMessage>) -> usize {
+        let mut size = 0;
+        while let Some(message) = next_message(interface).await {
+            match message {
+                LocalMessage::Data(_, data) => size += data.len(),
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+        size
+    }
+
+    /// Tests that the data of a connection goes through its own channels, and that the local client is not read
+    /// while the virtual interface did not give back the send credit of the data already sent.
+    #[tokio::test]
+    async fn test_proxy_send_credit() {
+        let bus = Bus::new();
+        let mut interface = bus.register_interface(PortProtocol::Tcp);
+        let virtual_port = VirtualPort::new(40000, PortProtocol::Tcp);
+        let destination = SocketAddr::from_str("192.168.4.2:80").unwrap();
+        let connection =
+            VirtualConnection::open(port_forward(destination, false), virtual_port, &bus)
+                .await
+                .unwrap();
+        let (sender, credit) = next_connect(&mut interface, virtual_port).await;
+        let (client, socket) = tcp_pair().await;
+        let proxy = tokio::spawn(connection.proxy(socket, None));
+
+        // The client sends more data than the virtual socket can take at once
+        let (mut client_read, mut client_write) = client.into_split();
+        let total = MAX_UNSENT_DATA * 3;
+        let writer = tokio::spawn(async move {
+            client_write.write_all(&vec![1u8; total]).await.unwrap();
+            client_write
+        });
+
+        let mut sent = sent_data(&mut interface).await;
+        assert!(sent > 0 && sent <= MAX_UNSENT_DATA);
+        assert_eq!(credit.available_permits(), MAX_UNSENT_DATA - sent);
+
+        // The client is read again as the virtual socket drains
+        while sent < total {
+            credit.add_permits(MAX_UNSENT_DATA - credit.available_permits());
+            let more = sent_data(&mut interface).await;
+            assert!(
+                more > 0,
+                "The client should be read once the credit is back"
+            );
+            sent += more;
+        }
+        assert_eq!(sent, total);
+        let _client_write = writer.await.unwrap();
+
+        // The data received by the virtual interface is written to the client
+        sender
+            .send((
+                virtual_port,
+                RemoteMessage::Data(Bytes::from_static(b"HTTP/1.1 200 OK\r\n")),
+            ))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 17];
+        client_read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 OK\r\n");
+
+        // Dropping the channel closes the connection
+        drop(sender);
+        proxy.await.unwrap().unwrap();
+        assert!(matches!(
+            next_message(&mut interface).await,
+            Some(LocalMessage::Close(vp)) if vp == virtual_port
+        ));
+        assert_eq!(client_read.read(&mut buf).await.unwrap(), 0);
+    }
+
+    /// Tests that a connection is established once the virtual interface reports it on its channel, or fails if
+    /// the channel is dropped.
+    #[tokio::test]
+    async fn test_wait_established() {
+        let bus = Bus::new();
+        let mut interface = bus.register_interface(PortProtocol::Tcp);
+        let destination = SocketAddr::from_str("192.168.4.2:80").unwrap();
+        let open = |port: u16| {
+            let bus = bus.clone();
+            async move {
+                let virtual_port = VirtualPort::new(port, PortProtocol::Tcp);
+                VirtualConnection::open(port_forward(destination, false), virtual_port, &bus)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let mut connection = open(40000).await;
+        let (sender, _credit) = next_connect(&mut interface, connection.virtual_port).await;
+        sender
+            .send((connection.virtual_port, RemoteMessage::Established))
+            .await
+            .unwrap();
+        connection
+            .wait_established()
+            .await
+            .expect("The connection should be established");
+
+        // The data received first is kept for the client
+        let mut connection = open(40001).await;
+        let (sender, _credit) = next_connect(&mut interface, connection.virtual_port).await;
+        sender
+            .send((
+                connection.virtual_port,
+                RemoteMessage::Data(Bytes::from_static(b"hello")),
+            ))
+            .await
+            .unwrap();
+        connection
+            .wait_established()
+            .await
+            .expect("The connection should be established");
+        assert_eq!(connection.received.as_deref(), Some(&b"hello"[..]));
+
+        // The virtual interface drops the channel if the connection is refused, or cannot be initiated
+        let mut connection = open(40002).await;
+        drop(next_connect(&mut interface, connection.virtual_port).await);
+        assert!(connection.wait_established().await.is_err());
+    }
+
+    /// Tests that a connection accepted by a remote port forward is proxied to its real destination.
+    #[tokio::test]
+    async fn test_remote_port_forward() {
+        let bus = Bus::new();
+        let mut interface = bus.register_interface(PortProtocol::Tcp);
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port_forward = port_forward(destination.local_addr().unwrap(), true);
+        tokio::spawn(tcp_remote_proxy_server(
+            port_forward.clone(),
+            TcpPortPool::new(),
+            bus.clone(),
+        ));
+
+        // The server gives the virtual interface the channel of the connections accepted by the port forward
+        let connections = match next_message(&mut interface).await {
+            Some(LocalMessage::Listen(pf, connections)) if pf == port_forward => connections,
+            other => panic!("Unexpected message: {:?}", other),
+        };
+        let virtual_port = VirtualPort::new(40000, PortProtocol::Tcp);
+        connections.send(virtual_port).await.unwrap();
+        let (mut socket, _) = destination.accept().await.unwrap();
+        let (sender, _credit) = next_connect(&mut interface, virtual_port).await;
+
+        // From the peer to the destination
+        sender
+            .send((
+                virtual_port,
+                RemoteMessage::Data(Bytes::from_static(b"ping")),
+            ))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // From the destination to the peer
+        socket.write_all(b"pong").await.unwrap();
+        match next_message(&mut interface).await {
+            Some(LocalMessage::Data(vp, data)) if vp == virtual_port => {
+                assert_eq!(&data[..], b"pong")
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        // The connection is closed in the virtual interface once the destination closes it
+        drop(socket);
+        assert!(matches!(
+            next_message(&mut interface).await,
+            Some(LocalMessage::Close(vp)) if vp == virtual_port
+        ));
+    }
 }
@@ -1,8 +1,9 @@
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, SocketAddr};
 use std::ops::Range;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use bytes::Bytes;
@@ -10,9 +11,12 @@ use priority_queue::double_priority_queue::DoublePriorityQueue;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
 
+use crate::buffer::BufferPool;
 use crate::config::{PortForwardConfig, PortProtocol};
-use crate::events::{Bus, Event};
+use crate::events::{Bus, LocalMessage, RemoteMessage};
+use crate::tunnel::dns::DnsResolver;
 use crate::virtual_iface::VirtualPort;
 
 const MAX_PACKET: usize = 65536;
@@ -24,28 +28,57 @@ const PORT_RANGE: Range<u16> = MIN_PORT..MAX_PORT;
 /// TODO: Make this configurable by the CLI
 const UDP_TIMEOUT_SECONDS: u64 = 60;
 
+/// How many datagrams can be queued for a local server or remote flow before they are dropped.
+const MAX_FLOW_QUEUE: usize = 100;
+
+/// How many new flows of a remote port forward can wait for its server.
+const MAX_PENDING_FLOWS: usize = 16;
+
 /// To prevent port-flooding, we set a limit on the amount of open ports per IP address.
 /// TODO: Make this configurable by the CLI
 const PORTS_PER_IP: usize = 100;
 
-/// Starts the server that listens on UDP datagrams.
+/// Starts the server that receives UDP datagrams on the socket bound on the source of the port forward.
 pub async fn udp_proxy_server(
+    socket: UdpSocket,
     port_forward: PortForwardConfig,
     port_pool: UdpPortPool,
+    resolver: DnsResolver,
     bus: Bus,
 ) -> anyhow::Result<()> {
-    let mut endpoint = bus.new_endpoint();
-    let socket = UdpSocket::bind(port_forward.source)
-        .await
-        .with_context(|| "Failed to bind on UDP proxy address")?;
+    let interface = bus.interface(PortProtocol::Udp)?;
+
+    // The datagrams received by the virtual interface for the virtual ports of this server
+    let (remote_sender, mut remote_data) = mpsc::channel(MAX_FLOW_QUEUE);
+
+    // Virtual ports assigned to the clients of this server, with the destination resolved for each
+    let mut ports: HashMap<VirtualPort, PortForwardConfig> = HashMap::new();
 
     let mut buffer = [0u8; MAX_PACKET];
+    let mut buffers = BufferPool::default();
     loop {
         tokio::select! {
-            to_send_result = next_udp_datagram(&socket, &mut buffer, port_pool.clone()) => {
+            to_send_result = next_udp_datagram(&socket, &mut buffer, &mut buffers, port_pool.clone()) => {
                 match to_send_result {
                     Ok(Some((port, data))) => {
-                        endpoint.send(Event::LocalData(port_forward, port, data));
+                        if let Entry::Vacant(entry) = ports.entry(port) {
+                            let resolved = match resolver.resolve_port_forward(&port_forward).await {
+                                Ok(resolved) => resolved,
+                                Err(e) => {
+                                    error!("[{}] Failed to resolve destination: {:?}", port, e);
+                                    continue;
+                                }
+                            };
+                            entry.insert(resolved.clone());
+                            interface
+                                .send(LocalMessage::Connect(resolved, port, remote_sender.clone(), None))
+                                .await
+                                .with_context(|| "The UDP virtual interface stopped")?;
+                        }
+                        interface
+                            .send(LocalMessage::Data(port, data))
+                            .await
+                            .with_context(|| "The UDP virtual interface stopped")?;
                     }
                     Ok(None) => {
                         continue;
@@ -59,35 +92,45 @@ pub async fn udp_proxy_server(
                     }
                 }
             }
-            event = endpoint.recv() => {
-                if let Event::RemoteData(virtual_port, data) = event {
-                    if let Some(peer) = port_pool.get_peer_addr(virtual_port).await {
-                        // Have remote data to send to the local client
-                        if let Err(e) = socket.writable().await {
-                            error!("[{}] Failed to check if writable: {:?}", virtual_port, e);
+            Some((virtual_port, message)) = remote_data.recv() => {
+                let data = match message {
+                    RemoteMessage::Data(data) => data,
+                    RemoteMessage::Closed => {
+                        // The flow was closed (e.g. by the control API): the next datagrams of its client open a new one
+                        ports.remove(&virtual_port);
+                        continue;
+                    }
+                    RemoteMessage::Established => continue,
+                };
+                if !ports.contains_key(&virtual_port) {
+                    continue;
+                }
+                if let Some(peer) = port_pool.get_peer_addr(virtual_port).await {
+                    // Have remote data to send to the local client
+                    if let Err(e) = socket.writable().await {
+                        error!("[{}] Failed to check if writable: {:?}", virtual_port, e);
+                    }
+                    let expected = data.len();
+                    let mut sent = 0;
+                    loop {
+                        if sent >= expected {
+                            break;
                         }
-                        let expected = data.len();
-                        let mut sent = 0;
-                        loop {
-                            if sent >= expected {
-                                break;
-                            }
-                            match socket.send_to(&data[sent..expected], peer).await {
-                                Ok(written) => {
-                                    debug!("[{}] Sent {} (expected {}) bytes to local client", virtual_port, written, expected);
-                                    sent += written;
-                                    if sent < expected {
-                                        debug!("[{}] Will try to resend remaining {} bytes to local client", virtual_port, (expected - written));
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("[{}] Failed to send {} bytes to local client: {:?}", virtual_port, expected, e);
-                                    break;
+                        match socket.send_to(&data[sent..expected], peer).await {
+                            Ok(written) => {
+                                debug!("[{}] Sent {} (expected {}) bytes to local client", virtual_port, written, expected);
+                                sent += written;
+                                if sent < expected {
+                                    debug!("[{}] Will try to resend remaining {} bytes to local client", virtual_port, (expected - written));
                                 }
+                            },
+                            Err(e) => {
+                                error!("[{}] Failed to send {} bytes to local client: {:?}", virtual_port, expected, e);
+                                break;
                             }
                         }
-                        port_pool.update_last_transmit(virtual_port).await;
                     }
+                    port_pool.update_last_transmit(virtual_port).await;
                 }
             }
         }
@@ -95,9 +138,138 @@ pub async fn udp_proxy_server(
     Ok(())
 }
 
+/// Starts the server that forwards datagrams received by a remote port forward in the virtual interface
+/// to the real destination. Each remote peer address gets its own local UDP socket.
+pub async fn udp_remote_proxy_server(
+    port_forward: PortForwardConfig,
+    port_pool: UdpPortPool,
+    bus: Bus,
+) -> anyhow::Result<()> {
+    let interface = bus.interface(PortProtocol::Udp)?;
+    let (sender, mut flows) = mpsc::channel(MAX_PENDING_FLOWS);
+    interface
+        .send(LocalMessage::Listen(port_forward.clone(), sender))
+        .await
+        .with_context(|| "The UDP virtual interface stopped")?;
+
+    // The channel is dropped once the port forward is removed, or the virtual interface stopped
+    while let Some(virtual_port) = flows.recv().await {
+        let bind_addr: SocketAddr = if port_forward.destination.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!(
+                    "[{}] Failed to bind UDP socket for remote flow: {:?}",
+                    virtual_port, e
+                );
+                let _ = interface.send(LocalMessage::Close(virtual_port)).await;
+                port_pool.release(virtual_port).await;
+                continue;
+            }
+        };
+        if let Err(e) = socket.connect(port_forward.destination).await {
+            error!(
+                "[{}] Failed to connect UDP socket to {}: {:?}",
+                virtual_port, port_forward.destination, e
+            );
+            let _ = interface.send(LocalMessage::Close(virtual_port)).await;
+            port_pool.release(virtual_port).await;
+            continue;
+        }
+
+        // The datagrams received by the virtual interface for the flow
+        let (remote_sender, remote_data) = mpsc::channel(MAX_FLOW_QUEUE);
+        interface
+            .send(LocalMessage::Connect(
+                port_forward.clone(),
+                virtual_port,
+                remote_sender,
+                None,
+            ))
+            .await
+            .with_context(|| "The UDP virtual interface stopped")?;
+
+        let interface = interface.clone();
+        let port_pool = port_pool.clone();
+        let port_forward = port_forward.clone();
+        tokio::spawn(async move {
+            handle_udp_remote_flow(
+                socket,
+                remote_data,
+                virtual_port,
+                port_forward,
+                port_pool,
+                interface,
+            )
+            .await
+        });
+    }
+    Ok(())
+}
+
+/// Proxies datagrams between a remote flow and its local UDP socket until the flow is idle, then releases its
+/// virtual port.
+async fn handle_udp_remote_flow(
+    socket: UdpSocket,
+    mut remote_data: mpsc::Receiver<(VirtualPort, RemoteMessage)>,
+    virtual_port: VirtualPort,
+    port_forward: PortForwardConfig,
+    port_pool: UdpPortPool,
+    interface: mpsc::Sender<LocalMessage>,
+) {
+    let mut buffer = [0u8; MAX_PACKET];
+    let mut buffers = BufferPool::default();
+    let timeout = Duration::from_secs(UDP_TIMEOUT_SECONDS);
+
+    loop {
+        tokio::select! {
+            recv_result = socket.recv(&mut buffer) => {
+                match recv_result {
+                    Ok(size) => {
+                        debug!("[{}] Received datagram of {} bytes from {}", virtual_port, size, port_forward.destination);
+                        port_pool.update_last_transmit(virtual_port).await;
+                        let data = buffers.copy_from_slice(&buffer[..size]);
+                        if interface.send(LocalMessage::Data(virtual_port, data)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("[{}] Failed to read from remote flow UDP socket: {:?}", virtual_port, e);
+                        break;
+                    }
+                }
+            }
+            message = remote_data.recv() => {
+                match message {
+                    Some((_, RemoteMessage::Data(data))) => {
+                        if let Err(e) = socket.send(&data).await {
+                            error!("[{}] Failed to send {} bytes to {}: {:?}", virtual_port, data.len(), port_forward.destination, e);
+                        }
+                    }
+                    Some(_) => {}
+                    // The flow was dropped by the virtual interface
+                    None => break,
+                }
+            }
+            _ = tokio::time::sleep(timeout) => {
+                debug!("[{}] Remote UDP flow is idle, closing", virtual_port);
+                break;
+            }
+        }
+    }
+
+    let _ = interface.send(LocalMessage::Close(virtual_port)).await;
+    port_pool.release(virtual_port).await;
+}
+
 async fn next_udp_datagram(
     socket: &UdpSocket,
     buffer: &mut [u8],
+    buffers: &mut BufferPool,
     port_pool: UdpPortPool,
 ) -> anyhow::Result<Option<(VirtualPort, Bytes)>> {
     let (size, peer_addr) = socket
@@ -126,8 +298,7 @@ async fn next_udp_datagram(
 
     port_pool.update_last_transmit(port).await;
 
-    let data = buffer[..size].to_vec();
-    Ok(Some((port, data.into())))
+    Ok(Some((port, buffers.copy_from_slice(&buffer[..size]))))
 }
 
 /// A pool of virtual ports available for TCP connections.
@@ -158,12 +329,30 @@ impl UdpPortPool {
 
     /// Requests a free port from the pool. An error is returned if none is available (exhausted max capacity).
     pub async fn next(&self, peer_addr: SocketAddr) -> anyhow::Result<VirtualPort> {
+        self.next_flow(peer_addr, None).await
+    }
+
+    /// Requests a free port from the pool, for a peer sending datagrams to several destinations (e.g. SOCKS5 UDP).
+    /// Each destination of the peer gets its own port, so that replies can be attributed to their destination.
+    pub async fn next_for_destination(
+        &self,
+        peer_addr: SocketAddr,
+        destination: SocketAddr,
+    ) -> anyhow::Result<VirtualPort> {
+        self.next_flow(peer_addr, Some(destination)).await
+    }
+
+    async fn next_flow(
+        &self,
+        peer_addr: SocketAddr,
+        destination: Option<SocketAddr>,
+    ) -> anyhow::Result<VirtualPort> {
         // A port found to be reused. This is outside of the block because the read lock cannot be upgraded to a write lock.
         let mut port_reuse: Option<u16> = None;
 
         {
             let inner = self.inner.read().await;
-            if let Some(port) = inner.port_by_peer_addr.get(&peer_addr) {
+            if let Some(port) = inner.port_by_flow.get(&(peer_addr, destination)) {
                 return Ok(VirtualPort::new(*port, PortProtocol::Udp));
             }
 
@@ -214,7 +403,7 @@ impl UdpPortPool {
             })
             .with_context(|| "virtual port pool is exhausted")?;
 
-        inner.port_by_peer_addr.insert(peer_addr, port);
+        inner.port_by_flow.insert((peer_addr, destination), port);
         inner.peer_addr_by_port.insert(port, peer_addr);
         Ok(VirtualPort::new(port, PortProtocol::Udp))
     }
@@ -233,10 +422,48 @@ impl UdpPortPool {
         pq.push(port.num(), Instant::now());
     }
 
+    /// Releases a port of a flow that ended, e.g. when its SOCKS5 association was closed.
+    pub async fn release(&self, port: VirtualPort) {
+        let mut inner = self.inner.write().await;
+        let port = port.num();
+        let peer = match inner.peer_addr_by_port.remove(&port) {
+            Some(peer) => peer,
+            // Already released
+            None => return,
+        };
+        inner.port_by_flow.retain(|_, flow_port| *flow_port != port);
+        if let Some(pq) = inner.peer_port_usage.get_mut(&peer.ip()) {
+            pq.remove(&port);
+            if pq.is_empty() {
+                inner.peer_port_usage.remove(&peer.ip());
+            }
+        }
+        inner.port_usage.remove(&port);
+        inner.queue.push_back(port);
+    }
+
     pub async fn get_peer_addr(&self, port: VirtualPort) -> Option<SocketAddr> {
         let inner = self.inner.read().await;
         inner.peer_addr_by_port.get(&port.num()).copied()
     }
+
+    /// Returns the number of ports in the pool, including those in use.
+    pub fn capacity(&self) -> usize {
+        PORT_RANGE.len()
+    }
+
+    /// Returns the ports assigned to a peer, with the peer address. Ports are only reused once idle,
+    /// so this includes ports that have not transmitted recently.
+    pub async fn active_ports(&self) -> Vec<(VirtualPort, SocketAddr)> {
+        let inner = self.inner.read().await;
+        let mut ports: Vec<(VirtualPort, SocketAddr)> = inner
+            .peer_addr_by_port
+            .iter()
+            .map(|(port, addr)| (VirtualPort::new(*port, PortProtocol::Udp), *addr))
+            .collect();
+        ports.sort_by_key(|(port, _)| port.num());
+        ports
+    }
 }
 
 /// Non thread-safe inner logic for UDP port pool.
@@ -244,9 +471,9 @@ impl UdpPortPool {
 struct UdpPortPoolInner {
     /// Remaining ports in the pool.
     queue: VecDeque<u16>,
-    /// The port assigned by peer IP/port. This is used to lookup an existing virtual port
-    /// for an incoming UDP datagram.
-    port_by_peer_addr: HashMap<SocketAddr, u16>,
+    /// The port assigned by peer IP/port, and destination if the peer has several. This is used to lookup
+    /// an existing virtual port for an incoming UDP datagram.
+    port_by_flow: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
     /// The socket address assigned to a peer IP/port. This is used to send a UDP datagram to
     /// the real peer address, given the virtual port.
     peer_addr_by_port: HashMap<u16, SocketAddr>,
@@ -255,3 +482,132 @@ struct UdpPortPoolInner {
     /// Keeps an ordered map of the most recently used virtual ports in general.
     port_usage: DoublePriorityQueue<u16, Instant>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// Returns the next message sent to the virtual interface, or `None` if none is sent for a while.
+    async fn next_message(interface: &mut mpsc::Receiver<LocalMessage>) -> Option<LocalMessage> {
+        tokio::time::timeout(Duration::from_millis(200), interface.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Tests that the datagrams of a flow go to the virtual interface on its channel, with the replies coming back
+    /// on the channel given for the flow, and that a closed flow is opened again by the next datagram.
+    #[tokio::test]
+    async fn test_udp_proxy_server_channels() {
+        let bus = Bus::new();
+        let mut interface = bus.register_interface(PortProtocol::Udp);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let source = socket.local_addr().unwrap();
+        let port_forward = PortForwardConfig {
+            name: None,
+            source,
+            destination: SocketAddr::from_str("192.168.4.2:53").unwrap(),
+            destination_host: None,
+            protocol: PortProtocol::Udp,
+            remote: false,
+            peer: None,
+        };
+        let port_pool = UdpPortPool::new();
+        let resolver = DnsResolver::new(None, vec![], port_pool.clone(), bus.clone());
+        tokio::spawn(udp_proxy_server(
+            socket,
+            port_forward.clone(),
+            port_pool,
+            resolver,
+            bus.clone(),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"query", source).await.unwrap();
+        let (virtual_port, sender) = match next_message(&mut interface).await {
+            Some(LocalMessage::Connect(pf, virtual_port, sender, None)) => {
+                assert_eq!(pf, port_forward);
+                (virtual_port, sender)
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        };
+        match next_message(&mut interface).await {
+            Some(LocalMessage::Data(vp, data)) if vp == virtual_port => {
+                assert_eq!(&data[..], b"query")
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        sender
+            .send((
+                virtual_port,
+                RemoteMessage::Data(Bytes::from_static(b"answer")),
+            ))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (size, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..size], b"answer");
+
+        // Once closed (e.g. by the control API), the flow is opened again
+        sender
+            .send((virtual_port, RemoteMessage::Closed))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.send_to(b"query", source).await.unwrap();
+        assert!(matches!(
+            next_message(&mut interface).await,
+            Some(LocalMessage::Connect(_, vp, _, None)) if vp == virtual_port
+        ));
+    }
+
+    /// Tests that the virtual port of a remote flow is released once the flow ends.
+    #[tokio::test]
+    async fn test_udp_remote_flow_releases_port() {
+        let bus = Bus::new();
+        let mut interface = bus.register_interface(PortProtocol::Udp);
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port_forward = PortForwardConfig {
+            name: None,
+            source: SocketAddr::from_str("192.168.4.3:8080").unwrap(),
+            destination: destination.local_addr().unwrap(),
+            destination_host: None,
+            protocol: PortProtocol::Udp,
+            remote: true,
+            peer: None,
+        };
+        let port_pool = UdpPortPool::new();
+        tokio::spawn(udp_remote_proxy_server(
+            port_forward.clone(),
+            port_pool.clone(),
+            bus.clone(),
+        ));
+
+        let flows = match next_message(&mut interface).await {
+            Some(LocalMessage::Listen(pf, flows)) if pf == port_forward => flows,
+            other => panic!("Unexpected message: {:?}", other),
+        };
+        let peer = SocketAddr::from_str("192.168.4.2:40000").unwrap();
+        let virtual_port = port_pool.next(peer).await.unwrap();
+        flows.send(virtual_port).await.unwrap();
+        let sender = match next_message(&mut interface).await {
+            Some(LocalMessage::Connect(_, vp, sender, None)) if vp == virtual_port => sender,
+            other => panic!("Unexpected message: {:?}", other),
+        };
+
+        // The virtual interface drops the channel of the flow (e.g. closed by the control API)
+        drop(sender);
+        assert!(matches!(
+            next_message(&mut interface).await,
+            Some(LocalMessage::Close(vp)) if vp == virtual_port
+        ));
+        tokio::task::yield_now().await;
+        assert!(port_pool.active_ports().await.is_empty());
+    }
+}
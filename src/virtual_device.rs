@@ -1,80 +1,73 @@
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::Bytes;
 use smoltcp::phy::{Device, DeviceCapabilities, Medium};
 use smoltcp::time::Instant;
+use tokio::sync::mpsc;
 
-use crate::config::PortProtocol;
-use crate::events::{BusSender, Event};
-use crate::Bus;
+use crate::buffer::BufferPool;
 
 /// A virtual device that processes IP packets through smoltcp and WireGuard.
 pub struct VirtualIpDevice {
     /// Max transmission unit (bytes)
     max_transmission_unit: usize,
-    /// Channel receiver for received IP packets.
-    bus_sender: BusSender,
-    /// Local queue for packets received from the bus that need to go through the smoltcp interface.
-    process_queue: Arc<Mutex<VecDeque<Bytes>>>,
+    /// Channel of the IP packets to send through the WireGuard tunnel.
+    outbound: mpsc::Sender<Bytes>,
+    /// Channel of the IP packets received from the WireGuard tunnel.
+    inbound: mpsc::Receiver<Bytes>,
+    /// Local queue for packets received from the tunnel that need to go through the smoltcp interface.
+    process_queue: VecDeque<Bytes>,
+    /// Buffer of the packet being processed by the smoltcp interface, which needs it to be mutable.
+    rx_buffer: Vec<u8>,
+    /// Buffers of the packets crafted by the smoltcp interface.
+    tx_buffers: BufferPool,
 }
 
 impl VirtualIpDevice {
-    /// Initializes a new virtual IP device.
-    pub fn new(protocol: PortProtocol, bus: Bus, max_transmission_unit: usize) -> Self {
-        let mut bus_endpoint = bus.new_endpoint();
-        let bus_sender = bus_endpoint.sender();
-        let process_queue = Arc::new(Mutex::new(VecDeque::new()));
-
-        {
-            let process_queue = process_queue.clone();
-            tokio::spawn(async move {
-                loop {
-                    match bus_endpoint.recv().await {
-                        Event::InboundInternetPacket(ip_proto, data) if ip_proto == protocol => {
-                            let mut queue = process_queue
-                                .lock()
-                                .expect("Failed to acquire process queue lock");
-                            queue.push_back(data);
-                            bus_endpoint.send(Event::VirtualDeviceFed(ip_proto));
-                        }
-                        _ => {}
-                    }
-                }
-            });
-        }
-
+    /// Initializes a new virtual IP device, which receives and sends IP packets on the given channels.
+    pub fn new(
+        inbound: mpsc::Receiver<Bytes>,
+        outbound: mpsc::Sender<Bytes>,
+        max_transmission_unit: usize,
+    ) -> Self {
         Self {
-            bus_sender,
-            process_queue,
+            outbound,
+            inbound,
+            process_queue: VecDeque::new(),
+            rx_buffer: Vec::new(),
+            tx_buffers: BufferPool::default(),
             max_transmission_unit,
         }
     }
+
+    /// Waits for an IP packet from the tunnel, and queues it to be processed on the next poll of the interface.
+    pub async fn recv_packet(&mut self) {
+        match self.inbound.recv().await {
+            Some(packet) => self.process_queue.push_back(packet),
+            // The tunnel was dropped, no packet will be received anymore
+            None => futures::future::pending().await,
+        }
+    }
 }
 
 impl<'a> Device<'a> for VirtualIpDevice {
-    type RxToken = RxToken;
-    type TxToken = TxToken;
+    type RxToken = RxToken<'a>;
+    type TxToken = TxToken<'a>;
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let next = {
-            let mut queue = self
-                .process_queue
-                .lock()
-                .expect("Failed to acquire process queue lock");
-            queue.pop_front()
-        };
+        let next = self
+            .process_queue
+            .pop_front()
+            .or_else(|| self.inbound.try_recv().ok());
         match next {
-            Some(buffer) => Some((
+            Some(packet) => Some((
                 Self::RxToken {
-                    buffer: {
-                        let mut buf = BytesMut::new();
-                        buf.put(buffer);
-                        buf
-                    },
+                    packet,
+                    buffer: &mut self.rx_buffer,
                 },
                 Self::TxToken {
-                    sender: self.bus_sender.clone(),
+                    sender: &self.outbound,
+                    buffers: &mut self.tx_buffers,
                 },
             )),
             None => None,
@@ -83,7 +76,8 @@ impl<'a> Device<'a> for VirtualIpDevice {
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
         Some(TxToken {
-            sender: self.bus_sender.clone(),
+            sender: &self.outbound,
+            buffers: &mut self.tx_buffers,
         })
     }
 
@@ -96,34 +90,39 @@ impl<'a> Device<'a> for VirtualIpDevice {
 }
 
 #[doc(hidden)]
-pub struct RxToken {
-    buffer: BytesMut,
+pub struct RxToken<'a> {
+    packet: Bytes,
+    buffer: &'a mut Vec<u8>,
 }
 
-impl smoltcp::phy::RxToken for RxToken {
-    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+impl smoltcp::phy::RxToken for RxToken<'_> {
+    fn consume<R, F>(self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
-        f(&mut self.buffer)
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&self.packet);
+        f(self.buffer)
     }
 }
 
 #[doc(hidden)]
-pub struct TxToken {
-    sender: BusSender,
+pub struct TxToken<'a> {
+    sender: &'a mpsc::Sender<Bytes>,
+    buffers: &'a mut BufferPool,
 }
 
-impl smoltcp::phy::TxToken for TxToken {
+impl smoltcp::phy::TxToken for TxToken<'_> {
     fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
-        let mut buffer = Vec::new();
-        buffer.resize(len, 0);
+        let mut buffer = self.buffers.get(len);
         let result = f(&mut buffer);
-        self.sender
-            .send(Event::OutboundInternetPacket(buffer.into()));
+        if self.sender.try_send(buffer.freeze()).is_err() {
+            // Like a full network interface, the packet is dropped: TCP sends it again
+            debug!("Dropped outbound IP packet, since the WireGuard tunnel is busy");
+        }
         result
     }
 }
@@ -1,10 +1,15 @@
 pub mod tcp;
 pub mod udp;
 
-use crate::config::PortProtocol;
+use crate::config::{PeerConfig, PortForwardConfig, PortProtocol};
+use crate::events::RemoteDataSender;
 use crate::VirtualIpDevice;
 use async_trait::async_trait;
+use smoltcp::iface::Routes;
+use smoltcp::wire::{IpAddress, IpCidr};
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
 
 #[async_trait]
 pub trait VirtualInterfacePoll {
@@ -13,6 +18,83 @@ pub trait VirtualInterfacePoll {
     async fn poll_loop(mut self, device: VirtualIpDevice) -> anyhow::Result<()>;
 }
 
+/// The addresses of a virtual interface: the source peer IPs with their prefix lengths, and the destinations of the
+/// port forwards, so that the interface accepts the packets to them.
+fn interface_addresses(peers: &[PeerConfig], port_forwards: &[PortForwardConfig]) -> Vec<IpCidr> {
+    let mut addresses: Vec<IpCidr> = Vec::new();
+    let destinations = port_forwards
+        .iter()
+        .filter(|pf| !pf.remote && pf.destination_host.is_none())
+        .map(|pf| {
+            let ip = pf.destination.ip();
+            IpCidr::new(IpAddress::from(ip), if ip.is_ipv4() { 32 } else { 128 })
+        });
+    for cidr in peers
+        .iter()
+        .flat_map(|peer| peer.source_peer_ips.iter().copied())
+        .chain(destinations)
+    {
+        if !addresses
+            .iter()
+            .any(|address| address.address() == cidr.address())
+        {
+            addresses.push(cidr);
+        }
+    }
+    addresses
+}
+
+/// Creates default routes through the source peer IPs, so that the virtual interface can reach any destination
+/// (e.g. from the SOCKS5 proxy), and not only the destinations of the port forwards in its addresses.
+fn default_routes(peers: &[PeerConfig]) -> anyhow::Result<Routes<'static>> {
+    let mut routes = Routes::new(BTreeMap::new());
+    let source_peer_ips = || {
+        peers
+            .iter()
+            .flat_map(|peer| peer.source_peer_ips.iter())
+            .map(|cidr| IpAddr::from(cidr.address()))
+    };
+    let ipv4 = source_peer_ips().find_map(|ip| match ip {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    });
+    let ipv6 = source_peer_ips().find_map(|ip| match ip {
+        IpAddr::V4(_) => None,
+        IpAddr::V6(ip) => Some(ip),
+    });
+    if let Some(ip) = ipv4 {
+        routes
+            .add_default_ipv4_route(ip.into())
+            .map_err(|e| anyhow::anyhow!("Failed to add default IPv4 route: {:?}", e))?;
+    }
+    if let Some(ip) = ipv6 {
+        routes
+            .add_default_ipv6_route(ip.into())
+            .map_err(|e| anyhow::anyhow!("Failed to add default IPv6 route: {:?}", e))?;
+    }
+    Ok(routes)
+}
+
+/// Waits until any of the channels has room for more data, or is closed.
+async fn any_capacity(senders: Vec<RemoteDataSender>) {
+    if senders.is_empty() {
+        return futures::future::pending().await;
+    }
+    let reservations = senders.iter().map(|sender| Box::pin(sender.reserve()));
+    // The reserved capacity is released when the permit is dropped
+    let _ = futures::future::select_all(reservations).await;
+}
+
+/// Waits until the next poll of a virtual interface: right away if it is due, and never if there is none,
+/// since the interface is then polled on its next packet or event.
+async fn wait_poll(next_poll: Option<tokio::time::Instant>) {
+    match next_poll {
+        Some(until) if until > tokio::time::Instant::now() => tokio::time::sleep_until(until).await,
+        Some(_) => {}
+        None => futures::future::pending().await,
+    }
+}
+
 /// Virtual port.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct VirtualPort(u16, PortProtocol);
@@ -63,3 +145,24 @@ impl Display for VirtualPort {
         write!(f, "[{}:{}]", self.num(), self.proto())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tokio::time::{Duration, Instant};
+
+    use super::*;
+
+    /// Tests that a due poll does not wait for the timer, and that there is no poll without a deadline.
+    #[tokio::test]
+    async fn test_wait_poll() {
+        assert!(wait_poll(Some(Instant::now())).now_or_never().is_some());
+        assert!(wait_poll(Some(Instant::now() - Duration::from_secs(1)))
+            .now_or_never()
+            .is_some());
+        assert!(wait_poll(Some(Instant::now() + Duration::from_secs(60)))
+            .now_or_never()
+            .is_none());
+        assert!(wait_poll(None).now_or_never().is_none());
+    }
+}
@@ -1,44 +1,71 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
 use async_trait::async_trait;
 use bytes::Bytes;
-use smoltcp::iface::{InterfaceBuilder, SocketHandle};
+use smoltcp::iface::{Interface, InterfaceBuilder, SocketHandle};
 use smoltcp::socket::{TcpSocket, TcpSocketBuffer, TcpState};
 use smoltcp::wire::{IpAddress, IpCidr};
+use tokio::sync::mpsc;
 
-use crate::config::{PortForwardConfig, PortProtocol};
-use crate::events::Event;
+use crate::buffer::BufferPool;
+use crate::config::{PeerConfig, PortForwardConfig, PortProtocol};
+use crate::events::{
+    Event, LocalMessage, RemoteConnectionSender, RemoteDataSender, RemoteMessage, SendCredit,
+};
+use crate::metrics::Traffic;
+use crate::tunnel::tcp::TcpPortPool;
 use crate::virtual_device::VirtualIpDevice;
-use crate::virtual_iface::{VirtualInterfacePoll, VirtualPort};
+use crate::virtual_iface::{
+    any_capacity, default_routes, interface_addresses, wait_poll, VirtualInterfacePoll, VirtualPort,
+};
 use crate::Bus;
 
 const MAX_PACKET: usize = 65536;
 
+/// How many sockets listen for each remote port forward, like the backlog of a listening socket: this is how many
+/// connections can be in the handshake at once. The SYNs of more connections in a burst are reset.
+const REMOTE_BACKLOG: usize = 4;
+
 /// A virtual interface for proxying Layer 7 data to Layer 3 packets, and vice-versa.
 pub struct TcpVirtualInterface {
-    source_peer_ip: IpAddr,
+    peers: Vec<PeerConfig>,
     port_forwards: Vec<PortForwardConfig>,
     bus: Bus,
+    /// The messages of the local servers, registered on the bus.
+    messages: mpsc::Receiver<LocalMessage>,
+    port_pool: TcpPortPool,
+    traffic: Arc<Traffic>,
 }
 
 impl TcpVirtualInterface {
-    /// Initialize the parameters for a new virtual interface.
+    /// Initialize the parameters for a new virtual interface, and register its channel on the bus.
     /// Use the `poll_loop()` future to start the virtual interface poll loop.
-    pub fn new(port_forwards: Vec<PortForwardConfig>, bus: Bus, source_peer_ip: IpAddr) -> Self {
+    pub fn new(
+        port_forwards: Vec<PortForwardConfig>,
+        bus: Bus,
+        peers: Vec<PeerConfig>,
+        port_pool: TcpPortPool,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        let messages = bus.register_interface(PortProtocol::Tcp);
         Self {
             port_forwards: port_forwards
                 .into_iter()
                 .filter(|f| matches!(f.protocol, PortProtocol::Tcp))
                 .collect(),
-            source_peer_ip,
+            peers,
             bus,
+            messages,
+            port_pool,
+            traffic,
         }
     }
 
-    fn new_server_socket(port_forward: PortForwardConfig) -> anyhow::Result<TcpSocket<'static>> {
+    fn new_server_socket(port_forward: &PortForwardConfig) -> anyhow::Result<TcpSocket<'static>> {
         static mut TCP_SERVER_RX_DATA: [u8; 0] = [];
         static mut TCP_SERVER_TX_DATA: [u8; 0] = [];
 
@@ -56,6 +83,27 @@ impl TcpVirtualInterface {
         Ok(socket)
     }
 
+    /// Creates a socket listening on the virtual interface for a remote port forward.
+    /// Unlike the virtual server sockets, this socket accepts connections from other peers, so it needs real buffers.
+    fn new_remote_server_socket(
+        port_forward: &PortForwardConfig,
+    ) -> anyhow::Result<TcpSocket<'static>> {
+        let rx_data = vec![0u8; MAX_PACKET];
+        let tx_data = vec![0u8; MAX_PACKET];
+        let tcp_rx_buffer = TcpSocketBuffer::new(rx_data);
+        let tcp_tx_buffer = TcpSocketBuffer::new(tx_data);
+        let mut socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+
+        socket
+            .listen((
+                IpAddress::from(port_forward.source.ip()),
+                port_forward.source.port(),
+            ))
+            .with_context(|| "Virtual remote server socket failed to listen")?;
+
+        Ok(socket)
+    }
+
     fn new_client_socket() -> anyhow::Result<TcpSocket<'static>> {
         let rx_data = vec![0u8; MAX_PACKET];
         let tx_data = vec![0u8; MAX_PACKET];
@@ -65,38 +113,91 @@ impl TcpVirtualInterface {
         Ok(socket)
     }
 
-    fn addresses(&self) -> Vec<IpCidr> {
-        let mut addresses = HashSet::new();
-        addresses.insert(IpAddress::from(self.source_peer_ip));
-        for config in self.port_forwards.iter() {
-            addresses.insert(IpAddress::from(config.destination.ip()));
+    /// Adds a client socket connecting to the destination of the port forward. The socket is removed if it fails.
+    fn connect_client_socket(
+        &self,
+        iface: &mut Interface<'static, VirtualIpDevice>,
+        port_forward: &PortForwardConfig,
+        virtual_port: VirtualPort,
+    ) -> anyhow::Result<SocketHandle> {
+        let source_peer_ip = self.source_peer_ip(port_forward)?;
+        let client_handle = iface.add_socket(TcpVirtualInterface::new_client_socket()?);
+        let (client_socket, context) = iface.get_socket_and_context::<TcpSocket>(client_handle);
+        let result = client_socket
+            .connect(
+                context,
+                (
+                    IpAddress::from(port_forward.destination.ip()),
+                    port_forward.destination.port(),
+                ),
+                (IpAddress::from(source_peer_ip), virtual_port.num()),
+            )
+            .with_context(|| "Virtual client socket failed to connect");
+        if result.is_err() {
+            iface.remove_socket(client_handle);
         }
-        addresses
-            .into_iter()
-            .map(|addr| IpCidr::new(addr, 32))
-            .collect()
+        result.map(|_| client_handle)
+    }
+
+    /// The source peer IP of the peer selected by the port forward, of the IP version of its destination.
+    fn source_peer_ip(&self, port_forward: &PortForwardConfig) -> anyhow::Result<IpAddr> {
+        port_forward
+            .select_peer(&self.peers)
+            .with_context(|| format!("Unknown peer for port forward {}", port_forward))?
+            .source_peer_ip(port_forward.destination.ip())
+            .with_context(|| {
+                format!(
+                    "No source peer IP of the IP version of the destination for port forward {}",
+                    port_forward
+                )
+            })
+    }
+
+    fn addresses(&self) -> Vec<IpCidr> {
+        interface_addresses(&self.peers, &self.port_forwards)
     }
 }
 
 #[async_trait]
 impl VirtualInterfacePoll for TcpVirtualInterface {
-    async fn poll_loop(self, device: VirtualIpDevice) -> anyhow::Result<()> {
+    async fn poll_loop(mut self, device: VirtualIpDevice) -> anyhow::Result<()> {
         // Create CIDR block for source peer IP + each port forward IP
         let addresses = self.addresses();
 
         // Create virtual interface (contains smoltcp state machine)
         let mut iface = InterfaceBuilder::new(device, vec![])
             .ip_addrs(addresses)
+            .routes(default_routes(&self.peers)?)
             .finalize();
 
-        // Create virtual server for each port forward
-        for port_forward in self.port_forwards.iter() {
-            let server_socket = TcpVirtualInterface::new_server_socket(*port_forward)?;
-            iface.add_socket(server_socket);
+        // Create virtual server for each port forward, except those with a destination resolved for each connection
+        let mut server_handles: HashMap<PortForwardConfig, SocketHandle> = HashMap::new();
+        for port_forward in self
+            .port_forwards
+            .iter()
+            .filter(|pf| !pf.remote && pf.destination_host.is_none())
+        {
+            let server_socket = TcpVirtualInterface::new_server_socket(port_forward)?;
+            server_handles.insert(port_forward.clone(), iface.add_socket(server_socket));
         }
 
-        // The next time to poll the interface. Can be None for instant poll.
-        let mut next_poll: Option<tokio::time::Instant> = None;
+        // Create the listening sockets of each remote port forward
+        let mut remote_listeners: Vec<(PortForwardConfig, SocketHandle)> = Vec::new();
+        for port_forward in self.port_forwards.iter().filter(|pf| pf.remote) {
+            for _ in 0..REMOTE_BACKLOG {
+                let server_socket = TcpVirtualInterface::new_remote_server_socket(port_forward)?;
+                remote_listeners.push((port_forward.clone(), iface.add_socket(server_socket)));
+            }
+        }
+
+        // Accepted sockets that were aborted, removed once their RST is sent
+        let mut aborted: Vec<SocketHandle> = Vec::new();
+
+        // The channel of the proxy server of each remote port forward, for the connections it accepts
+        let mut remote_proxies: HashMap<PortForwardConfig, RemoteConnectionSender> = HashMap::new();
+
+        // The next time to poll the interface. Can be None to wait for a packet or another event.
+        let mut next_poll: Option<tokio::time::Instant> = Some(tokio::time::Instant::now());
 
         // Bus endpoint to read events
         let mut endpoint = self.bus.new_endpoint();
@@ -104,24 +205,67 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
         // Maps virtual port to its client socket handle
         let mut port_client_handle_map: HashMap<VirtualPort, SocketHandle> = HashMap::new();
 
+        // Maps virtual port to its port forward, and the channel of the data received from the virtual server
+        let mut connections: HashMap<VirtualPort, (PortForwardConfig, RemoteDataSender)> =
+            HashMap::new();
+
+        // Maps virtual port to an accepted remote connection, until the real connection is initiated
+        let mut remote_pending_handle_map: HashMap<VirtualPort, SocketHandle> = HashMap::new();
+
         // Data packets to send from a virtual client
         let mut send_queue: HashMap<VirtualPort, VecDeque<Bytes>> = HashMap::new();
 
+        // The send credit of the connections, given back as their queued data is sent
+        let mut send_credits: HashMap<VirtualPort, SendCredit> = HashMap::new();
+
+        // Virtual ports of the client sockets to close once their queued data is sent
+        let mut closing: HashSet<VirtualPort> = HashSet::new();
+
+        // Virtual ports with data left in their socket, because their channel was full
+        let mut blocked: HashSet<VirtualPort> = HashSet::new();
+
+        // Virtual ports of the client sockets connecting to their destination
+        let mut connecting: HashSet<VirtualPort> = HashSet::new();
+
+        // Buffers of the data received from the virtual servers
+        let mut buffers = BufferPool::default();
+
+        // Once shutting down, the interface stops when all the client sockets are closed
+        let mut shutting_down = false;
+
         loop {
             tokio::select! {
-                _ = match (next_poll, port_client_handle_map.len()) {
-                    (None, 0) => tokio::time::sleep(Duration::MAX),
-                    (None, _) => tokio::time::sleep(Duration::ZERO),
-                    (Some(until), _) => tokio::time::sleep_until(until),
-                } => {
+                _ = wait_poll(next_poll) => {
                     let loop_start = smoltcp::time::Instant::now();
 
+                    match iface.poll(loop_start) {
+                        Ok(processed) if processed => {
+                            trace!("TCP virtual interface polled some packets to be processed");
+                        }
+                        Err(e) => error!("TCP virtual interface poll error: {:?}", e),
+                        _ => {}
+                    }
+
+                    // The RST of the sockets aborted before was sent by the poll
+                    for handle in aborted.drain(..) {
+                        iface.remove_socket(handle);
+                    }
+
                     // Find closed sockets
                     port_client_handle_map.retain(|virtual_port, client_handle| {
                         let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
-                        if client_socket.state() == TcpState::Closed {
-                            endpoint.send(Event::ClientConnectionDropped(*virtual_port));
+                        // When shutting down, there is no need to wait for TIME-WAIT to end
+                        if client_socket.state() == TcpState::Closed
+                            || (shutting_down && client_socket.state() == TcpState::TimeWait)
+                        {
+                            // Dropping the channel notifies the local server, after the data already sent
+                            connections.remove(virtual_port);
+                            self.traffic.connection_closed(*virtual_port);
                             send_queue.remove(virtual_port);
+                            send_credits.remove(virtual_port);
+                            closing.remove(virtual_port);
+                            blocked.remove(virtual_port);
+                            connecting.remove(virtual_port);
                             iface.remove_socket(*client_handle);
                             false
                         } else {
@@ -130,52 +274,130 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
                         }
                     });
 
-                    match iface.poll(loop_start) {
-                        Ok(processed) if processed => {
-                            trace!("TCP virtual interface polled some packets to be processed");
+                    if shutting_down && port_client_handle_map.is_empty() {
+                        debug!("TCP virtual interface closed all connections");
+                        return Ok(());
+                    }
+
+                    // Find remote connections that were accepted by a listening socket
+                    for (port_forward, listener_handle) in remote_listeners.iter_mut() {
+                        let listener_socket = iface.get_socket::<TcpSocket>(*listener_handle);
+                        if matches!(listener_socket.state(), TcpState::Listen | TcpState::SynReceived) {
+                            continue;
+                        }
+
+                        let remote_endpoint = listener_socket.remote_endpoint();
+                        let client_addr = SocketAddr::new(remote_endpoint.addr.into(), remote_endpoint.port);
+                        // The server of the port forward connects to the real destination
+                        let accepted = match remote_proxies.get(port_forward).map(|server| server.try_reserve()) {
+                            Some(Ok(permit)) => match self.port_pool.next(client_addr).await {
+                                Ok(virtual_port) => Some((permit, virtual_port)),
+                                Err(e) => {
+                                    error!(
+                                        "Failed to assign virtual port number for remote connection [{}]: {:?}",
+                                        client_addr, e
+                                    );
+                                    None
+                                }
+                            },
+                            _ => {
+                                warn!("Rejecting remote connection from {}, since the server of port forward {} is not ready", client_addr, port_forward);
+                                None
+                            }
+                        };
+
+                        // The accepted socket becomes the connection, or is reset; a new socket listens in its place
+                        let accepted_handle = *listener_handle;
+                        let server_socket = TcpVirtualInterface::new_remote_server_socket(port_forward)?;
+                        *listener_handle = iface.add_socket(server_socket);
+                        match accepted {
+                            Some((permit, virtual_port)) => {
+                                info!("[{}] Incoming remote connection from {}", virtual_port, client_addr);
+                                remote_pending_handle_map.insert(virtual_port, accepted_handle);
+                                self.traffic.connection_opened(port_forward, virtual_port);
+                                permit.send(virtual_port);
+                            }
+                            None => {
+                                // Listening again right away would drop the RST before it is sent.
+                                // It is sent by the next poll, which is right away.
+                                iface.get_socket::<TcpSocket>(accepted_handle).abort();
+                                aborted.push(accepted_handle);
+                            }
                         }
-                        Err(e) => error!("TCP virtual interface poll error: {:?}", e),
-                        _ => {}
                     }
 
                     for (virtual_port, client_handle) in port_client_handle_map.iter() {
                         let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
-                        if client_socket.can_send() {
-                            if let Some(send_queue) = send_queue.get_mut(virtual_port) {
-                                let to_transfer = send_queue.pop_front();
-                                if let Some(to_transfer_slice) = to_transfer.as_deref() {
-                                    let total = to_transfer_slice.len();
-                                    match client_socket.send_slice(to_transfer_slice) {
-                                        Ok(sent) => {
-                                            if sent < total {
-                                                // Sometimes only a subset is sent, so the rest needs to be sent on the next poll
-                                                let tx_extra = Vec::from(&to_transfer_slice[sent..total]);
-                                                send_queue.push_front(tx_extra.into());
-                                            }
+                        if client_socket.state() != TcpState::SynSent && connecting.remove(virtual_port) {
+                            // Nothing was sent on the channel before, so it has room for the message
+                            if let Some((_, sender)) = connections.get(virtual_port) {
+                                let _ = sender.try_send((*virtual_port, RemoteMessage::Established));
+                            }
+                        }
+                        if let Some(send_queue) = send_queue.get_mut(virtual_port) {
+                            // Send as much queued data as the buffer of the socket can take
+                            while client_socket.can_send() {
+                                let to_transfer = match send_queue.pop_front() {
+                                    Some(to_transfer) => to_transfer,
+                                    None => break,
+                                };
+                                let total = to_transfer.len();
+                                match client_socket.send_slice(&to_transfer) {
+                                    Ok(sent) => {
+                                        // The local server can send more data for the connection
+                                        if let Some(credit) = send_credits.get(virtual_port) {
+                                            credit.add_permits(sent);
                                         }
-                                        Err(e) => {
-                                            error!(
-                                                "Failed to send slice via virtual client socket: {:?}", e
-                                            );
+                                        if sent < total {
+                                            // Sometimes only a subset is sent, so the rest needs to be sent on the next poll
+                                            send_queue.push_front(to_transfer.slice(sent..total));
+                                            break;
                                         }
                                     }
-                                } else if client_socket.state() == TcpState::CloseWait {
-                                    client_socket.close();
+                                    Err(e) => {
+                                        error!(
+                                            "Failed to send slice via virtual client socket: {:?}", e
+                                        );
+                                        break;
+                                    }
                                 }
                             }
+                            if send_queue.is_empty()
+                                && client_socket.may_send()
+                                && (client_socket.state() == TcpState::CloseWait || closing.contains(virtual_port))
+                            {
+                                client_socket.close();
+                            }
                         }
-                        if client_socket.can_recv() {
-                            match client_socket.recv(|buffer| (buffer.len(), Bytes::from(buffer.to_vec()))) {
+                        // Receive until the socket is empty, since it is not polled again until the next packet
+                        while client_socket.can_recv() {
+                            let (port_forward, sender) = match connections.get(virtual_port) {
+                                Some(connection) => connection,
+                                None => break,
+                            };
+                            // The data is left in the socket until the local server has room for it
+                            let permit = match sender.try_reserve() {
+                                Ok(permit) => permit,
+                                Err(mpsc::error::TrySendError::Full(_)) => {
+                                    blocked.insert(*virtual_port);
+                                    break;
+                                }
+                                // The local server stopped, the connection is being closed
+                                Err(mpsc::error::TrySendError::Closed(_)) => break,
+                            };
+                            match client_socket.recv(|buffer| (buffer.len(), buffers.copy_from_slice(buffer))) {
                                 Ok(data) => {
                                     debug!("[{}] Received {} bytes from virtual server", virtual_port, data.len());
                                     if !data.is_empty() {
-                                        endpoint.send(Event::RemoteData(*virtual_port, data));
+                                        self.traffic.inbound(port_forward, data.len());
+                                        permit.send((*virtual_port, RemoteMessage::Data(data)));
                                     }
                                 }
                                 Err(e) => {
                                     error!(
                                         "Failed to read from virtual client socket: {:?}", e
                                     );
+                                    break;
                                 }
                             }
                         }
@@ -183,7 +405,7 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
 
                     // The virtual interface determines the next time to poll (this is to reduce unnecessary polls)
                     next_poll = match iface.poll_delay(loop_start) {
-                        Some(smoltcp::time::Duration::ZERO) => None,
+                        Some(smoltcp::time::Duration::ZERO) => Some(tokio::time::Instant::now()),
                         Some(delay) => {
                             trace!("TCP Virtual interface delayed next poll by {}", delay);
                             Some(tokio::time::Instant::now() + Duration::from_millis(delay.total_millis()))
@@ -191,46 +413,161 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
                         None => None,
                     };
                 }
-                event = endpoint.recv() => {
-                    match event {
-                        Event::ClientConnectionInitiated(port_forward, virtual_port) => {
-                            let client_socket = TcpVirtualInterface::new_client_socket()?;
-                            let client_handle = iface.add_socket(client_socket);
+                _ = iface.device_mut().recv_packet() => {
+                    // Poll even without client sockets, since remote port forwards may be listening
+                    next_poll = Some(tokio::time::Instant::now());
+                }
+                _ = any_capacity(
+                    blocked.iter().filter_map(|virtual_port| connections.get(virtual_port)).map(|(_, sender)| sender.clone()).collect()
+                ), if !blocked.is_empty() => {
+                    // The local servers have room for the data left in the sockets
+                    blocked.clear();
+                    next_poll = Some(tokio::time::Instant::now());
+                }
+                Some(message) = self.messages.recv() => {
+                    match message {
+                        LocalMessage::Connect(port_forward, virtual_port, sender, credit) if port_forward.remote => {
+                            // The real connection for the accepted remote connection is ready
+                            if let Some(client_handle) = remote_pending_handle_map.remove(&virtual_port) {
+                                port_client_handle_map.insert(virtual_port, client_handle);
+                                connections.insert(virtual_port, (port_forward, sender));
+                                send_queue.insert(virtual_port, VecDeque::new());
+                                if let Some(credit) = credit {
+                                    send_credits.insert(virtual_port, credit);
+                                }
+                                next_poll = Some(tokio::time::Instant::now());
+                            }
+                        }
+                        LocalMessage::Connect(port_forward, virtual_port, sender, credit) => {
+                            let client_handle = match self.connect_client_socket(&mut iface, &port_forward, virtual_port) {
+                                Ok(client_handle) => client_handle,
+                                Err(e) => {
+                                    // Dropping the channel closes the local connection; the interface keeps running
+                                    error!("[{}] Failed to connect to {}: {:?}", virtual_port, port_forward.destination, e);
+                                    continue;
+                                }
+                            };
 
                             // Add handle to map
                             port_client_handle_map.insert(virtual_port, client_handle);
                             send_queue.insert(virtual_port, VecDeque::new());
+                            if let Some(credit) = credit {
+                                send_credits.insert(virtual_port, credit);
+                            }
+                            self.traffic.connection_opened(&port_forward, virtual_port);
+                            connections.insert(virtual_port, (port_forward, sender));
+                            connecting.insert(virtual_port);
 
-                            let (client_socket, context) = iface.get_socket_and_context::<TcpSocket>(client_handle);
-
-                            client_socket
-                                .connect(
-                                    context,
-                                    (
-                                        IpAddress::from(port_forward.destination.ip()),
-                                        port_forward.destination.port(),
-                                    ),
-                                    (IpAddress::from(self.source_peer_ip), virtual_port.num()),
-                                )
-                                .with_context(|| "Virtual server socket failed to listen")?;
-
-                            next_poll = None;
+                            next_poll = Some(tokio::time::Instant::now());
                         }
-                        Event::ClientConnectionDropped(virtual_port) => {
-                            if let Some(client_handle) = port_client_handle_map.get(&virtual_port) {
-                                let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
+                        LocalMessage::Data(virtual_port, data) => {
+                            if let (Some(send_queue), Some((port_forward, _))) = (send_queue.get_mut(&virtual_port), connections.get(&virtual_port)) {
+                                self.traffic.outbound(port_forward, data.len());
+                                send_queue.push_back(data);
+                                next_poll = Some(tokio::time::Instant::now());
+                            }
+                        }
+                        LocalMessage::Close(virtual_port) => {
+                            if let Some(client_handle) = remote_pending_handle_map.remove(&virtual_port) {
+                                // The real connection could not be established
+                                let client_socket = iface.get_socket::<TcpSocket>(client_handle);
                                 client_socket.close();
-                                next_poll = None;
+                                port_client_handle_map.insert(virtual_port, client_handle);
+                                next_poll = Some(tokio::time::Instant::now());
+                            } else if let Some(client_handle) = port_client_handle_map.get(&virtual_port) {
+                                // Dropping the channel stops the local server, if it is still running (e.g. when
+                                // closed from the control API). The data received before is sent first.
+                                connections.remove(&virtual_port);
+                                let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
+                                let queued = send_queue.get(&virtual_port).is_some_and(|queue| !queue.is_empty());
+                                if queued && client_socket.may_send() {
+                                    closing.insert(virtual_port);
+                                } else {
+                                    client_socket.close();
+                                }
+                                next_poll = Some(tokio::time::Instant::now());
                             }
                         }
-                        Event::LocalData(_, virtual_port, data) if send_queue.contains_key(&virtual_port) => {
-                            if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
-                                send_queue.push_back(data);
-                                next_poll = None;
+                        LocalMessage::Listen(port_forward, sender) => {
+                            remote_proxies.insert(port_forward, sender);
+                        }
+                    }
+                }
+                event = endpoint.recv() => {
+                    match event {
+                        Event::PortForwardAdded(port_forward)
+                            if port_forward.protocol == PortProtocol::Tcp && !self.port_forwards.contains(&port_forward) =>
+                        {
+                            let result = if port_forward.remote {
+                                (0..REMOTE_BACKLOG).try_for_each(|_| {
+                                    TcpVirtualInterface::new_remote_server_socket(&port_forward)
+                                        .map(|socket| remote_listeners.push((port_forward.clone(), iface.add_socket(socket))))
+                                })
+                            } else if port_forward.destination_host.is_none() {
+                                TcpVirtualInterface::new_server_socket(&port_forward)
+                                    .map(|socket| server_handles.insert(port_forward.clone(), iface.add_socket(socket)))
+                                    .map(|_| ())
+                            } else {
+                                Ok(())
+                            };
+                            match result {
+                                Ok(()) => {
+                                    debug!("Added port forward {} to the TCP virtual interface", port_forward);
+                                    self.port_forwards.push(port_forward);
+                                    let addresses = self.addresses();
+                                    iface.update_ip_addrs(|addrs| *addrs = addresses.into());
+                                    next_poll = Some(tokio::time::Instant::now());
+                                }
+                                Err(e) => error!("Failed to add port forward {} to the TCP virtual interface: {:?}", port_forward, e),
                             }
                         }
-                        Event::VirtualDeviceFed(protocol) if protocol == PortProtocol::Tcp => {
-                            next_poll = None;
+                        Event::PortForwardRemoved(port_forward) if port_forward.protocol == PortProtocol::Tcp => {
+                            // Connections already accepted or initiated have their own sockets, which stay open
+                            self.port_forwards.retain(|pf| pf != &port_forward);
+                            remote_proxies.remove(&port_forward);
+                            if let Some(server_handle) = server_handles.remove(&port_forward) {
+                                iface.remove_socket(server_handle);
+                            }
+                            remote_listeners.retain(|(pf, listener_handle)| {
+                                if pf == &port_forward {
+                                    iface.remove_socket(*listener_handle);
+                                    false
+                                } else {
+                                    true
+                                }
+                            });
+                            debug!("Removed port forward {} from the TCP virtual interface", port_forward);
+                            let addresses = self.addresses();
+                            iface.update_ip_addrs(|addrs| *addrs = addresses.into());
+                            next_poll = Some(tokio::time::Instant::now());
+                        }
+                        Event::Shutdown => {
+                            // Stop listening, and close the connections with FIN once their queued data is sent
+                            shutting_down = true;
+                            for server_handle in server_handles.drain().map(|(_, handle)| handle) {
+                                iface.remove_socket(server_handle);
+                            }
+                            for (_, listener_handle) in remote_listeners.drain(..) {
+                                iface.remove_socket(listener_handle);
+                            }
+                            remote_proxies.clear();
+                            for (virtual_port, client_handle) in remote_pending_handle_map.drain() {
+                                iface.get_socket::<TcpSocket>(client_handle).abort();
+                                port_client_handle_map.insert(virtual_port, client_handle);
+                            }
+                            for (virtual_port, client_handle) in port_client_handle_map.iter() {
+                                let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
+                                if client_socket.may_send() {
+                                    closing.insert(*virtual_port);
+                                } else {
+                                    client_socket.close();
+                                }
+                            }
+                            if port_client_handle_map.is_empty() {
+                                debug!("TCP virtual interface closed all connections");
+                                return Ok(());
+                            }
+                            next_poll = Some(tokio::time::Instant::now());
                         }
                         _ => {}
                     }
@@ -1,5 +1,7 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::net::IpAddr;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
@@ -7,37 +9,91 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use smoltcp::iface::{InterfaceBuilder, SocketHandle};
 use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
-use smoltcp::wire::{IpAddress, IpCidr};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint};
+use tokio::sync::mpsc;
 
-use crate::config::PortForwardConfig;
-use crate::events::Event;
+use crate::buffer::BufferPool;
+use crate::config::{PeerConfig, PortForwardConfig};
+use crate::events::{Event, LocalMessage, RemoteConnectionSender, RemoteDataSender, RemoteMessage};
+use crate::metrics::Traffic;
+use crate::tunnel::udp::UdpPortPool;
 use crate::virtual_device::VirtualIpDevice;
-use crate::virtual_iface::{VirtualInterfacePoll, VirtualPort};
+use crate::virtual_iface::{
+    default_routes, interface_addresses, wait_poll, VirtualInterfacePoll, VirtualPort,
+};
 use crate::{Bus, PortProtocol};
 
 const MAX_PACKET: usize = 65536;
 
+/// How many datagrams the buffers of a virtual socket hold. A burst of packets is processed in a single poll of the
+/// interface, before the socket is read, so this is enough for the bursts of small datagrams to fill the buffers.
+const MAX_SOCKET_DATAGRAMS: usize = 128;
+
+/// How many datagrams of a new remote flow are kept until the real destination is ready.
+const MAX_PENDING_DATAGRAMS: usize = 10;
+
 pub struct UdpVirtualInterface {
-    source_peer_ip: IpAddr,
+    peers: Vec<PeerConfig>,
     port_forwards: Vec<PortForwardConfig>,
     bus: Bus,
+    /// The messages of the local servers, registered on the bus.
+    messages: mpsc::Receiver<LocalMessage>,
+    port_pool: UdpPortPool,
+    traffic: Arc<Traffic>,
 }
 
 impl UdpVirtualInterface {
-    /// Initialize the parameters for a new virtual interface.
+    /// Initialize the parameters for a new virtual interface, and register its channel on the bus.
     /// Use the `poll_loop()` future to start the virtual interface poll loop.
-    pub fn new(port_forwards: Vec<PortForwardConfig>, bus: Bus, source_peer_ip: IpAddr) -> Self {
+    pub fn new(
+        port_forwards: Vec<PortForwardConfig>,
+        bus: Bus,
+        peers: Vec<PeerConfig>,
+        port_pool: UdpPortPool,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        let messages = bus.register_interface(PortProtocol::Udp);
         Self {
             port_forwards: port_forwards
                 .into_iter()
                 .filter(|f| matches!(f.protocol, PortProtocol::Udp))
                 .collect(),
-            source_peer_ip,
+            peers,
             bus,
+            messages,
+            port_pool,
+            traffic,
         }
     }
 
-    fn new_server_socket(port_forward: PortForwardConfig) -> anyhow::Result<UdpSocket<'static>> {
+    /// Sends a datagram received by the virtual interface to the local server of its flow.
+    /// Like a full socket buffer, the datagram is dropped if the channel of the flow is full.
+    fn send_to_local(
+        &self,
+        connections: &mut HashMap<VirtualPort, (PortForwardConfig, RemoteDataSender)>,
+        virtual_port: VirtualPort,
+        data: Bytes,
+    ) {
+        let (port_forward, sender) = match connections.get(&virtual_port) {
+            Some(connection) => connection,
+            None => return,
+        };
+        self.traffic.inbound(port_forward, data.len());
+        match sender.try_send((virtual_port, RemoteMessage::Data(data))) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!(
+                    "[{}] Dropped datagram for local server (queue full)",
+                    virtual_port
+                );
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                connections.remove(&virtual_port);
+            }
+        }
+    }
+
+    fn new_server_socket(port_forward: &PortForwardConfig) -> anyhow::Result<UdpSocket<'static>> {
         static mut UDP_SERVER_RX_META: [UdpPacketMetadata; 0] = [];
         static mut UDP_SERVER_RX_DATA: [u8; 0] = [];
         static mut UDP_SERVER_TX_META: [UdpPacketMetadata; 0] = [];
@@ -58,12 +114,33 @@ impl UdpVirtualInterface {
         Ok(socket)
     }
 
+    /// Creates a socket bound on the virtual interface for a remote port forward.
+    /// Unlike the virtual server sockets, this socket receives datagrams from other peers, so it needs real buffers.
+    fn new_remote_server_socket(
+        port_forward: &PortForwardConfig,
+    ) -> anyhow::Result<UdpSocket<'static>> {
+        let rx_meta = vec![UdpPacketMetadata::EMPTY; MAX_SOCKET_DATAGRAMS];
+        let tx_meta = vec![UdpPacketMetadata::EMPTY; MAX_SOCKET_DATAGRAMS];
+        let rx_data = vec![0u8; MAX_PACKET];
+        let tx_data = vec![0u8; MAX_PACKET];
+        let udp_rx_buffer = UdpSocketBuffer::new(rx_meta, rx_data);
+        let udp_tx_buffer = UdpSocketBuffer::new(tx_meta, tx_data);
+        let mut socket = UdpSocket::new(udp_rx_buffer, udp_tx_buffer);
+        socket
+            .bind((
+                IpAddress::from(port_forward.source.ip()),
+                port_forward.source.port(),
+            ))
+            .with_context(|| "UDP virtual remote server socket failed to bind")?;
+        Ok(socket)
+    }
+
     fn new_client_socket(
         source_peer_ip: IpAddr,
         client_port: VirtualPort,
     ) -> anyhow::Result<UdpSocket<'static>> {
-        let rx_meta = vec![UdpPacketMetadata::EMPTY; 10];
-        let tx_meta = vec![UdpPacketMetadata::EMPTY; 10];
+        let rx_meta = vec![UdpPacketMetadata::EMPTY; MAX_SOCKET_DATAGRAMS];
+        let tx_meta = vec![UdpPacketMetadata::EMPTY; MAX_SOCKET_DATAGRAMS];
         let rx_data = vec![0u8; MAX_PACKET];
         let tx_data = vec![0u8; MAX_PACKET];
         let udp_rx_buffer = UdpSocketBuffer::new(rx_meta, rx_data);
@@ -75,38 +152,60 @@ impl UdpVirtualInterface {
         Ok(socket)
     }
 
+    /// The source peer IP of the peer selected by the port forward, of the IP version of its destination.
+    fn source_peer_ip(&self, port_forward: &PortForwardConfig) -> anyhow::Result<IpAddr> {
+        port_forward
+            .select_peer(&self.peers)
+            .with_context(|| format!("Unknown peer for port forward {}", port_forward))?
+            .source_peer_ip(port_forward.destination.ip())
+            .with_context(|| {
+                format!(
+                    "No source peer IP of the IP version of the destination for port forward {}",
+                    port_forward
+                )
+            })
+    }
+
     fn addresses(&self) -> Vec<IpCidr> {
-        let mut addresses = HashSet::new();
-        addresses.insert(IpAddress::from(self.source_peer_ip));
-        for config in self.port_forwards.iter() {
-            addresses.insert(IpAddress::from(config.destination.ip()));
-        }
-        addresses
-            .into_iter()
-            .map(|addr| IpCidr::new(addr, 32))
-            .collect()
+        interface_addresses(&self.peers, &self.port_forwards)
     }
 }
 
 #[async_trait]
 impl VirtualInterfacePoll for UdpVirtualInterface {
-    async fn poll_loop(self, device: VirtualIpDevice) -> anyhow::Result<()> {
+    async fn poll_loop(mut self, device: VirtualIpDevice) -> anyhow::Result<()> {
         // Create CIDR block for source peer IP + each port forward IP
         let addresses = self.addresses();
 
         // Create virtual interface (contains smoltcp state machine)
         let mut iface = InterfaceBuilder::new(device, vec![])
             .ip_addrs(addresses)
+            .routes(default_routes(&self.peers)?)
             .finalize();
 
-        // Create virtual server for each port forward
-        for port_forward in self.port_forwards.iter() {
-            let server_socket = UdpVirtualInterface::new_server_socket(*port_forward)?;
-            iface.add_socket(server_socket);
+        // Create virtual server for each port forward, except those with a destination resolved for each connection
+        let mut server_handles: HashMap<PortForwardConfig, SocketHandle> = HashMap::new();
+        for port_forward in self
+            .port_forwards
+            .iter()
+            .filter(|pf| !pf.remote && pf.destination_host.is_none())
+        {
+            let server_socket = UdpVirtualInterface::new_server_socket(port_forward)?;
+            server_handles.insert(port_forward.clone(), iface.add_socket(server_socket));
+        }
+
+        // Create bound socket for each remote port forward
+        let mut remote_servers: Vec<(PortForwardConfig, SocketHandle)> = Vec::new();
+        for port_forward in self.port_forwards.iter().filter(|pf| pf.remote) {
+            let server_socket = UdpVirtualInterface::new_remote_server_socket(port_forward)?;
+            remote_servers.push((port_forward.clone(), iface.add_socket(server_socket)));
         }
 
-        // The next time to poll the interface. Can be None for instant poll.
-        let mut next_poll: Option<tokio::time::Instant> = None;
+        // The channel of the proxy server of each remote port forward, for the flows it receives
+        let mut remote_proxies: HashMap<PortForwardConfig, RemoteConnectionSender> = HashMap::new();
+
+        // The next time to poll the interface. Can be None to wait for a packet or another event.
+        let mut next_poll: Option<tokio::time::Instant> = Some(tokio::time::Instant::now());
 
         // Bus endpoint to read events
         let mut endpoint = self.bus.new_endpoint();
@@ -114,17 +213,25 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
         // Maps virtual port to its client socket handle
         let mut port_client_handle_map: HashMap<VirtualPort, SocketHandle> = HashMap::new();
 
-        // Data packets to send from a virtual client
-        let mut send_queue: HashMap<VirtualPort, VecDeque<(PortForwardConfig, Bytes)>> =
+        // Maps virtual port to the remote server socket handle and the peer that sent datagrams to it
+        let mut remote_flow_map: HashMap<VirtualPort, (SocketHandle, IpEndpoint)> = HashMap::new();
+
+        // Datagrams of the remote flows whose real destination is not ready yet
+        let mut remote_pending: HashMap<VirtualPort, Vec<Bytes>> = HashMap::new();
+
+        // Maps virtual port to its port forward, and the channel of the datagrams received for the flow
+        let mut connections: HashMap<VirtualPort, (PortForwardConfig, RemoteDataSender)> =
             HashMap::new();
 
+        // Data packets to send from a virtual client, or back to the peer of a remote flow
+        let mut send_queue: HashMap<VirtualPort, VecDeque<Bytes>> = HashMap::new();
+
+        // Buffers of the datagrams received from the virtual sockets
+        let mut buffers = BufferPool::default();
+
         loop {
             tokio::select! {
-                _ = match (next_poll, port_client_handle_map.len()) {
-                    (None, 0) => tokio::time::sleep(Duration::MAX),
-                    (None, _) => tokio::time::sleep(Duration::ZERO),
-                    (Some(until), _) => tokio::time::sleep_until(until),
-                } => {
+                _ = wait_poll(next_poll) => {
                     let loop_start = smoltcp::time::Instant::now();
 
                     match iface.poll(loop_start) {
@@ -135,37 +242,117 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
                         _ => {}
                     }
 
+                    // Dispatch datagrams received by remote port forwards
+                    for (port_forward, server_handle) in remote_servers.iter() {
+                        loop {
+                            let server_socket = iface.get_socket::<UdpSocket>(*server_handle);
+                            if !server_socket.can_recv() {
+                                break;
+                            }
+                            let (data, peer) = match server_socket.recv() {
+                                Ok((data, peer)) => (buffers.copy_from_slice(data), peer),
+                                Err(e) => {
+                                    error!("Failed to read from virtual remote server socket: {:?}", e);
+                                    break;
+                                }
+                            };
+
+                            let peer_addr = SocketAddr::new(IpAddr::from(peer.addr), peer.port);
+                            let virtual_port = match self.port_pool.next(peer_addr).await {
+                                Ok(port) => port,
+                                Err(e) => {
+                                    error!(
+                                        "Failed to assign virtual port number for remote UDP datagram from [{}]: {:?}",
+                                        peer_addr, e
+                                    );
+                                    continue;
+                                }
+                            };
+                            self.port_pool.update_last_transmit(virtual_port).await;
+
+                            if let Entry::Vacant(entry) = remote_flow_map.entry(virtual_port) {
+                                // The proxy server of the port forward opens the flow to the real destination
+                                let sent = remote_proxies
+                                    .get(port_forward)
+                                    .map(|proxy| proxy.try_send(virtual_port).is_ok())
+                                    .unwrap_or(false);
+                                if !sent {
+                                    warn!("Dropping remote UDP datagram from {}, since the server of port forward {} is not ready", peer_addr, port_forward);
+                                    self.port_pool.release(virtual_port).await;
+                                    continue;
+                                }
+                                debug!("[{}] Incoming remote UDP flow from {}", virtual_port, peer_addr);
+                                entry.insert((*server_handle, peer));
+                                self.traffic.connection_opened(port_forward, virtual_port);
+                            }
+                            if data.is_empty() {
+                                continue;
+                            }
+                            if connections.contains_key(&virtual_port) {
+                                self.send_to_local(&mut connections, virtual_port, data);
+                            } else {
+                                let pending = remote_pending.entry(virtual_port).or_default();
+                                if pending.len() < MAX_PENDING_DATAGRAMS {
+                                    pending.push(data);
+                                }
+                            }
+                        }
+                    }
+
+                    // Send the datagrams of the remote flows back to their peer, through the server socket
+                    for (virtual_port, (server_handle, peer)) in remote_flow_map.iter() {
+                        if let Some(send_queue) = send_queue.get_mut(virtual_port) {
+                            let server_socket = iface.get_socket::<UdpSocket>(*server_handle);
+                            while server_socket.can_send() {
+                                let data = match send_queue.pop_front() {
+                                    Some(data) => data,
+                                    None => break,
+                                };
+                                server_socket.send_slice(&data, *peer).unwrap_or_else(|e| {
+                                    error!(
+                                        "[{}] Failed to send data to remote peer: {:?}",
+                                        virtual_port, e
+                                    );
+                                });
+                            }
+                        }
+                    }
+
                     for (virtual_port, client_handle) in port_client_handle_map.iter() {
                         let client_socket = iface.get_socket::<UdpSocket>(*client_handle);
-                        if client_socket.can_send() {
-                            if let Some(send_queue) = send_queue.get_mut(virtual_port) {
-                                let to_transfer = send_queue.pop_front();
-                                if let Some((port_forward, data)) = to_transfer {
-                                    client_socket
-                                        .send_slice(
-                                            &data,
-                                            (IpAddress::from(port_forward.destination.ip()), port_forward.destination.port()).into(),
-                                        )
-                                        .unwrap_or_else(|e| {
-                                            error!(
-                                                "[{}] Failed to send data to virtual server: {:?}",
-                                                virtual_port, e
-                                            );
-                                        });
-                                }
+                        // Send and receive until the queue or the socket is empty, since it is not polled again until the next event
+                        if let (Some(send_queue), Some((port_forward, _))) = (send_queue.get_mut(virtual_port), connections.get(virtual_port)) {
+                            while client_socket.can_send() {
+                                let data = match send_queue.pop_front() {
+                                    Some(data) => data,
+                                    None => break,
+                                };
+                                client_socket
+                                    .send_slice(
+                                        &data,
+                                        (IpAddress::from(port_forward.destination.ip()), port_forward.destination.port()).into(),
+                                    )
+                                    .unwrap_or_else(|e| {
+                                        error!(
+                                            "[{}] Failed to send data to virtual server: {:?}",
+                                            virtual_port, e
+                                        );
+                                    });
                             }
                         }
-                        if client_socket.can_recv() {
+                        while client_socket.can_recv() {
                             match client_socket.recv() {
                                 Ok((data, _peer)) => {
                                     if !data.is_empty() {
-                                        endpoint.send(Event::RemoteData(*virtual_port, data.to_vec().into()));
+                                        let data = buffers.copy_from_slice(data);
+                                        self.send_to_local(&mut connections, *virtual_port, data);
                                     }
                                 }
                                 Err(e) => {
                                     error!(
                                         "Failed to read from virtual client socket: {:?}", e
                                     );
+                                    break;
                                 }
                             }
                         }
@@ -173,7 +360,7 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
 
                     // The virtual interface determines the next time to poll (this is to reduce unnecessary polls)
                     next_poll = match iface.poll_delay(loop_start) {
-                        Some(smoltcp::time::Duration::ZERO) => None,
+                        Some(smoltcp::time::Duration::ZERO) => Some(tokio::time::Instant::now()),
                         Some(delay) => {
                             trace!("UDP Virtual interface delayed next poll by {}", delay);
                             Some(tokio::time::Instant::now() + Duration::from_millis(delay.total_millis()))
@@ -181,25 +368,142 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
                         None => None,
                     };
                 }
-                event = endpoint.recv() => {
-                    match event {
-                        Event::LocalData(port_forward, virtual_port, data) => {
-                            if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
-                                // Client socket already exists
-                                send_queue.push_back((port_forward, data));
-                            } else {
-                                // Client socket does not exist
-                                let client_socket = UdpVirtualInterface::new_client_socket(self.source_peer_ip, virtual_port)?;
+                _ = iface.device_mut().recv_packet() => {
+                    // Poll even without client sockets, since remote port forwards may be listening
+                    next_poll = Some(tokio::time::Instant::now());
+                }
+                Some(message) = self.messages.recv() => {
+                    match message {
+                        LocalMessage::Connect(port_forward, virtual_port, sender, _) if port_forward.remote => {
+                            // The real destination of the remote flow is ready
+                            if remote_flow_map.contains_key(&virtual_port) {
+                                connections.insert(virtual_port, (port_forward, sender));
+                                for data in remote_pending.remove(&virtual_port).unwrap_or_default() {
+                                    self.send_to_local(&mut connections, virtual_port, data);
+                                }
+                            }
+                        }
+                        LocalMessage::Connect(port_forward, virtual_port, sender, _) => {
+                            if let Entry::Vacant(entry) = port_client_handle_map.entry(virtual_port) {
+                                let client_socket = match self
+                                    .source_peer_ip(&port_forward)
+                                    .and_then(|source_peer_ip| UdpVirtualInterface::new_client_socket(source_peer_ip, virtual_port))
+                                {
+                                    Ok(client_socket) => client_socket,
+                                    Err(e) => {
+                                        // Dropping the channel ends the flow in its local server; the interface keeps running
+                                        error!("[{}] Failed to open a flow to {}: {:?}", virtual_port, port_forward.destination, e);
+                                        continue;
+                                    }
+                                };
                                 let client_handle = iface.add_socket(client_socket);
 
                                 // Add handle to map
-                                port_client_handle_map.insert(virtual_port, client_handle);
-                                send_queue.insert(virtual_port, VecDeque::from(vec![(port_forward, data)]));
+                                entry.insert(client_handle);
+                                send_queue.insert(virtual_port, VecDeque::new());
                             }
-                            next_poll = None;
+                            // A virtual port of an idle flow can be reused by another local server
+                            self.traffic.connection_opened(&port_forward, virtual_port);
+                            connections.insert(virtual_port, (port_forward, sender));
                         }
-                        Event::VirtualDeviceFed(protocol) if protocol == PortProtocol::Udp => {
-                            next_poll = None;
+                        LocalMessage::Data(virtual_port, data) => {
+                            let port_forward = match connections.get(&virtual_port) {
+                                Some((port_forward, _)) => port_forward,
+                                None => continue,
+                            };
+                            self.traffic.outbound(port_forward, data.len());
+                            if remote_flow_map.contains_key(&virtual_port) {
+                                send_queue.entry(virtual_port).or_default().push_back(data);
+                                next_poll = Some(tokio::time::Instant::now());
+                            } else if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
+                                send_queue.push_back(data);
+                                next_poll = Some(tokio::time::Instant::now());
+                            }
+                        }
+                        LocalMessage::Close(virtual_port) => {
+                            remote_flow_map.remove(&virtual_port);
+                            if let Some(client_handle) = port_client_handle_map.remove(&virtual_port) {
+                                iface.remove_socket(client_handle);
+                            }
+                            send_queue.remove(&virtual_port);
+                            remote_pending.remove(&virtual_port);
+                            if let Some((_, sender)) = connections.remove(&virtual_port) {
+                                self.traffic.connection_closed(virtual_port);
+                                // The channel may be shared with other flows, so the local server is told to forget the flow
+                                if let Err(mpsc::error::TrySendError::Full(message)) = sender.try_send((virtual_port, RemoteMessage::Closed)) {
+                                    tokio::spawn(async move { sender.send(message).await });
+                                }
+                            }
+                        }
+                        LocalMessage::Listen(port_forward, sender) => {
+                            remote_proxies.insert(port_forward, sender);
+                        }
+                    }
+                }
+                event = endpoint.recv() => {
+                    match event {
+                        Event::PortForwardAdded(port_forward)
+                            if port_forward.protocol == PortProtocol::Udp && !self.port_forwards.contains(&port_forward) =>
+                        {
+                            let result = if port_forward.remote {
+                                UdpVirtualInterface::new_remote_server_socket(&port_forward)
+                                    .map(|socket| remote_servers.push((port_forward.clone(), iface.add_socket(socket))))
+                            } else if port_forward.destination_host.is_none() {
+                                UdpVirtualInterface::new_server_socket(&port_forward)
+                                    .map(|socket| server_handles.insert(port_forward.clone(), iface.add_socket(socket)))
+                                    .map(|_| ())
+                            } else {
+                                Ok(())
+                            };
+                            match result {
+                                Ok(()) => {
+                                    debug!("Added port forward {} to the UDP virtual interface", port_forward);
+                                    self.port_forwards.push(port_forward);
+                                    let addresses = self.addresses();
+                                    iface.update_ip_addrs(|addrs| *addrs = addresses.into());
+                                    next_poll = Some(tokio::time::Instant::now());
+                                }
+                                Err(e) => error!("Failed to add port forward {} to the UDP virtual interface: {:?}", port_forward, e),
+                            }
+                        }
+                        Event::PortForwardRemoved(port_forward) if port_forward.protocol == PortProtocol::Udp => {
+                            self.port_forwards.retain(|pf| pf != &port_forward);
+                            remote_proxies.remove(&port_forward);
+                            if let Some(server_handle) = server_handles.remove(&port_forward) {
+                                iface.remove_socket(server_handle);
+                            }
+                            remote_servers.retain(|(pf, server_handle)| {
+                                if pf != &port_forward {
+                                    return true;
+                                }
+                                // The remote flows reply through the server socket, so they end with it
+                                remote_flow_map.retain(|virtual_port, (flow_server_handle, _)| {
+                                    if flow_server_handle == server_handle {
+                                        // Dropping the channel ends the remote flow in its local server
+                                        remote_pending.remove(virtual_port);
+                                        send_queue.remove(virtual_port);
+                                        connections.remove(virtual_port);
+                                        self.traffic.connection_closed(*virtual_port);
+                                        false
+                                    } else {
+                                        true
+                                    }
+                                });
+                                iface.remove_socket(*server_handle);
+                                false
+                            });
+                            debug!("Removed port forward {} from the UDP virtual interface", port_forward);
+                            let addresses = self.addresses();
+                            iface.update_ip_addrs(|addrs| *addrs = addresses.into());
+                            next_poll = Some(tokio::time::Instant::now());
+                        }
+                        Event::Shutdown => {
+                            // UDP has no connection to close, only send the datagrams already queued
+                            if let Err(e) = iface.poll(smoltcp::time::Instant::now()) {
+                                error!("UDP virtual interface poll error: {:?}", e);
+                            }
+                            debug!("UDP virtual interface stopped");
+                            return Ok(());
                         }
                         _ => {}
                     }
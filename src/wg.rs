@@ -1,72 +1,460 @@
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::Bus;
 use anyhow::Context;
 use async_recursion::async_recursion;
+use boringtun::crypto::{X25519PublicKey, X25519SecretKey};
 use boringtun::noise::errors::WireGuardError;
-use boringtun::noise::{Tunn, TunnResult};
+use boringtun::noise::handshake::parse_handshake_anon;
+use boringtun::noise::{Packet, Tunn, TunnResult};
+use bytes::Bytes;
 use log::Level;
 use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::MissedTickBehavior;
 
-use crate::config::{Config, PortProtocol};
-use crate::events::Event;
+use crate::buffer::BufferPool;
+use crate::config::{can_reach, is_dual_stack, Config, PeerConfig, PortProtocol};
 
-/// The capacity of the channel for received IP packets.
+/// The capacity of the channels for received and sent IP packets.
 pub const DISPATCH_CAPACITY: usize = 1_000;
 const MAX_PACKET: usize = 65536;
+/// The interval between the updates of the WireGuard timers (handshake retries and expiry, keep-alives).
+const TIMER_TICK: Duration = Duration::from_millis(250);
+/// The interval between the checks for unreachable peers, whose endpoint host name is resolved again.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+/// The time without handshake after which a peer is unreachable, and its endpoint host name is resolved again.
+/// This is the threshold of `reresolve-dns.sh` from `wireguard-tools`.
+const UNREACHABLE_AFTER: Duration = Duration::from_secs(135);
+/// The time after which the session of a handshake expires, and no packet goes through until the next handshake.
+/// This is `Reject-After-Time` of the WireGuard protocol.
+const REJECT_AFTER: Duration = Duration::from_secs(180);
+/// The number of consecutive errors of the UDP socket after which it is bound again.
+const MAX_SOCKET_ERRORS: u32 = 3;
+/// The maximum delay between two attempts to bind the UDP socket again.
+const MAX_REBIND_BACKOFF: Duration = Duration::from_secs(10);
+/// The number of handshake initiations without response after which the next endpoint of a peer is tried.
+const MAX_HANDSHAKE_ATTEMPTS: u32 = 3;
+/// The interval between the handshakes with the preferred endpoint of the peers using a fallback endpoint,
+/// to go back to it once it is reachable again.
+const PREFERRED_ENDPOINT_PROBE_INTERVAL: Duration = Duration::from_secs(60);
 
 /// A WireGuard tunnel. Encapsulates and decapsulates IP packets
-/// to be sent to and received from a remote UDP endpoint.
-/// This tunnel supports at most 1 peer IP at a time, but supports simultaneous ports.
+/// to be sent to and received from remote UDP endpoints.
+/// This tunnel supports multiple peers, which share the same UDP socket, and simultaneous ports for each of them.
 pub struct WireGuardTunnel {
-    pub(crate) source_peer_ip: IpAddr,
+    /// The peers of the tunnel. The position of a peer is used as its `boringtun` index.
+    peers: Vec<WireGuardPeer>,
+    /// The UDP socket for the public WireGuard endpoints to connect to.
+    /// It is bound again on `endpoint_bind_addr` when it fails (e.g. after a network change), and `None` meanwhile.
+    udp: RwLock<Option<Arc<UdpSocket>>>,
+    endpoint_bind_addr: SocketAddr,
+    /// The consecutive errors of the UDP socket. Notifies `socket_failed` when reaching `MAX_SOCKET_ERRORS`.
+    socket_errors: AtomicU32,
+    socket_failed: Notify,
+    /// Our keys, to find the peer that initiates a handshake.
+    private_key: Arc<X25519SecretKey>,
+    public_key: X25519PublicKey,
+    /// Notified when the endpoint of a peer should be resolved again right away.
+    resolve: Notify,
+    /// The channels of the received IP packets.
+    dispatch: PacketDispatch,
+    /// Counters of the IP packets sent and received through the tunnel.
+    packets: PacketCounters,
+    /// Set once a handshake was completed with any peer.
+    handshake_completed: watch::Sender<bool>,
+}
+
+/// The channels of the IP packets received from the tunnel: to the virtual device of their protocol,
+/// and to the packet capture if enabled, which also receives the sent packets.
+/// A packet is dropped when its channel is full.
+pub struct PacketDispatch {
+    pub tcp: mpsc::Sender<Bytes>,
+    pub udp: mpsc::Sender<Bytes>,
+    pub capture: Option<mpsc::Sender<Bytes>>,
+}
+
+impl PacketDispatch {
+    fn capture(&self, packet: &Bytes) {
+        if let Some(capture) = &self.capture {
+            let _ = capture.try_send(packet.clone());
+        }
+    }
+}
+
+/// Counters of the IP packets sent (outbound) and received (inbound) through the tunnel, since it started.
+#[derive(Debug, Default)]
+struct PacketCounters {
+    inbound_packets: AtomicU64,
+    inbound_bytes: AtomicU64,
+    outbound_packets: AtomicU64,
+    outbound_bytes: AtomicU64,
+}
+
+/// The IP packets sent (outbound) and received (inbound) through the tunnel, since it started.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketStats {
+    pub inbound_packets: u64,
+    pub inbound_bytes: u64,
+    pub outbound_packets: u64,
+    pub outbound_bytes: u64,
+}
+
+/// Tracks whether the tunnel was ever usable, i.e. a handshake was completed with any of its peers.
+/// See `WireGuardTunnel::is_ready` for whether it is usable now.
+#[derive(Clone)]
+pub struct Readiness {
+    handshake_completed: watch::Receiver<bool>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        *self.handshake_completed.borrow()
+    }
+
+    /// Waits until the first handshake is completed.
+    pub async fn wait(&mut self) {
+        // The sender is owned by the tunnel, which lives as long as its tasks
+        let _ = self.handshake_completed.wait_for(|ready| *ready).await;
+    }
+}
+
+/// A peer of the WireGuard tunnel.
+struct WireGuardPeer {
+    config: PeerConfig,
+    endpoints: RwLock<PeerEndpoints>,
+    /// The handshake initiations sent to the active endpoint since the last completed handshake.
+    handshake_attempts: AtomicU32,
+    /// Set when the handshake expired, to resolve the endpoint host names again.
+    resolve_pending: AtomicBool,
     /// `boringtun` peer/tunnel implementation, used for crypto & WG protocol.
-    peer: Box<Tunn>,
-    /// The UDP socket for the public WireGuard endpoint to connect to.
-    udp: UdpSocket,
-    /// The address of the public WireGuard endpoint (UDP).
-    pub(crate) endpoint: SocketAddr,
-    /// Event bus
-    bus: Bus,
+    tunn: Box<Tunn>,
+    counters: PeerCounters,
+}
+
+/// The endpoints of a peer, and the one in use.
+struct PeerEndpoints {
+    /// The addresses of the configured endpoints, in order of preference, as last resolved.
+    addrs: Vec<SocketAddr>,
+    /// The index of the endpoint in use.
+    active: usize,
+    /// The address that datagrams are sent to: the active endpoint, or the source of the last datagram
+    /// authenticated from the peer (roaming).
+    current: SocketAddr,
+}
+
+impl WireGuardPeer {
+    /// Creates the peer at the given index, using its preferred endpoint.
+    fn new(
+        config: &PeerConfig,
+        private_key: Arc<X25519SecretKey>,
+        index: u32,
+    ) -> anyhow::Result<Self> {
+        let tunn = Tunn::new(
+            private_key,
+            config.endpoint_public_key.clone(),
+            config.preshared_key,
+            config.keepalive_seconds,
+            index,
+            None,
+        )
+        .map_err(|s| anyhow::anyhow!("{}", s))
+        .with_context(|| {
+            format!(
+                "Failed to initialize boringtun Tunn for peer '{}'",
+                config.name
+            )
+        })?;
+        Ok(Self {
+            config: config.clone(),
+            endpoints: RwLock::new(PeerEndpoints {
+                addrs: config
+                    .endpoints
+                    .iter()
+                    .map(|endpoint| endpoint.addr)
+                    .collect(),
+                active: 0,
+                current: config.endpoints[0].addr,
+            }),
+            handshake_attempts: AtomicU32::new(0),
+            resolve_pending: AtomicBool::new(false),
+            tunn,
+            counters: PeerCounters::default(),
+        })
+    }
+
+    /// The current address of the endpoint.
+    fn endpoint(&self) -> SocketAddr {
+        self.endpoints.read().unwrap().current
+    }
+
+    /// Sends the next datagrams to the source address of a datagram authenticated from the peer.
+    /// If this is the address of one of its endpoints, that endpoint becomes the active one.
+    fn roam(&self, addr: SocketAddr) {
+        // Checked on every authenticated datagram, so the write lock is only taken for an actual change
+        if self.endpoint() == addr {
+            return;
+        }
+        let mut endpoints = self.endpoints.write().unwrap();
+        if endpoints.current == addr {
+            return;
+        }
+        match endpoints
+            .addrs
+            .iter()
+            .position(|endpoint| *endpoint == addr)
+        {
+            Some(index) if index != endpoints.active => {
+                endpoints.active = index;
+                info!(
+                    "Active endpoint of peer '{}' is now {}",
+                    self.config.name, self.config.endpoints[index]
+                );
+            }
+            _ => info!(
+                "Endpoint of peer '{}' changed from {} to {} (roaming)",
+                self.config.name, endpoints.current, addr
+            ),
+        }
+        endpoints.current = addr;
+    }
+
+    /// Updates the address that the host name of an endpoint resolved to.
+    fn set_resolved(&self, index: usize, addr: SocketAddr) {
+        let mut endpoints = self.endpoints.write().unwrap();
+        endpoints.addrs[index] = addr;
+        if index == endpoints.active && endpoints.current != addr {
+            info!(
+                "Endpoint of peer '{}' changed from {} to {} (resolved)",
+                self.config.name, endpoints.current, addr
+            );
+            endpoints.current = addr;
+        }
+    }
+
+    /// Counts a handshake initiation about to be sent to the peer. After `MAX_HANDSHAKE_ATTEMPTS` without response,
+    /// the next endpoint becomes the active one.
+    fn count_handshake_attempt(&self) {
+        if self.config.endpoints.len() < 2
+            || self.handshake_attempts.fetch_add(1, Ordering::Relaxed) < MAX_HANDSHAKE_ATTEMPTS
+        {
+            return;
+        }
+        self.handshake_attempts.store(1, Ordering::Relaxed);
+        let mut endpoints = self.endpoints.write().unwrap();
+        let failed = endpoints.active;
+        endpoints.active = (failed + 1) % endpoints.addrs.len();
+        endpoints.current = endpoints.addrs[endpoints.active];
+        warn!(
+            "No handshake with endpoint {} of peer '{}' after {} attempts, the active endpoint is now {}",
+            self.config.endpoints[failed],
+            self.config.name,
+            MAX_HANDSHAKE_ATTEMPTS,
+            self.config.endpoints[endpoints.active]
+        );
+    }
+
+    /// The address of the preferred endpoint, if it is not the active one.
+    fn preferred_endpoint(&self) -> Option<SocketAddr> {
+        let endpoints = self.endpoints.read().unwrap();
+        (endpoints.active != 0).then(|| endpoints.addrs[0])
+    }
+
+    /// Whether the peer did not complete a handshake recently, so that its endpoint may have changed.
+    fn is_unreachable(&self) -> bool {
+        match self.time_since_last_handshake() {
+            Some(elapsed) => elapsed > UNREACHABLE_AFTER,
+            None => true,
+        }
+    }
+
+    /// Whether the session of the last handshake did not expire, so that packets go through.
+    fn has_session(&self) -> bool {
+        self.time_since_last_handshake()
+            .is_some_and(|elapsed| elapsed < REJECT_AFTER)
+    }
+
+    /// Returns the time since the last handshake, if any was completed.
+    fn time_since_last_handshake(&self) -> Option<Duration> {
+        // Despite its name, `boringtun` returns the time of the handshake since the Unix epoch
+        let handshake_time = self.tunn.time_since_last_handshake()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(now.saturating_sub(handshake_time))
+    }
+}
+
+/// The delay before the next attempt to bind the UDP socket again: none for the first one, then growing exponentially
+/// up to `MAX_REBIND_BACKOFF`, until the socket receives a datagram.
+#[derive(Debug, Default)]
+struct RebindBackoff {
+    delay: Duration,
+}
+
+impl RebindBackoff {
+    /// Returns the delay before the next attempt, and doubles the following one.
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).clamp(Duration::from_secs(1), MAX_REBIND_BACKOFF);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+}
+
+/// Counters of the WireGuard protocol events of a peer, since the tunnel started.
+#[derive(Debug, Default)]
+struct PeerCounters {
+    handshakes: AtomicU64,
+    encapsulate_errors: AtomicU64,
+    decapsulate_errors: AtomicU64,
+}
+
+/// The statistics of a peer of the tunnel.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub name: Arc<str>,
+    pub endpoint_addr: SocketAddr,
+    /// The time since the last handshake, if any was completed.
+    pub last_handshake: Option<Duration>,
+    /// The data bytes sent to and received from the peer, as counted by `boringtun`.
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    /// The estimated ratio of packets lost, between 0 and 1.
+    pub estimated_loss: f32,
+    /// The round-trip time measured during the last handshake.
+    pub rtt: Option<Duration>,
+    /// The number of handshake messages from the peer that were processed successfully.
+    pub handshakes: u64,
+    pub encapsulate_errors: u64,
+    pub decapsulate_errors: u64,
 }
 
 impl WireGuardTunnel {
-    /// Initialize a new WireGuard tunnel.
-    pub async fn new(config: &Config, bus: Bus) -> anyhow::Result<Self> {
-        let source_peer_ip = config.source_peer_ip;
-        let peer = Self::create_tunnel(config)?;
-        let endpoint = config.endpoint_addr;
-        let udp = UdpSocket::bind(config.endpoint_bind_addr)
+    /// Initialize a new WireGuard tunnel, which sends the IP packets it receives on the channels of `dispatch`.
+    pub async fn new(config: &Config, dispatch: PacketDispatch) -> anyhow::Result<Self> {
+        Self::with_peers(
+            &config.peers,
+            config.private_key.clone(),
+            config.endpoint_bind_addr,
+            dispatch,
+        )
+        .await
+    }
+
+    /// Initialize a tunnel with the peers, binding its UDP socket on `endpoint_bind_addr`.
+    async fn with_peers(
+        peers: &[PeerConfig],
+        private_key: Arc<X25519SecretKey>,
+        endpoint_bind_addr: SocketAddr,
+        dispatch: PacketDispatch,
+    ) -> anyhow::Result<Self> {
+        let peers = peers
+            .iter()
+            .enumerate()
+            .map(|(index, peer)| WireGuardPeer::new(peer, private_key.clone(), index as u32))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let udp = Self::bind(endpoint_bind_addr)
             .await
             .with_context(|| "Failed to create UDP socket for WireGuard connection")?;
 
+        let (handshake_completed, _) = watch::channel(false);
+
         Ok(Self {
-            source_peer_ip,
-            peer,
-            udp,
-            endpoint,
-            bus,
+            peers,
+            udp: RwLock::new(Some(Arc::new(udp))),
+            endpoint_bind_addr,
+            socket_errors: AtomicU32::new(0),
+            socket_failed: Notify::new(),
+            public_key: private_key.public_key(),
+            private_key,
+            resolve: Notify::new(),
+            dispatch,
+            packets: PacketCounters::default(),
+            handshake_completed,
         })
     }
 
-    /// Encapsulates and sends an IP packet through to the WireGuard endpoint.
-    pub async fn send_ip_packet(&self, packet: &[u8]) -> anyhow::Result<()> {
+    /// Returns the readiness of the tunnel, which is set once the first handshake is completed.
+    pub fn readiness(&self) -> Readiness {
+        Readiness {
+            handshake_completed: self.handshake_completed.subscribe(),
+        }
+    }
+
+    /// Whether the tunnel is usable now, i.e. the session of a handshake with any of its peers did not expire.
+    pub fn is_ready(&self) -> bool {
+        self.peers.iter().any(|peer| peer.has_session())
+    }
+
+    /// Returns the statistics of each peer, like `wg show`, in the order of the configuration.
+    pub fn stats(&self) -> Vec<PeerStats> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                let (_, tx_bytes, rx_bytes, estimated_loss, rtt) = peer.tunn.stats();
+                PeerStats {
+                    name: peer.config.name.clone(),
+                    endpoint_addr: peer.endpoint(),
+                    last_handshake: peer.time_since_last_handshake(),
+                    tx_bytes: tx_bytes as u64,
+                    rx_bytes: rx_bytes as u64,
+                    estimated_loss,
+                    rtt: rtt.map(|ms| Duration::from_millis(ms as u64)),
+                    handshakes: peer.counters.handshakes.load(Ordering::Relaxed),
+                    encapsulate_errors: peer.counters.encapsulate_errors.load(Ordering::Relaxed),
+                    decapsulate_errors: peer.counters.decapsulate_errors.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the counters of the IP packets sent and received through the tunnel.
+    pub fn packet_stats(&self) -> PacketStats {
+        PacketStats {
+            inbound_packets: self.packets.inbound_packets.load(Ordering::Relaxed),
+            inbound_bytes: self.packets.inbound_bytes.load(Ordering::Relaxed),
+            outbound_packets: self.packets.outbound_packets.load(Ordering::Relaxed),
+            outbound_bytes: self.packets.outbound_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Encapsulates and sends an IP packet through to the WireGuard endpoint of the peer
+    /// identified by the source and destination IPs of the packet.
+    /// The encrypted packet is written in the given buffer, which should be `MAX_PACKET` bytes long.
+    pub async fn send_ip_packet(&self, packet: &[u8], send_buf: &mut [u8]) -> anyhow::Result<()> {
         trace_ip_packet("Sending IP packet", packet);
-        let mut send_buf = [0u8; MAX_PACKET];
-        match self.peer.encapsulate(packet, &mut send_buf) {
+        let peer = match self.route_outbound(packet) {
+            Some(peer) => peer,
+            None => {
+                warn!("Dropping outbound IP packet, since no peer matches its source IP and allows its destination IP");
+                return Ok(());
+            }
+        };
+        match peer.tunn.encapsulate(packet, send_buf) {
             TunnResult::WriteToNetwork(packet) => {
-                self.udp
-                    .send_to(packet, self.endpoint)
-                    .await
-                    .with_context(|| "Failed to send encrypted IP packet to WireGuard endpoint.")?;
+                self.send_to_peer(peer, packet).await.with_context(|| {
+                    format!(
+                        "Failed to send encrypted IP packet to WireGuard endpoint of peer '{}'.",
+                        peer.config.name
+                    )
+                })?;
                 debug!(
-                    "Sent {} bytes to WireGuard endpoint (encrypted IP packet)",
-                    packet.len()
+                    "Sent {} bytes to WireGuard endpoint of peer '{}' (encrypted IP packet)",
+                    packet.len(),
+                    peer.config.name
                 );
             }
             TunnResult::Err(e) => {
+                peer.counters
+                    .encapsulate_errors
+                    .fetch_add(1, Ordering::Relaxed);
                 error!("Failed to encapsulate IP packet: {:?}", e);
             }
             TunnResult::Done => {
@@ -82,68 +470,213 @@ impl WireGuardTunnel {
         Ok(())
     }
 
-    pub async fn produce_task(&self) -> ! {
+    /// WireGuard production task. Sends the IP packets crafted by the virtual devices through the tunnel.
+    pub async fn produce_task(&self, mut packets: mpsc::Receiver<Bytes>) -> ! {
         trace!("Starting WireGuard production task");
-        let mut endpoint = self.bus.new_endpoint();
 
-        loop {
-            if let Event::OutboundInternetPacket(data) = endpoint.recv().await {
-                match self.send_ip_packet(&data).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("{:?}", e);
-                    }
+        let mut send_buf = vec![0u8; MAX_PACKET];
+        while let Some(data) = packets.recv().await {
+            self.packets
+                .outbound_packets
+                .fetch_add(1, Ordering::Relaxed);
+            self.packets
+                .outbound_bytes
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+            self.dispatch.capture(&data);
+            match self.send_ip_packet(&data, &mut send_buf).await {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("{:?}", e);
                 }
             }
         }
+        // The virtual devices were dropped
+        futures::future::pending().await
     }
 
     /// WireGuard Routine task. Handles Handshake, keep-alive, etc.
+    ///
+    /// The timers of the peers are updated every `TIMER_TICK`, like `boringtun` does, since they are rounded anyway.
+    /// Handshakes needed to send or answer packets are not delayed: they are started by `encapsulate` and `decapsulate`.
     pub async fn routine_task(&self) -> ! {
         trace!("Starting WireGuard routine task");
 
+        let mut send_buf = vec![0u8; MAX_PACKET];
+
+        // Handshake with the peers right away, so that the tunnel is ready before the first connection
+        for peer in self.peers.iter() {
+            let tun_result = peer.tunn.format_handshake_initiation(&mut send_buf, false);
+            self.handle_routine_tun_result(peer, tun_result).await;
+        }
+
+        let mut timers = tokio::time::interval(TIMER_TICK);
+        timers.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut probes = tokio::time::interval_at(
+            tokio::time::Instant::now() + PREFERRED_ENDPOINT_PROBE_INTERVAL,
+            PREFERRED_ENDPOINT_PROBE_INTERVAL,
+        );
+        probes.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
-            let mut send_buf = [0u8; MAX_PACKET];
-            let tun_result = self.peer.update_timers(&mut send_buf);
-            self.handle_routine_tun_result(tun_result).await;
+            tokio::select! {
+                _ = timers.tick() => {
+                    for peer in self.peers.iter() {
+                        let tun_result = peer.tunn.update_timers(&mut send_buf);
+                        self.handle_routine_tun_result(peer, tun_result).await;
+                    }
+                }
+                _ = probes.tick() => {
+                    for peer in self.peers.iter() {
+                        if let Some(preferred) = peer.preferred_endpoint() {
+                            self.probe_endpoint(peer, preferred, &mut send_buf).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a handshake initiation to an endpoint of the peer other than the active one. If it answers, its address
+    /// becomes the current one (see `WireGuardPeer::roam`). Otherwise, the handshake is retried with the active endpoint.
+    async fn probe_endpoint(&self, peer: &WireGuardPeer, addr: SocketAddr, send_buf: &mut [u8]) {
+        if let TunnResult::WriteToNetwork(packet) =
+            peer.tunn.format_handshake_initiation(send_buf, true)
+        {
+            debug!(
+                "Sending handshake initiation to endpoint {} of peer '{}'",
+                addr, peer.config.name
+            );
+            if let Err(e) = self.send_to(addr, packet).await {
+                debug!(
+                    "Failed to send handshake initiation to endpoint {} of peer '{}': {:?}",
+                    addr, peer.config.name, e
+                );
+            }
+        }
+    }
+
+    /// WireGuard resolution task. Resolves the host names of the peer endpoints again when the peers are unreachable,
+    /// or when their handshake expired, since their address may have changed (e.g. with dynamic DNS).
+    pub async fn resolve_task(&self) -> ! {
+        if self.peers.iter().all(|peer| {
+            peer.config
+                .endpoints
+                .iter()
+                .all(|endpoint| endpoint.host.is_none())
+        }) {
+            // All the endpoints are IP addresses
+            futures::future::pending().await
+        }
+        trace!("Starting WireGuard resolution task");
+
+        let mut checks = tokio::time::interval(RESOLVE_INTERVAL);
+        checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = checks.tick() => {}
+                _ = self.resolve.notified() => {}
+            }
+            for peer in self.peers.iter() {
+                if peer.resolve_pending.swap(false, Ordering::Relaxed) || peer.is_unreachable() {
+                    self.resolve_endpoints(peer).await;
+                }
+            }
+        }
+    }
+
+    /// Resolves the host names of the endpoints of the peer, and updates their addresses.
+    async fn resolve_endpoints(&self, peer: &WireGuardPeer) {
+        for (index, endpoint) in peer.config.endpoints.iter().enumerate() {
+            if let Some(host) = &endpoint.host {
+                if let Some(addr) = self.resolve_endpoint(peer, host).await {
+                    peer.set_resolved(index, addr);
+                }
+            }
+        }
+    }
+
+    /// Resolves the host name of an endpoint of the peer.
+    /// With a dual-stack UDP socket, this is the first address of either IP version.
+    async fn resolve_endpoint(&self, peer: &WireGuardPeer, host: &str) -> Option<SocketAddr> {
+        match tokio::net::lookup_host(host).await {
+            Ok(mut addrs) => match addrs.find(|addr| can_reach(self.endpoint_bind_addr, *addr)) {
+                Some(addr) => {
+                    debug!(
+                        "Resolved endpoint {} of peer '{}' to {}",
+                        host, peer.config.name, addr
+                    );
+                    Some(addr)
+                }
+                None => {
+                    warn!(
+                        "Endpoint {} of peer '{}' did not resolve to any address reachable from {}",
+                        host, peer.config.name, self.endpoint_bind_addr
+                    );
+                    None
+                }
+            },
+            Err(e) => {
+                warn!(
+                    "Failed to resolve endpoint {} of peer '{}': {:?}",
+                    host, peer.config.name, e
+                );
+                None
+            }
         }
     }
 
     #[async_recursion]
-    async fn handle_routine_tun_result<'a: 'async_recursion>(&self, result: TunnResult<'a>) -> () {
+    async fn handle_routine_tun_result<'a: 'async_recursion>(
+        &self,
+        peer: &WireGuardPeer,
+        result: TunnResult<'a>,
+    ) -> () {
         match result {
             TunnResult::WriteToNetwork(packet) => {
                 debug!(
-                    "Sending routine packet of {} bytes to WireGuard endpoint",
-                    packet.len()
+                    "Sending routine packet of {} bytes to WireGuard endpoint of peer '{}'",
+                    packet.len(),
+                    peer.config.name
                 );
-                match self.udp.send_to(packet, self.endpoint).await {
+                match self.send_to_peer(peer, packet).await {
                     Ok(_) => {}
                     Err(e) => {
                         error!(
-                            "Failed to send routine packet to WireGuard endpoint: {:?}",
-                            e
+                            "Failed to send routine packet to WireGuard endpoint of peer '{}': {:?}",
+                            peer.config.name, e
                         );
                     }
                 };
             }
             TunnResult::Err(WireGuardError::ConnectionExpired) => {
-                warn!("Wireguard handshake has expired!");
+                warn!(
+                    "Wireguard handshake has expired for peer '{}'!",
+                    peer.config.name
+                );
+
+                // The addresses of the endpoints may have changed
+                if peer
+                    .config
+                    .endpoints
+                    .iter()
+                    .any(|endpoint| endpoint.host.is_some())
+                {
+                    peer.resolve_pending.store(true, Ordering::Relaxed);
+                    self.resolve.notify_one();
+                }
 
                 let mut buf = vec![0u8; MAX_PACKET];
-                let result = self.peer.format_handshake_initiation(&mut buf[..], false);
+                let result = peer.tunn.format_handshake_initiation(&mut buf[..], false);
 
-                self.handle_routine_tun_result(result).await
+                self.handle_routine_tun_result(peer, result).await
             }
             TunnResult::Err(e) => {
                 error!(
-                    "Failed to prepare routine packet for WireGuard endpoint: {:?}",
-                    e
+                    "Failed to prepare routine packet for WireGuard endpoint of peer '{}': {:?}",
+                    peer.config.name, e
                 );
             }
             TunnResult::Done => {
-                // Sleep for a bit
-                tokio::time::sleep(Duration::from_millis(1)).await;
+                // Nothing to send
             }
             other => {
                 warn!("Unexpected WireGuard routine task state: {:?}", other);
@@ -151,92 +684,354 @@ impl WireGuardTunnel {
         };
     }
 
-    /// WireGuard consumption task. Receives encrypted packets from the WireGuard endpoint,
+    /// WireGuard consumption task. Receives encrypted packets from the WireGuard endpoints,
     /// decapsulates them, and dispatches newly received IP packets.
     pub async fn consume_task(&self) -> ! {
         trace!("Starting WireGuard consumption task");
-        let endpoint = self.bus.new_endpoint();
 
-        loop {
-            let mut recv_buf = [0u8; MAX_PACKET];
-            let mut send_buf = [0u8; MAX_PACKET];
+        let mut recv_buf = vec![0u8; MAX_PACKET];
+        let mut send_buf = vec![0u8; MAX_PACKET];
+        // The received IP packets are copied to the pool, to be sent to the virtual devices
+        let mut packet_buffers = BufferPool::default();
+        let mut rebind_backoff = RebindBackoff::default();
 
-            let size = match self.udp.recv(&mut recv_buf).await {
-                Ok(size) => size,
+        loop {
+            let udp = match self.socket() {
+                Some(udp) => udp,
+                None => {
+                    self.rebind(&mut rebind_backoff).await;
+                    continue;
+                }
+            };
+            let received = tokio::select! {
+                received = udp.recv_from(&mut recv_buf) => received,
+                _ = self.socket_failed.notified() => {
+                    // Sending failed repeatedly
+                    drop(udp);
+                    self.rebind(&mut rebind_backoff).await;
+                    continue;
+                }
+            };
+            let (size, addr) = match received {
+                Ok((size, addr)) => {
+                    self.socket_ok();
+                    rebind_backoff.reset();
+                    // The IPv4 endpoints are received as IPv4-mapped addresses on a dual-stack socket
+                    match addr {
+                        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                            Some(ip) => (size, SocketAddr::from((ip, v6.port()))),
+                            None => (size, addr),
+                        },
+                        SocketAddr::V4(_) => (size, addr),
+                    }
+                }
                 Err(e) => {
                     error!("Failed to read from WireGuard endpoint: {:?}", e);
-                    // Sleep a little bit and try again
-                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    self.socket_error(&udp, &e);
                     continue;
                 }
             };
 
             let data = &recv_buf[..size];
-            match self.peer.decapsulate(None, data, &mut send_buf) {
+            let peer = match self.route_inbound(data) {
+                Some(peer) => peer,
+                None => {
+                    debug!(
+                        "Ignoring WireGuard datagram of {} bytes from {}, since it does not match any peer",
+                        size, addr
+                    );
+                    continue;
+                }
+            };
+
+            let packet = Tunn::parse_incoming_packet(data);
+            let handshake = matches!(
+                packet,
+                Ok(Packet::HandshakeInit(_)) | Ok(Packet::HandshakeResponse(_))
+            );
+            let result = peer.tunn.decapsulate(None, data, &mut send_buf);
+            if !matches!(result, TunnResult::Err(_)) {
+                if handshake {
+                    peer.counters.handshakes.fetch_add(1, Ordering::Relaxed);
+                    peer.handshake_attempts.store(0, Ordering::Relaxed);
+                }
+                // The peer authenticated the datagram, so it can be reached at its source address (roaming).
+                // Cookie replies are not authenticated by the static key of the peer.
+                if !matches!(packet, Ok(Packet::PacketCookieReply(_))) {
+                    peer.roam(addr);
+                }
+            }
+            if !*self.handshake_completed.borrow() && peer.time_since_last_handshake().is_some() {
+                info!(
+                    "WireGuard handshake completed with peer '{}'",
+                    peer.config.name
+                );
+                self.handshake_completed.send_replace(true);
+            }
+            match result {
                 TunnResult::WriteToNetwork(packet) => {
-                    match self.udp.send_to(packet, self.endpoint).await {
+                    match self.send_to_peer(peer, packet).await {
                         Ok(_) => {}
                         Err(e) => {
                             error!("Failed to send decapsulation-instructed packet to WireGuard endpoint: {:?}", e);
                             continue;
                         }
                     };
-                    loop {
-                        let mut send_buf = [0u8; MAX_PACKET];
-                        match self.peer.decapsulate(None, &[], &mut send_buf) {
-                            TunnResult::WriteToNetwork(packet) => {
-                                match self.udp.send_to(packet, self.endpoint).await {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        error!("Failed to send decapsulation-instructed packet to WireGuard endpoint: {:?}", e);
-                                        break;
-                                    }
-                                };
-                            }
-                            _ => {
-                                break;
-                            }
+                    // Send the packets queued while handshaking
+                    while let TunnResult::WriteToNetwork(packet) =
+                        peer.tunn.decapsulate(None, &[], &mut send_buf)
+                    {
+                        if let Err(e) = self.send_to_peer(peer, packet).await {
+                            error!("Failed to send decapsulation-instructed packet to WireGuard endpoint: {:?}", e);
+                            break;
                         }
                     }
                 }
                 TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
                     debug!(
-                        "WireGuard endpoint sent IP packet of {} bytes",
+                        "WireGuard endpoint of peer '{}' sent IP packet of {} bytes",
+                        peer.config.name,
                         packet.len()
                     );
 
                     // For debugging purposes: parse packet
                     trace_ip_packet("Received IP packet", packet);
 
-                    if let Some(proto) = self.route_protocol(packet) {
-                        endpoint.send(Event::InboundInternetPacket(proto, packet.to_vec().into()));
+                    if let Some(proto) = Self::route_protocol(peer, packet) {
+                        self.dispatch_packet(proto, packet_buffers.copy_from_slice(packet));
                     }
                 }
+                TunnResult::Err(e) => {
+                    peer.counters
+                        .decapsulate_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    debug!(
+                        "Failed to decapsulate WireGuard datagram from peer '{}': {:?}",
+                        peer.config.name, e
+                    );
+                }
                 _ => {}
             }
         }
     }
 
-    fn create_tunnel(config: &Config) -> anyhow::Result<Box<Tunn>> {
-        Tunn::new(
-            config.private_key.clone(),
-            config.endpoint_public_key.clone(),
-            config.preshared_key,
-            config.keepalive_seconds,
-            0,
-            None,
-        )
-        .map_err(|s| anyhow::anyhow!("{}", s))
-        .with_context(|| "Failed to initialize boringtun Tunn")
+    /// The current UDP socket, if it is bound.
+    fn socket(&self) -> Option<Arc<UdpSocket>> {
+        self.udp.read().unwrap().clone()
+    }
+
+    /// Sends a datagram to the current endpoint of the peer, and keeps track of the health of the UDP socket.
+    async fn send_to_peer(&self, peer: &WireGuardPeer, datagram: &[u8]) -> io::Result<usize> {
+        if matches!(
+            Tunn::parse_incoming_packet(datagram),
+            Ok(Packet::HandshakeInit(_))
+        ) {
+            peer.count_handshake_attempt();
+        }
+        self.send_to(peer.endpoint(), datagram).await
+    }
+
+    /// Sends a datagram to an address, keeping track of the errors of the UDP socket.
+    async fn send_to(&self, addr: SocketAddr, datagram: &[u8]) -> io::Result<usize> {
+        let addr = match addr {
+            SocketAddr::V4(v4) if is_dual_stack(self.endpoint_bind_addr) => {
+                SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port()))
+            }
+            addr => addr,
+        };
+        let udp = self.socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The UDP socket is being bound again",
+            )
+        })?;
+        match udp.send_to(datagram, addr).await {
+            Ok(size) => {
+                self.socket_ok();
+                Ok(size)
+            }
+            Err(e) => {
+                self.socket_error(&udp, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resets the count of consecutive errors of the UDP socket, after a successful operation.
+    fn socket_ok(&self) {
+        // Only written on change, since this is called for every datagram
+        if self.socket_errors.load(Ordering::Relaxed) != 0 {
+            self.socket_errors.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts an error of the UDP socket, and requests to bind it again after `MAX_SOCKET_ERRORS` in a row.
+    fn socket_error(&self, udp: &Arc<UdpSocket>, e: &io::Error) {
+        // ICMP errors reported by some platforms (e.g. unreachable ports on Windows) do not affect the socket
+        if matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+        ) {
+            return;
+        }
+        // Errors of a socket that was already replaced are ignored
+        let current = self.udp.read().unwrap();
+        if current
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, udp))
+            && self.socket_errors.fetch_add(1, Ordering::Relaxed) + 1 == MAX_SOCKET_ERRORS
+        {
+            self.socket_failed.notify_one();
+        }
+    }
+
+    /// Binds the UDP socket for the WireGuard endpoints. On `[::]`, the socket is dual-stack:
+    /// the IPv4 endpoints are reached with IPv4-mapped IPv6 addresses.
+    async fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+        if !is_dual_stack(addr) {
+            return UdpSocket::bind(addr).await;
+        }
+        // The default of IPV6_V6ONLY depends on the OS
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Binds the UDP socket again on the same address, retrying with exponential backoff, and handshakes with the peers
+    /// right away, since they may only reach us at a new address.
+    async fn rebind(&self, backoff: &mut RebindBackoff) {
+        // The previous socket is closed first, since the new one may use the same port
+        self.udp.write().unwrap().take();
+        loop {
+            let delay = backoff.next();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            match Self::bind(self.endpoint_bind_addr).await {
+                Ok(udp) => {
+                    info!(
+                        "Bound the UDP socket for the WireGuard endpoints again on {}",
+                        udp.local_addr().unwrap_or(self.endpoint_bind_addr)
+                    );
+                    *self.udp.write().unwrap() = Some(Arc::new(udp));
+                    self.socket_errors.store(0, Ordering::Relaxed);
+                    break;
+                }
+                Err(e) => warn!(
+                    "Failed to bind the UDP socket for the WireGuard endpoints on {}, retrying in {:?}: {:?}",
+                    self.endpoint_bind_addr, backoff.delay, e
+                ),
+            }
+        }
+
+        let mut send_buf = vec![0u8; MAX_PACKET];
+        for peer in self.peers.iter() {
+            let tun_result = peer.tunn.format_handshake_initiation(&mut send_buf, false);
+            self.handle_routine_tun_result(peer, tun_result).await;
+        }
+    }
+
+    /// Sends a received IP packet to the virtual device of its protocol.
+    fn dispatch_packet(&self, proto: PortProtocol, packet: Bytes) {
+        self.packets.inbound_packets.fetch_add(1, Ordering::Relaxed);
+        self.packets
+            .inbound_bytes
+            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        self.dispatch.capture(&packet);
+        let device = match proto {
+            PortProtocol::Tcp => &self.dispatch.tcp,
+            PortProtocol::Udp => &self.dispatch.udp,
+        };
+        match device.try_send(packet) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!(
+                    "Dropped inbound IP packet, since the {} virtual device is busy",
+                    proto
+                );
+            }
+            // There is no virtual interface for the protocol
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Determine the peer to send an outgoing IP packet to.
+    ///
+    /// Among the peers with the source IP of the packet, this is the peer with the most specific
+    /// allowed IP range containing the destination IP (cryptokey routing).
+    fn route_outbound(&self, packet: &[u8]) -> Option<&WireGuardPeer> {
+        let (source, destination): (IpAddr, IpAddr) = match IpVersion::of_packet(packet) {
+            Ok(IpVersion::Ipv4) => Ipv4Packet::new_checked(&packet).ok().map(|packet| {
+                (
+                    Ipv4Addr::from(packet.src_addr()).into(),
+                    Ipv4Addr::from(packet.dst_addr()).into(),
+                )
+            }),
+            Ok(IpVersion::Ipv6) => Ipv6Packet::new_checked(&packet).ok().map(|packet| {
+                (
+                    Ipv6Addr::from(packet.src_addr()).into(),
+                    Ipv6Addr::from(packet.dst_addr()).into(),
+                )
+            }),
+            _ => None,
+        }?;
+        self.peers
+            .iter()
+            .filter(|peer| peer.config.is_source_peer_ip(source))
+            .filter_map(|peer| {
+                peer.config
+                    .allowed_prefix_len(destination)
+                    .map(|prefix_len| (peer, prefix_len))
+            })
+            // On equal prefix lengths, the first configured peer wins
+            .rev()
+            .max_by_key(|(_, prefix_len)| *prefix_len)
+            .map(|(peer, _)| peer)
+    }
+
+    /// Determine the peer that sent an incoming WireGuard datagram.
+    ///
+    /// Handshake initiations are matched by the static public key of the initiator, since they do not contain our
+    /// index yet, and may come from any address if the peer roamed.
+    /// Other messages contain the index that `boringtun` assigned to the session, which starts with the peer index.
+    fn route_inbound(&self, datagram: &[u8]) -> Option<&WireGuardPeer> {
+        let receiver_idx = match Tunn::parse_incoming_packet(datagram) {
+            Ok(Packet::HandshakeInit(packet)) => {
+                let initiator =
+                    parse_handshake_anon(&self.private_key, &self.public_key, &packet).ok()?;
+                return self.peers.iter().find(|peer| {
+                    peer.config.endpoint_public_key.as_bytes() == initiator.peer_static_public
+                });
+            }
+            Ok(Packet::HandshakeResponse(packet)) => packet.receiver_idx,
+            Ok(Packet::PacketCookieReply(packet)) => packet.receiver_idx,
+            Ok(Packet::PacketData(packet)) => packet.receiver_idx,
+            Err(_) => return None,
+        };
+        self.peers.get((receiver_idx >> 8) as usize)
     }
 
     /// Determine the inner protocol of the incoming IP packet (TCP/UDP).
-    fn route_protocol(&self, packet: &[u8]) -> Option<PortProtocol> {
+    fn route_protocol(peer: &WireGuardPeer, packet: &[u8]) -> Option<PortProtocol> {
         match IpVersion::of_packet(packet) {
             Ok(IpVersion::Ipv4) => Ipv4Packet::new_checked(&packet)
                 .ok()
-                // Only care if the packet is destined for this tunnel
-                .filter(|packet| Ipv4Addr::from(packet.dst_addr()) == self.source_peer_ip)
+                // Only care if the packet is destined for this peer
+                .filter(|packet| {
+                    peer.config
+                        .is_source_peer_ip(Ipv4Addr::from(packet.dst_addr()).into())
+                })
+                // Drop packets from IPs that the peer is not allowed to send from
+                .filter(|packet| {
+                    peer.config
+                        .allowed_prefix_len(Ipv4Addr::from(packet.src_addr()).into())
+                        .is_some()
+                })
                 .and_then(|packet| match packet.protocol() {
                     IpProtocol::Tcp => Some(PortProtocol::Tcp),
                     IpProtocol::Udp => Some(PortProtocol::Udp),
@@ -245,8 +1040,17 @@ impl WireGuardTunnel {
                 }),
             Ok(IpVersion::Ipv6) => Ipv6Packet::new_checked(&packet)
                 .ok()
-                // Only care if the packet is destined for this tunnel
-                .filter(|packet| Ipv6Addr::from(packet.dst_addr()) == self.source_peer_ip)
+                // Only care if the packet is destined for this peer
+                .filter(|packet| {
+                    peer.config
+                        .is_source_peer_ip(Ipv6Addr::from(packet.dst_addr()).into())
+                })
+                // Drop packets from IPs that the peer is not allowed to send from
+                .filter(|packet| {
+                    peer.config
+                        .allowed_prefix_len(Ipv6Addr::from(packet.src_addr()).into())
+                        .is_some()
+                })
                 .and_then(|packet| match packet.next_header() {
                     IpProtocol::Tcp => Some(PortProtocol::Tcp),
                     IpProtocol::Udp => Some(PortProtocol::Udp),
@@ -277,3 +1081,418 @@ fn trace_ip_packet(message: &str, packet: &[u8]) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{IpCidr, Ipv4Repr};
+
+    use super::*;
+
+    fn peer(
+        name: &str,
+        public_key: X25519PublicKey,
+        endpoints: &[&str],
+        source_peer_ips: &str,
+        allowed_ips: &str,
+    ) -> PeerConfig {
+        let cidrs = |s: &str| {
+            s.split(',')
+                .map(|s| IpCidr::from_str(s.trim()).unwrap())
+                .collect()
+        };
+        PeerConfig {
+            name: name.into(),
+            endpoint_public_key: Arc::new(public_key),
+            preshared_key: None,
+            endpoints: endpoints
+                .iter()
+                .map(|addr| SocketAddr::from_str(addr).unwrap().into())
+                .collect(),
+            source_peer_ips: cidrs(source_peer_ips),
+            allowed_ips: cidrs(allowed_ips),
+            keepalive_seconds: None,
+        }
+    }
+
+    async fn tunnel(peers: &[PeerConfig], private_key: Arc<X25519SecretKey>) -> WireGuardTunnel {
+        let (tcp, _) = mpsc::channel(1);
+        let (udp, _) = mpsc::channel(1);
+        WireGuardTunnel::with_peers(
+            peers,
+            private_key,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            PacketDispatch {
+                tcp,
+                udp,
+                capture: None,
+            },
+        )
+        .await
+        .expect("Failed to create tunnel")
+    }
+
+    fn ipv4_packet(source: &str, destination: &str, protocol: IpProtocol) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Addr::from_str(source).unwrap().into(),
+            dst_addr: Ipv4Addr::from_str(destination).unwrap().into(),
+            protocol,
+            payload_len: 20,
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; repr.buffer_len() + repr.payload_len];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &ChecksumCapabilities::default(),
+        );
+        packet
+    }
+
+    /// Tests that outbound packets go to the peer with the most specific allowed IP range among the peers with
+    /// their source IP, and are dropped if no peer matches.
+    #[tokio::test]
+    async fn test_route_outbound() {
+        let key = || X25519SecretKey::new().public_key();
+        let peers = [
+            peer(
+                "wide",
+                key(),
+                &["127.0.0.1:51820"],
+                "192.168.4.3/24",
+                "10.0.0.0/8",
+            ),
+            peer(
+                "narrow",
+                key(),
+                &["127.0.0.1:51821"],
+                "192.168.4.3/24",
+                "10.1.0.0/16",
+            ),
+            peer(
+                "other",
+                key(),
+                &["127.0.0.1:51822"],
+                "192.168.5.3/24",
+                "0.0.0.0/0",
+            ),
+        ];
+        let wg = tunnel(&peers, Arc::new(X25519SecretKey::new())).await;
+        let route = |source: &str, destination: &str| {
+            wg.route_outbound(&ipv4_packet(source, destination, IpProtocol::Tcp))
+                .map(|peer| peer.config.name.to_string())
+        };
+
+        assert_eq!(route("192.168.4.3", "10.1.2.3").as_deref(), Some("narrow"));
+        assert_eq!(route("192.168.4.3", "10.2.0.1").as_deref(), Some("wide"));
+        assert_eq!(route("192.168.5.3", "10.1.2.3").as_deref(), Some("other"));
+        assert_eq!(route("192.168.4.3", "172.16.0.1"), None);
+        assert_eq!(route("192.168.6.3", "10.1.2.3"), None);
+        assert!(wg.route_outbound(&[0x45, 0, 0]).is_none());
+    }
+
+    /// Tests that inbound datagrams are matched to their peer by the public key of handshake initiations,
+    /// and by the receiver index of the other messages.
+    #[tokio::test]
+    async fn test_route_inbound() {
+        let private_key = Arc::new(X25519SecretKey::new());
+        let remote_keys: Vec<Arc<X25519SecretKey>> =
+            (0..2).map(|_| Arc::new(X25519SecretKey::new())).collect();
+        let peers = [
+            peer(
+                "a",
+                remote_keys[0].public_key(),
+                &["127.0.0.1:51820"],
+                "192.168.4.3/24",
+                "10.0.0.0/8",
+            ),
+            peer(
+                "b",
+                remote_keys[1].public_key(),
+                &["127.0.0.1:51821"],
+                "192.168.4.3/24",
+                "10.1.0.0/16",
+            ),
+        ];
+        let wg = tunnel(&peers, private_key.clone()).await;
+
+        let initiation = |remote_key: Arc<X25519SecretKey>| {
+            let remote = Tunn::new(
+                remote_key,
+                Arc::new(private_key.public_key()),
+                None,
+                None,
+                0,
+                None,
+            )
+            .unwrap();
+            let mut buf = vec![0u8; MAX_PACKET];
+            match remote.format_handshake_initiation(&mut buf, false) {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => panic!("No handshake initiation"),
+            }
+        };
+        let route = |datagram: &[u8]| {
+            wg.route_inbound(datagram)
+                .map(|peer| peer.config.name.to_string())
+        };
+        assert_eq!(
+            route(&initiation(remote_keys[1].clone())).as_deref(),
+            Some("b")
+        );
+        assert_eq!(route(&initiation(Arc::new(X25519SecretKey::new()))), None);
+
+        // The receiver index of the other messages starts with the peer index
+        let data = |receiver_idx: u32| {
+            let mut datagram = vec![4, 0, 0, 0];
+            datagram.extend_from_slice(&receiver_idx.to_le_bytes());
+            datagram.extend_from_slice(&[0; 24]);
+            datagram
+        };
+        assert_eq!(route(&data(1 << 8 | 5)).as_deref(), Some("b"));
+        assert_eq!(route(&data(7 << 8)), None);
+        assert_eq!(route(&[1, 2, 3]), None);
+    }
+
+    /// Tests that inbound packets are only dispatched if they are sent to a source peer IP of the peer,
+    /// from its allowed IPs.
+    #[tokio::test]
+    async fn test_route_protocol() {
+        let peers = [peer(
+            "narrow",
+            X25519SecretKey::new().public_key(),
+            &["127.0.0.1:51820"],
+            "192.168.4.3/24",
+            "10.1.0.0/16",
+        )];
+        let wg = tunnel(&peers, Arc::new(X25519SecretKey::new())).await;
+        let peer = &wg.peers[0];
+        let route = |source: &str, destination: &str, protocol: IpProtocol| {
+            WireGuardTunnel::route_protocol(peer, &ipv4_packet(source, destination, protocol))
+        };
+
+        assert_eq!(
+            route("10.1.0.5", "192.168.4.3", IpProtocol::Tcp),
+            Some(PortProtocol::Tcp)
+        );
+        assert_eq!(
+            route("10.1.0.5", "192.168.4.3", IpProtocol::Udp),
+            Some(PortProtocol::Udp)
+        );
+        assert_eq!(route("172.16.0.1", "192.168.4.3", IpProtocol::Tcp), None);
+        assert_eq!(route("10.1.0.5", "192.168.4.9", IpProtocol::Tcp), None);
+        assert_eq!(route("10.1.0.5", "192.168.4.3", IpProtocol::Icmp), None);
+    }
+
+    /// Tests that the peer is reached at the source address of its authenticated datagrams (roaming),
+    /// which makes one of its endpoints the active one if it is configured.
+    #[test]
+    fn test_roam() {
+        let config = peer(
+            "roaming",
+            X25519SecretKey::new().public_key(),
+            &["127.0.0.1:51820", "127.0.0.1:51821"],
+            "192.168.4.3/24",
+            "10.0.0.0/8",
+        );
+        let peer = WireGuardPeer::new(&config, Arc::new(X25519SecretKey::new()), 0).unwrap();
+        let addr = |s: &str| SocketAddr::from_str(s).unwrap();
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51820"));
+
+        peer.roam(addr("203.0.113.7:40000"));
+        assert_eq!(peer.endpoint(), addr("203.0.113.7:40000"));
+        assert_eq!(peer.endpoints.read().unwrap().active, 0);
+
+        peer.roam(addr("127.0.0.1:51821"));
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51821"));
+        assert_eq!(peer.endpoints.read().unwrap().active, 1);
+
+        // A new address of the active endpoint is used right away, but not one of the others
+        peer.set_resolved(1, addr("127.0.0.2:51821"));
+        assert_eq!(peer.endpoint(), addr("127.0.0.2:51821"));
+        peer.set_resolved(0, addr("127.0.0.2:51820"));
+        assert_eq!(peer.endpoint(), addr("127.0.0.2:51821"));
+        assert_eq!(peer.preferred_endpoint(), Some(addr("127.0.0.2:51820")));
+    }
+
+    /// Tests that the delay between the attempts to bind the UDP socket again grows up to its maximum,
+    /// and starts over once the socket works again.
+    #[test]
+    fn test_rebind_backoff() {
+        let mut backoff = RebindBackoff::default();
+        let delays: Vec<u64> = (0..7).map(|_| backoff.next().as_secs()).collect();
+        assert_eq!(delays, vec![0, 1, 2, 4, 8, 10, 10]);
+
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::ZERO);
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+    }
+
+    /// Tests that the UDP socket is bound again after repeated errors, right away the first time.
+    #[tokio::test]
+    async fn test_rebind() {
+        let peers = [peer(
+            "default",
+            X25519SecretKey::new().public_key(),
+            &["127.0.0.1:51820"],
+            "192.168.4.3/24",
+            "10.0.0.0/8",
+        )];
+        let wg = tunnel(&peers, Arc::new(X25519SecretKey::new())).await;
+        let udp = wg.socket().unwrap();
+        let error = io::Error::new(io::ErrorKind::AddrNotAvailable, "Address not available");
+        for _ in 0..MAX_SOCKET_ERRORS {
+            wg.socket_error(&udp, &error);
+        }
+        tokio::time::timeout(Duration::from_secs(1), wg.socket_failed.notified())
+            .await
+            .expect("The socket should have failed");
+
+        let mut backoff = RebindBackoff::default();
+        tokio::time::timeout(Duration::from_secs(1), wg.rebind(&mut backoff))
+            .await
+            .expect("The socket should be bound without delay");
+        assert!(!Arc::ptr_eq(&udp, &wg.socket().unwrap()));
+        assert_eq!(wg.socket_errors.load(Ordering::Relaxed), 0);
+        assert_eq!(backoff.delay, Duration::from_secs(1));
+
+        // Errors of the previous socket are ignored
+        for _ in 0..MAX_SOCKET_ERRORS {
+            wg.socket_error(&udp, &error);
+        }
+        assert_eq!(wg.socket_errors.load(Ordering::Relaxed), 0);
+    }
+
+    /// Tests that the next endpoint becomes the active one after `MAX_HANDSHAKE_ATTEMPTS` handshake initiations
+    /// without response, going back to the first one after the last.
+    #[test]
+    fn test_endpoint_failover() {
+        let config = peer(
+            "failover",
+            X25519SecretKey::new().public_key(),
+            &["127.0.0.1:51820", "127.0.0.1:51821"],
+            "192.168.4.3/24",
+            "10.0.0.0/8",
+        );
+        let peer = WireGuardPeer::new(&config, Arc::new(X25519SecretKey::new()), 0).unwrap();
+        let addr = |s: &str| SocketAddr::from_str(s).unwrap();
+
+        for _ in 0..MAX_HANDSHAKE_ATTEMPTS {
+            peer.count_handshake_attempt();
+        }
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51820"));
+        // The next initiation is sent to the next endpoint
+        peer.count_handshake_attempt();
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51821"));
+        assert_eq!(peer.preferred_endpoint(), Some(addr("127.0.0.1:51820")));
+
+        for _ in 1..MAX_HANDSHAKE_ATTEMPTS {
+            peer.count_handshake_attempt();
+        }
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51821"));
+        peer.count_handshake_attempt();
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51820"));
+        assert_eq!(peer.preferred_endpoint(), None);
+
+        // A single endpoint is kept
+        let config = PeerConfig {
+            endpoints: config.endpoints[..1].to_vec(),
+            ..config
+        };
+        let peer = WireGuardPeer::new(&config, Arc::new(X25519SecretKey::new()), 0).unwrap();
+        for _ in 0..MAX_HANDSHAKE_ATTEMPTS * 2 {
+            peer.count_handshake_attempt();
+        }
+        assert_eq!(peer.endpoint(), addr("127.0.0.1:51820"));
+    }
+
+    /// Tests that the statistics of a peer count the handshake and the bytes sent and received, once a handshake
+    /// is completed with a remote peer.
+    #[tokio::test]
+    async fn test_stats() {
+        let remote_key = Arc::new(X25519SecretKey::new());
+        let remote_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote_udp.local_addr().unwrap();
+        let peers = [peer(
+            "remote",
+            remote_key.public_key(),
+            &[&remote_addr.to_string()],
+            "192.168.4.3/24",
+            "10.0.0.0/8",
+        )];
+        let private_key = Arc::new(X25519SecretKey::new());
+        let wg = Arc::new(tunnel(&peers, private_key.clone()).await);
+        let wg_addr = wg.socket().unwrap().local_addr().unwrap();
+        let consume = tokio::spawn({
+            let wg = wg.clone();
+            async move { wg.consume_task().await }
+        });
+
+        let stats = &wg.stats()[0];
+        assert_eq!(stats.last_handshake, None);
+        assert!(!wg.is_ready());
+        assert_eq!(
+            (stats.tx_bytes, stats.rx_bytes, stats.handshakes),
+            (0, 0, 0)
+        );
+
+        // The packet is queued until the handshake is completed
+        let packet = ipv4_packet("192.168.4.3", "10.0.0.1", IpProtocol::Udp);
+        let mut send_buf = vec![0u8; MAX_PACKET];
+        wg.send_ip_packet(&packet, &mut send_buf).await.unwrap();
+
+        let remote = Tunn::new(
+            remote_key,
+            Arc::new(private_key.public_key()),
+            None,
+            None,
+            0,
+            None,
+        )
+        .unwrap();
+        let mut recv_buf = vec![0u8; MAX_PACKET];
+        loop {
+            let (size, _) =
+                tokio::time::timeout(Duration::from_secs(1), remote_udp.recv_from(&mut recv_buf))
+                    .await
+                    .expect("The remote peer should receive the packet")
+                    .unwrap();
+            match remote.decapsulate(None, &recv_buf[..size], &mut send_buf) {
+                TunnResult::WriteToNetwork(datagram) => {
+                    remote_udp.send_to(datagram, wg_addr).await.unwrap();
+                }
+                TunnResult::WriteToTunnelV4(received, _) => {
+                    assert_eq!(received, &packet[..]);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        let reply = ipv4_packet("10.0.0.1", "192.168.4.3", IpProtocol::Udp);
+        match remote.encapsulate(&reply, &mut send_buf) {
+            TunnResult::WriteToNetwork(datagram) => {
+                remote_udp.send_to(datagram, wg_addr).await.unwrap();
+            }
+            other => panic!("Unexpected encapsulation result: {:?}", other),
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while wg.stats()[0].rx_bytes == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The reply should be received");
+        consume.abort();
+
+        assert!(wg.is_ready());
+        let stats = &wg.stats()[0];
+        assert!(stats.last_handshake.unwrap() < Duration::from_secs(5));
+        assert_eq!(stats.handshakes, 1);
+        assert_eq!(stats.endpoint_addr, remote_addr);
+        assert!(stats.tx_bytes >= packet.len() as u64);
+        assert!(stats.rx_bytes >= reply.len() as u64);
+        assert_eq!((stats.encapsulate_errors, stats.decapsulate_errors), (0, 0));
+    }
+}